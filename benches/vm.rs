@@ -0,0 +1,118 @@
+//! times compiling and running programs through the vm, run with `cargo bench`
+//! criterion isn't a dependency, so this is a small timing harness of its own
+
+use std::time::{Duration, Instant};
+use monkey::compiler::compile_from_source;
+use monkey::eval::Object;
+use monkey::vm::run_from_bytecode;
+
+const ITERATIONS : u32 = 5;
+
+// the classic benchmark, nearly all of its time is spent calling and returning from functions
+const RECURSIVE : &str = "
+    let fibonacci = fn(x) {
+        if (x < 2) {
+            return x;
+        };
+        fibonacci(x - 1) + fibonacci(x - 2);
+    };
+    fibonacci(25);
+";
+
+const ITERATIVE : &str = "
+    let fibonacci = fn(x) {
+        let a = 0;
+        let b = 1;
+        while (x > 0) {
+            let next = a + b;
+            a = b;
+            b = next;
+            x -= 1;
+        }
+        a;
+    };
+    let i = 0;
+    let result = 0;
+    while (i < 20000) {
+        result = fibonacci(30);
+        i++;
+    }
+    result;
+";
+
+// a tight loop which is nearly all comparisons and the booleans they produce
+const COMPARISONS : &str = "
+    let i = 0;
+    let count = 0;
+    while (i < 300000) {
+        if (i == 5 || i >= 100 && i != 200 && !(i < 150)) {
+            count++;
+        };
+        i++;
+    }
+    count;
+";
+
+// arrays are copied onto the stack every time a global or an element is read
+const ARRAYS : &str = "
+    let array = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20];
+    let nested = [array, array, array, array, array, array, array, array];
+    let i = 0;
+    let total = 0;
+    while (i < 100000) {
+        let copy = nested;
+        total += len(copy[i % 8]) + len(array);
+        i++;
+    }
+    total;
+";
+
+// a large literal loaded from the constant pool on every iteration, TEXT is replaced before it's run
+const STRING_CONSTANTS : &str = "
+    let i = 0;
+    let total = 0;
+    while (i < 100000) {
+        let text = \"TEXT\";
+        total += len(text);
+        i++;
+    }
+    total;
+";
+
+// a closure called over and over, reading a large array it captured
+const CAPTURED_ARRAY : &str = "
+    let make = fn(array) { fn() { len(array); }; };
+    let count = make(range(0, 100000));
+    let i = 0;
+    let total = 0;
+    while (i < 2000) {
+        total += count();
+        i++;
+    }
+    total;
+";
+
+fn main() {
+    bench("fibonacci(25), recursive", RECURSIVE, Object::Integer(75025));
+    bench("fibonacci(30), iterative, 20000 times", ITERATIVE, Object::Integer(832040));
+    bench("comparison loop, 300000 times", COMPARISONS, Object::Integer(299850));
+    bench("array copying loop, 100000 times", ARRAYS, Object::Integer(4000000));
+    let string_constants = STRING_CONSTANTS.replace("TEXT", &"monkey".repeat(1000));
+    bench("string constant loop, 100000 times", &string_constants, Object::Integer(600000000));
+    bench("closure over a large array, 2000 calls", CAPTURED_ARRAY, Object::Integer(200000000));
+}
+
+fn bench(name: &str, input: &str, expected: Object) {
+    let mut total = Duration::new(0, 0);
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        let byte_code = compile_from_source(input).expect("compile");
+        let result = run_from_bytecode(byte_code).expect("vm run");
+        total += start.elapsed();
+
+        assert_eq!(expected, result);
+    }
+
+    let mean = total / ITERATIONS;
+    println!("{}: {:?} per run, {:.2} runs/sec", name, mean, 1.0 / mean.as_secs_f64());
+}
@@ -0,0 +1,913 @@
+use std::io::Write;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use crate::eval::{float_operands, HashKey, Object};
+
+/// what a builtin can ask of the vm or interpreter running it
+pub trait Interpreter {
+    /// where anything printed should be written to
+    fn output(&mut self) -> &mut dyn Write;
+
+    /// calls a function object, returning its result, or an error object if the call failed
+    fn call(&mut self, function: &Object, arguments: Vec<Object>) -> Object;
+}
+
+pub type BuiltinFunction = fn(&[&Object], &mut dyn Interpreter) -> Object;
+
+/// the builtin functions, the position of a builtin in this list is the index the compiler emits for it
+pub const BUILTINS: &[(&str, BuiltinFunction)] = &[
+    ("len", len),
+    ("puts", puts),
+    ("first", first),
+    ("last", last),
+    ("rest", rest),
+    ("push", push),
+    ("type", type_of),
+    ("str", str),
+    ("int", int),
+    ("keys", keys),
+    ("values", values),
+    ("contains", contains),
+    ("map", map),
+    ("filter", filter),
+    ("reduce", reduce),
+    ("split", split),
+    ("join", join),
+    ("range", range),
+    ("assert", assert),
+    ("abs", abs),
+    ("min", min),
+    ("max", max),
+    ("sort", sort),
+    ("reverse", reverse),
+];
+
+// the most elements range will build, so a huge range can't exhaust memory
+const MAX_RANGE_LENGTH : i64 = 1 << 20;
+
+pub fn lookup(index: usize) -> Option<BuiltinFunction> {
+    BUILTINS.get(index).map(|&(_, function)| function)
+}
+
+/// the tree-walking interpreter has no compiler to resolve names to indexes, so it looks builtins up by name
+pub fn lookup_by_name(name: &str) -> Option<BuiltinFunction> {
+    BUILTINS.iter().find(|&&(builtin_name, _)| builtin_name == name).map(|&(_, function)| function)
+}
+
+fn len(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::String(string)] => Object::Integer(string.chars().count() as i32),
+        [Object::Array(elements)] => Object::Integer(elements.len() as i32),
+        [obj] => Object::Error(format!("argument to `len` not supported, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+fn puts(arguments: &[&Object], interpreter: &mut dyn Interpreter) -> Object {
+    for argument in arguments {
+        if let Err(err) = writeln!(interpreter.output(), "{}", argument) {
+            return Object::Error(format!("failed to write output: {}", err));
+        }
+    }
+
+    Object::Null
+}
+
+fn first(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements)] => elements.first().cloned().unwrap_or(Object::Null),
+        [obj] => Object::Error(format!("argument to `first` must be ARRAY, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+fn last(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements)] => elements.last().cloned().unwrap_or(Object::Null),
+        [obj] => Object::Error(format!("argument to `last` must be ARRAY, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+fn rest(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements)] if elements.is_empty() => Object::Null,
+        [Object::Array(elements)] => Object::Array(elements[1..].to_vec()),
+        [obj] => Object::Error(format!("argument to `rest` must be ARRAY, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+fn push(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements), obj] => {
+            // builds a new array, the array passed in is left untouched
+            let mut new_elements = elements.clone();
+            new_elements.push(Object::clone(obj));
+
+            Object::Array(new_elements)
+        },
+        [obj, _] => Object::Error(format!("argument to `push` must be ARRAY, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 2),
+    }
+}
+
+fn type_of(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [obj] => Object::String(String::from(obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+fn str(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        // the same form puts prints, so a string is returned as it is rather than quoted
+        [obj] => Object::String(obj.to_string()),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+/// floats are truncated toward zero, so int(-3.9) is -3
+fn int(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Integer(integer)] => Object::Integer(*integer),
+        [Object::Float(float)] => {
+            let truncated = float.trunc();
+            if truncated >= i32::MIN as f64 && truncated <= i32::MAX as f64 {
+                Object::Integer(truncated as i32)
+            } else {
+                Object::Error(format!("could not convert {} to INTEGER", float))
+            }
+        },
+        [Object::String(string)] => match string.parse() {
+            Ok(integer) => Object::Integer(integer),
+            Err(_) => Object::Error(format!("could not parse {:?} as INTEGER", string)),
+        },
+        [obj] => Object::Error(format!("argument to `int` not supported, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+/// the keys in sorted order, so they line up with the values returned by values()
+fn keys(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Hash(hash)] => Object::Array(sorted_pairs(hash).map(|(key, _)| Object::from(key.clone())).collect()),
+        [obj] => Object::Error(format!("argument to `keys` must be HASH, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+/// the values ordered by their keys
+fn values(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Hash(hash)] => Object::Array(sorted_pairs(hash).map(|(_, value)| value.clone()).collect()),
+        [obj] => Object::Error(format!("argument to `values` must be HASH, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+/// whether an array has an element equal to the value, or a hash has the value as a key
+fn contains(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements), obj] => Object::Boolean(elements.contains(obj)),
+        [Object::Hash(hash), obj] => match HashKey::try_from(Object::clone(obj)) {
+            Ok(key) => Object::Boolean(hash.contains_key(&key)),
+            Err(err) => Object::Error(err.to_string()),
+        },
+        [obj, _] => Object::Error(format!("argument to `contains` must be ARRAY or HASH, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 2),
+    }
+}
+
+/// a new array holding the result of calling the function on each element
+fn map(arguments: &[&Object], interpreter: &mut dyn Interpreter) -> Object {
+    let (elements, function) = match arguments {
+        [Object::Array(elements), function] if is_function(function) => (elements, *function),
+        [Object::Array(_), obj] => return Object::Error(format!("argument to `map` must be FUNCTION, got {}", obj.type_name())),
+        [obj, _] => return Object::Error(format!("argument to `map` must be ARRAY, got {}", obj.type_name())),
+        _ => return wrong_number_of_arguments(arguments, 2),
+    };
+
+    let mut mapped = Vec::with_capacity(elements.len());
+    for element in elements {
+        match interpreter.call(function, vec![element.clone()]) {
+            Object::Error(message) => return Object::Error(message),
+            result => mapped.push(result),
+        }
+    }
+
+    Object::Array(mapped)
+}
+
+/// a new array holding the elements for which the function returns true
+fn filter(arguments: &[&Object], interpreter: &mut dyn Interpreter) -> Object {
+    let (elements, function) = match arguments {
+        [Object::Array(elements), function] if is_function(function) => (elements, *function),
+        [Object::Array(_), obj] => return Object::Error(format!("argument to `filter` must be FUNCTION, got {}", obj.type_name())),
+        [obj, _] => return Object::Error(format!("argument to `filter` must be ARRAY, got {}", obj.type_name())),
+        _ => return wrong_number_of_arguments(arguments, 2),
+    };
+
+    let mut kept = Vec::new();
+    for element in elements {
+        match interpreter.call(function, vec![element.clone()]) {
+            Object::Boolean(true) => kept.push(element.clone()),
+            Object::Boolean(false) => {},
+            Object::Error(message) => return Object::Error(message),
+            obj => return Object::Error(format!("function passed to `filter` must return BOOLEAN, got {}", obj.type_name())),
+        }
+    }
+
+    Object::Array(kept)
+}
+
+/// folds the elements into one value, calling the function with the value so far and each element in turn
+fn reduce(arguments: &[&Object], interpreter: &mut dyn Interpreter) -> Object {
+    let (elements, function, initial) = match arguments {
+        [Object::Array(elements), function, initial] if is_function(function) => (elements, *function, *initial),
+        [Object::Array(_), obj, _] => return Object::Error(format!("argument to `reduce` must be FUNCTION, got {}", obj.type_name())),
+        [obj, _, _] => return Object::Error(format!("argument to `reduce` must be ARRAY, got {}", obj.type_name())),
+        _ => return wrong_number_of_arguments(arguments, 3),
+    };
+
+    let mut accumulator = initial.clone();
+    for element in elements {
+        accumulator = match interpreter.call(function, vec![accumulator, element.clone()]) {
+            Object::Error(message) => return Object::Error(message),
+            result => result,
+        };
+    }
+
+    accumulator
+}
+
+/// an empty separator splits the string into its characters
+fn split(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    let parts: Vec<Object> = match arguments {
+        [Object::String(string), Object::String(separator)] if separator.is_empty() => {
+            string.chars().map(|character| Object::String(character.to_string())).collect()
+        },
+        [Object::String(string), Object::String(separator)] => {
+            string.split(separator.as_str()).map(|part| Object::String(part.to_string())).collect()
+        },
+        [Object::String(_), obj] | [obj, _] => {
+            return Object::Error(format!("arguments to `split` must be STRING, got {}", obj.type_name()))
+        },
+        _ => return wrong_number_of_arguments(arguments, 2),
+    };
+
+    Object::Array(parts)
+}
+
+/// elements which aren't strings are joined in the form str() gives them
+fn join(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements), Object::String(separator)] => {
+            let parts: Vec<String> = elements.iter().map(Object::to_string).collect();
+            Object::String(parts.join(separator))
+        },
+        [Object::Array(_), obj] => Object::Error(format!("argument to `join` must be STRING, got {}", obj.type_name())),
+        [obj, _] => Object::Error(format!("argument to `join` must be ARRAY, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 2),
+    }
+}
+
+/// range(end), range(start, end) or range(start, end, step), the end is excluded
+/// a step of zero or less gives an empty array, as does a start past the end
+fn range(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    let (start, end, step) = match arguments {
+        [Object::Integer(end)] => (0, *end, 1),
+        [Object::Integer(start), Object::Integer(end)] => (*start, *end, 1),
+        [Object::Integer(start), Object::Integer(end), Object::Integer(step)] => (*start, *end, *step),
+        [_] | [_, _] | [_, _, _] => {
+            let obj = arguments.iter().find(|obj| !matches!(obj, Object::Integer(_))).expect("a non-integer argument");
+            return Object::Error(format!("arguments to `range` must be INTEGER, got {}", obj.type_name()));
+        },
+        _ => return Object::Error(format!("wrong number of arguments. got={}, want=1 to 3", arguments.len())),
+    };
+    if step <= 0 || start >= end {
+        return Object::Array(vec![]);
+    }
+
+    let length = (end as i64 - start as i64 + step as i64 - 1) / step as i64;
+    if length > MAX_RANGE_LENGTH {
+        return Object::Error(format!("range would have more than {} elements", MAX_RANGE_LENGTH));
+    }
+
+    Object::Array((start..end).step_by(step as usize).map(Object::Integer).collect())
+}
+
+/// assert(condition) or assert(condition, message), a false condition gives an error which stops the program
+fn assert(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    let message = match arguments {
+        [_] => "assertion failed",
+        [_, Object::String(message)] => message,
+        [_, obj] => return Object::Error(format!("message passed to `assert` must be STRING, got {}", obj.type_name())),
+        _ => return Object::Error(format!("wrong number of arguments. got={}, want=1 or 2", arguments.len())),
+    };
+
+    match arguments[0] {
+        Object::Boolean(true) => Object::Null,
+        Object::Boolean(false) => Object::Error(message.to_owned()),
+        obj => Object::Error(format!("condition passed to `assert` must be BOOLEAN, got {}", obj.type_name())),
+    }
+}
+
+fn abs(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Integer(integer)] => match integer.checked_abs() {
+            Some(integer) => Object::Integer(integer),
+            None => Object::Error(format!("integer overflow in `abs` of {}", integer)),
+        },
+        [Object::Float(float)] => Object::Float(float.abs()),
+        [obj] => Object::Error(format!("argument to `abs` must be INTEGER or FLOAT, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+/// min(a, b, ...), the result is a float if any of the arguments is one
+fn min(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    extremum("min", arguments, std::cmp::min, f64::min)
+}
+
+/// max(a, b, ...), the result is a float if any of the arguments is one
+fn max(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    extremum("max", arguments, std::cmp::max, f64::max)
+}
+
+fn extremum(name: &str, arguments: &[&Object], integers: fn(i32, i32) -> i32, floats: fn(f64, f64) -> f64) -> Object {
+    if arguments.len() < 2 {
+        return Object::Error(format!("wrong number of arguments. got={}, want=2 or more", arguments.len()));
+    }
+
+    if let Some(obj) = arguments.iter().find(|obj| !matches!(obj, Object::Integer(_) | Object::Float(_))) {
+        return Object::Error(format!("arguments to `{}` must be INTEGER or FLOAT, got {}", name, obj.type_name()));
+    }
+
+    let as_integers: Option<Vec<i32>> = arguments
+        .iter()
+        .map(|obj| match obj {
+            Object::Integer(integer) => Some(*integer),
+            _ => None,
+        })
+        .collect();
+    match as_integers {
+        Some(as_integers) => Object::Integer(as_integers.into_iter().reduce(integers).expect("two or more arguments")),
+        None => {
+            let as_floats = arguments.iter().map(|obj| match obj {
+                Object::Integer(integer) => *integer as f64,
+                Object::Float(float) => *float,
+                _ => unreachable!(),
+            });
+            Object::Float(as_floats.reduce(floats).expect("two or more arguments"))
+        },
+    }
+}
+
+/// strings are reversed by unicode scalar value, so multibyte characters stay whole
+fn reverse(arguments: &[&Object], _interpreter: &mut dyn Interpreter) -> Object {
+    match arguments {
+        [Object::Array(elements)] => Object::Array(elements.iter().rev().cloned().collect()),
+        [Object::String(string)] => Object::String(string.chars().rev().collect()),
+        [obj] => Object::Error(format!("argument to `reverse` must be ARRAY or STRING, got {}", obj.type_name())),
+        _ => wrong_number_of_arguments(arguments, 1),
+    }
+}
+
+/// sort(array) puts numbers or strings in ascending order, sort(array, function) orders by a comparator
+/// which returns true or a negative integer when its first argument goes first. a new array is returned
+fn sort(arguments: &[&Object], interpreter: &mut dyn Interpreter) -> Object {
+    let sorted = match arguments {
+        [Object::Array(elements)] => merge_sort(elements.clone(), &mut |left, right| match natural_order(left, right) {
+            Some(ordering) => Ok(ordering == Ordering::Less),
+            None => Err(Object::Error(format!(
+                "elements passed to `sort` can't be compared, got {} and {}", left.type_name(), right.type_name()
+            ))),
+        }),
+        [Object::Array(elements), function] if is_function(function) => merge_sort(elements.clone(), &mut |left, right| {
+            match interpreter.call(function, vec![left.clone(), right.clone()]) {
+                Object::Boolean(goes_first) => Ok(goes_first),
+                Object::Integer(ordering) => Ok(ordering < 0),
+                Object::Error(message) => Err(Object::Error(message)),
+                obj => Err(Object::Error(format!(
+                    "function passed to `sort` must return BOOLEAN or INTEGER, got {}", obj.type_name()
+                ))),
+            }
+        }),
+        [Object::Array(_), obj] => return Object::Error(format!("argument to `sort` must be FUNCTION, got {}", obj.type_name())),
+        [obj] | [obj, _] => return Object::Error(format!("argument to `sort` must be ARRAY, got {}", obj.type_name())),
+        _ => return Object::Error(format!("wrong number of arguments. got={}, want=1 or 2", arguments.len())),
+    };
+
+    match sorted {
+        Ok(sorted) => Object::Array(sorted),
+        Err(err) => err,
+    }
+}
+
+/// a stable sort which stops at the first error, slice sorting can't be stopped partway and
+/// may panic when given a comparator which isn't a total order
+fn merge_sort(
+    mut elements: Vec<Object>,
+    goes_first: &mut dyn FnMut(&Object, &Object) -> Result<bool, Object>
+) -> Result<Vec<Object>, Object> {
+    if elements.len() <= 1 {
+        return Ok(elements);
+    }
+
+    let right = elements.split_off(elements.len() / 2);
+    let (left, right) = (merge_sort(elements, goes_first)?, merge_sort(right, goes_first)?);
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(left_element), Some(right_element)) = (left.peek(), right.peek()) {
+        // equal elements keep their order, the left one is only passed over when the right one goes first
+        let next = if goes_first(right_element, left_element)? { right.next() } else { left.next() };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+
+    Ok(merged)
+}
+
+/// integers and floats compare with each other by value, strings compare with strings
+fn natural_order(left: &Object, right: &Object) -> Option<Ordering> {
+    match (left, right) {
+        (Object::Integer(left), Object::Integer(right)) => Some(left.cmp(right)),
+        (Object::String(left), Object::String(right)) => Some(left.cmp(right)),
+        _ => float_operands(left, right).map(|(left, right)| left.partial_cmp(&right).unwrap_or(Ordering::Equal)),
+    }
+}
+
+fn is_function(obj: &Object) -> bool {
+    matches!(obj, Object::Function{..} | Object::Closure{..} | Object::Builtin(_))
+}
+
+fn sorted_pairs(hash: &HashMap<HashKey, Object>) -> impl Iterator<Item = (&HashKey, &Object)> {
+    let mut pairs: Vec<(&HashKey, &Object)> = hash.iter().collect();
+    pairs.sort_by_key(|&(key, _)| key);
+
+    pairs.into_iter()
+}
+
+fn wrong_number_of_arguments(arguments: &[&Object], expected: usize) -> Object {
+    Object::Error(format!("wrong number of arguments. got={}, want={}", arguments.len(), expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_len() {
+        assert_eq!(Object::Integer(5), call(len, &[Object::String(String::from("hello"))]));
+        assert_eq!(Object::Integer(0), call(len, &[Object::String(String::new())]));
+        assert_eq!(Object::Integer(2), call(len, &[Object::Array(vec![Object::Null, Object::Null])]));
+        assert_eq!(
+            Object::Error(String::from("argument to `len` not supported, got INTEGER")),
+            call(len, &[Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=2, want=1")),
+            call(len, &[Object::Integer(1), Object::Integer(2)])
+        );
+    }
+
+    #[test]
+    fn builtin_puts() {
+        let mut interpreter = TestInterpreter { output: Vec::new() };
+        let result = puts(
+            &[
+                &Object::String(String::from("hi")),
+                &Object::Integer(42),
+                &Object::Array(vec![Object::Boolean(true), Object::Null]),
+            ],
+            &mut interpreter
+        );
+
+        assert_eq!(Object::Null, result);
+        assert_eq!("hi\n42\n[true, null]\n", String::from_utf8(interpreter.output).unwrap());
+    }
+
+    #[test]
+    fn builtin_first_last_rest() {
+        let array = || Object::Array(vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)]);
+        let empty = || Object::Array(vec![]);
+
+        assert_eq!(Object::Integer(1), call(first, &[array()]));
+        assert_eq!(Object::Null, call(first, &[empty()]));
+        assert_eq!(Object::Integer(3), call(last, &[array()]));
+        assert_eq!(Object::Null, call(last, &[empty()]));
+        assert_eq!(Object::Array(vec![Object::Integer(2), Object::Integer(3)]), call(rest, &[array()]));
+        assert_eq!(Object::Null, call(rest, &[empty()]));
+        assert_eq!(
+            Object::Error(String::from("argument to `first` must be ARRAY, got INTEGER")),
+            call(first, &[Object::Integer(1)])
+        );
+    }
+
+    #[test]
+    fn builtin_push() {
+        let array = Object::Array(vec![Object::Integer(1)]);
+
+        assert_eq!(
+            Object::Array(vec![Object::Integer(1), Object::Integer(2)]),
+            call(push, &[array.clone(), Object::Integer(2)])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=1, want=2")),
+            call(push, &[array])
+        );
+    }
+
+    #[test]
+    fn builtin_type() {
+        let type_name = |obj: Object| call(type_of, &[obj]);
+        let string = |s: &str| Object::String(String::from(s));
+
+        assert_eq!(string("INTEGER"), type_name(Object::Integer(1)));
+        assert_eq!(string("FLOAT"), type_name(Object::Float(1.5)));
+        assert_eq!(string("STRING"), type_name(string("")));
+        assert_eq!(string("BOOLEAN"), type_name(Object::Boolean(false)));
+        assert_eq!(string("NULL"), type_name(Object::Null));
+        assert_eq!(string("ARRAY"), type_name(Object::Array(vec![])));
+        assert_eq!(string("HASH"), type_name(Object::Hash(std::collections::HashMap::new())));
+        assert_eq!(string("FUNCTION"), type_name(Object::Function { parameters: vec![], body: vec![] }));
+        assert_eq!(string("BUILTIN"), type_name(Object::Builtin(0)));
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=0, want=1")),
+            call(type_of, &[])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=2, want=1")),
+            call(type_of, &[Object::Null, Object::Null])
+        );
+    }
+
+    #[test]
+    fn builtin_str() {
+        let string = |s: &str| Object::String(String::from(s));
+
+        assert_eq!(string("42"), call(str, &[Object::Integer(42)]));
+        assert_eq!(string("-1.5"), call(str, &[Object::Float(-1.5)]));
+        assert_eq!(string("true"), call(str, &[Object::Boolean(true)]));
+        assert_eq!(string("null"), call(str, &[Object::Null]));
+        assert_eq!(string("hi"), call(str, &[string("hi")]));
+        assert_eq!(string("[1, 2]"), call(str, &[Object::Array(vec![Object::Integer(1), Object::Integer(2)])]));
+        assert_eq!(string(r#"["a"]"#), call(str, &[Object::Array(vec![string("a")])]));
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=2, want=1")),
+            call(str, &[Object::Null, Object::Null])
+        );
+    }
+
+    #[test]
+    fn builtin_int() {
+        let string = |s: &str| Object::String(String::from(s));
+        let error = |s: &str| Object::Error(String::from(s));
+
+        assert_eq!(Object::Integer(42), call(int, &[string("42")]));
+        assert_eq!(Object::Integer(-7), call(int, &[string("-7")]));
+        assert_eq!(Object::Integer(5), call(int, &[Object::Integer(5)]));
+        assert_eq!(Object::Integer(3), call(int, &[Object::Float(3.9)]));
+        assert_eq!(Object::Integer(-3), call(int, &[Object::Float(-3.9)]));
+        assert_eq!(error(r#"could not parse "abc" as INTEGER"#), call(int, &[string("abc")]));
+        assert_eq!(error(r#"could not parse "" as INTEGER"#), call(int, &[string("")]));
+        assert_eq!(error(r#"could not parse "99999999999" as INTEGER"#), call(int, &[string("99999999999")]));
+        assert_eq!(error("could not convert 10000000000 to INTEGER"), call(int, &[Object::Float(1e10)]));
+        assert_eq!(error("could not convert NaN to INTEGER"), call(int, &[Object::Float(f64::NAN)]));
+        assert_eq!(error("argument to `int` not supported, got BOOLEAN"), call(int, &[Object::Boolean(true)]));
+        assert_eq!(error("wrong number of arguments. got=0, want=1"), call(int, &[]));
+    }
+
+    #[test]
+    fn builtin_keys_values() {
+        let mut hash = HashMap::new();
+        hash.insert(HashKey::String(String::from("b")), Object::Integer(2));
+        hash.insert(HashKey::Boolean(true), Object::Integer(3));
+        hash.insert(HashKey::String(String::from("a")), Object::Integer(1));
+        hash.insert(HashKey::Integer(10), Object::Integer(0));
+        let hash = Object::Hash(hash);
+
+        assert_eq!(
+            Object::Array(vec![
+                Object::Integer(10),
+                Object::String(String::from("a")),
+                Object::String(String::from("b")),
+                Object::Boolean(true),
+            ]),
+            call(keys, std::slice::from_ref(&hash))
+        );
+        assert_eq!(
+            Object::Array(vec![Object::Integer(0), Object::Integer(1), Object::Integer(2), Object::Integer(3)]),
+            call(values, std::slice::from_ref(&hash))
+        );
+        assert_eq!(Object::Array(vec![]), call(keys, &[Object::Hash(HashMap::new())]));
+        assert_eq!(
+            Object::Error(String::from("argument to `keys` must be HASH, got ARRAY")),
+            call(keys, &[Object::Array(vec![])])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `values` must be HASH, got INTEGER")),
+            call(values, &[Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=2, want=1")),
+            call(values, &[hash.clone(), hash])
+        );
+    }
+
+    #[test]
+    fn builtin_contains() {
+        let array = Object::Array(vec![Object::Integer(1), Object::String(String::from("two"))]);
+        let mut hash = HashMap::new();
+        hash.insert(HashKey::String(String::from("a")), Object::Integer(1));
+        let hash = Object::Hash(hash);
+
+        assert_eq!(Object::Boolean(true), call(contains, &[array.clone(), Object::Integer(1)]));
+        assert_eq!(Object::Boolean(true), call(contains, &[array.clone(), Object::String(String::from("two"))]));
+        assert_eq!(Object::Boolean(false), call(contains, &[array.clone(), Object::Integer(2)]));
+        assert_eq!(Object::Boolean(true), call(contains, &[hash.clone(), Object::String(String::from("a"))]));
+        assert_eq!(Object::Boolean(false), call(contains, &[hash.clone(), Object::String(String::from("b"))]));
+        // values aren't keys
+        assert_eq!(Object::Boolean(false), call(contains, &[hash.clone(), Object::Integer(1)]));
+        assert_eq!(
+            Object::Error(String::from("unusable as hash key: ARRAY")),
+            call(contains, &[hash, Object::Array(vec![])])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `contains` must be ARRAY or HASH, got STRING")),
+            call(contains, &[Object::String(String::from("abc")), Object::String(String::from("a"))])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=1, want=2")),
+            call(contains, &[array])
+        );
+    }
+
+    #[test]
+    fn builtin_map_filter_reduce() {
+        let strings = Object::Array(vec![
+            Object::String(String::from("a")),
+            Object::String(String::from("")),
+            Object::String(String::from("abc")),
+        ]);
+        let len = Object::Builtin(0);
+        let contains = Object::Builtin(BUILTINS.iter().position(|&(name, _)| name == "contains").unwrap());
+
+        assert_eq!(
+            Object::Array(vec![Object::Integer(1), Object::Integer(0), Object::Integer(3)]),
+            call(map, &[strings.clone(), len.clone()])
+        );
+        assert_eq!(Object::Array(vec![]), call(map, &[Object::Array(vec![]), len.clone()]));
+        // the function is called with the value so far and the element, here contains(["a"], element)
+        assert_eq!(
+            Object::Boolean(true),
+            call(reduce, &[Object::Array(vec![Object::String(String::from("a"))]), contains, strings.clone()])
+        );
+        assert_eq!(Object::Integer(7), call(reduce, &[Object::Array(vec![]), len.clone(), Object::Integer(7)]));
+        assert_eq!(
+            Object::Error(String::from("function passed to `filter` must return BOOLEAN, got INTEGER")),
+            call(filter, &[strings.clone(), len.clone()])
+        );
+        // errors from the function stop the iteration and become the result
+        assert_eq!(
+            Object::Error(String::from("argument to `len` not supported, got INTEGER")),
+            call(map, &[Object::Array(vec![Object::Integer(1)]), len.clone()])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `map` must be FUNCTION, got INTEGER")),
+            call(map, &[strings.clone(), Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `filter` must be ARRAY, got BUILTIN")),
+            call(filter, &[len.clone(), len.clone()])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=2, want=3")),
+            call(reduce, &[strings, len])
+        );
+    }
+
+    #[test]
+    fn builtin_split_join() {
+        let string = |s: &str| Object::String(String::from(s));
+        let strings = |parts: &[&str]| Object::Array(parts.iter().map(|part| string(part)).collect());
+
+        assert_eq!(strings(&["a", "b", "c"]), call(split, &[string("a,b,c"), string(",")]));
+        assert_eq!(strings(&["a", "", "b"]), call(split, &[string("a,,b"), string(",")]));
+        assert_eq!(strings(&["a", "b"]), call(split, &[string("a::b"), string("::")]));
+        assert_eq!(strings(&["abc"]), call(split, &[string("abc"), string(",")]));
+        assert_eq!(strings(&["h", "é", "!"]), call(split, &[string("hé!"), string("")]));
+        assert_eq!(strings(&[""]), call(split, &[string(""), string(",")]));
+        assert_eq!(strings(&[]), call(split, &[string(""), string("")]));
+        assert_eq!(
+            Object::Error(String::from("arguments to `split` must be STRING, got INTEGER")),
+            call(split, &[string("a"), Object::Integer(1)])
+        );
+
+        assert_eq!(string("a-b"), call(join, &[strings(&["a", "b"]), string("-")]));
+        assert_eq!(string("abc"), call(join, &[strings(&["a", "b", "c"]), string("")]));
+        assert_eq!(string(""), call(join, &[strings(&[]), string(", ")]));
+        assert_eq!(
+            string("1, true, [2]"),
+            call(join, &[
+                Object::Array(vec![Object::Integer(1), Object::Boolean(true), Object::Array(vec![Object::Integer(2)])]),
+                string(", "),
+            ])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `join` must be ARRAY, got STRING")),
+            call(join, &[string("ab"), string("")])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=1, want=2")),
+            call(join, &[strings(&[])])
+        );
+    }
+
+    #[test]
+    fn builtin_assert() {
+        let message = Object::String(String::from("nope"));
+
+        assert_eq!(Object::Null, call(assert, &[Object::Boolean(true)]));
+        assert_eq!(Object::Null, call(assert, &[Object::Boolean(true), message.clone()]));
+        assert_eq!(Object::Error(String::from("assertion failed")), call(assert, &[Object::Boolean(false)]));
+        assert_eq!(Object::Error(String::from("nope")), call(assert, &[Object::Boolean(false), message]));
+
+        assert_eq!(
+            Object::Error(String::from("condition passed to `assert` must be BOOLEAN, got INTEGER")),
+            call(assert, &[Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("message passed to `assert` must be STRING, got INTEGER")),
+            call(assert, &[Object::Boolean(false), Object::Integer(1)])
+        );
+        assert_eq!(Object::Error(String::from("wrong number of arguments. got=0, want=1 or 2")), call(assert, &[]));
+    }
+
+    #[test]
+    fn builtin_range() {
+        let integers = |integers: &[i32]| Object::Array(integers.iter().map(|&integer| Object::Integer(integer)).collect());
+
+        assert_eq!(integers(&[0, 1, 2, 3, 4]), call(range, &[Object::Integer(5)]));
+        assert_eq!(integers(&[2, 3, 4]), call(range, &[Object::Integer(2), Object::Integer(5)]));
+        assert_eq!(integers(&[1, 4, 7]), call(range, &[Object::Integer(1), Object::Integer(10), Object::Integer(3)]));
+        assert_eq!(integers(&[-2, -1]), call(range, &[Object::Integer(-2), Object::Integer(0)]));
+        assert_eq!(
+            integers(&[i32::MAX - 1]),
+            call(range, &[Object::Integer(i32::MAX - 1), Object::Integer(i32::MAX), Object::Integer(i32::MAX)])
+        );
+
+        assert_eq!(integers(&[]), call(range, &[Object::Integer(0)]));
+        assert_eq!(integers(&[]), call(range, &[Object::Integer(-3)]));
+        assert_eq!(integers(&[]), call(range, &[Object::Integer(5), Object::Integer(2)]));
+        assert_eq!(integers(&[]), call(range, &[Object::Integer(0), Object::Integer(5), Object::Integer(0)]));
+        assert_eq!(integers(&[]), call(range, &[Object::Integer(5), Object::Integer(0), Object::Integer(-1)]));
+
+        assert_eq!(
+            Object::Error(String::from("range would have more than 1048576 elements")),
+            call(range, &[Object::Integer(i32::MIN), Object::Integer(i32::MAX)])
+        );
+        assert_eq!(
+            Object::Error(String::from("arguments to `range` must be INTEGER, got STRING")),
+            call(range, &[Object::Integer(0), Object::String(String::from("5"))])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=0, want=1 to 3")),
+            call(range, &[])
+        );
+    }
+
+    #[test]
+    fn builtin_abs() {
+        assert_eq!(Object::Integer(5), call(abs, &[Object::Integer(-5)]));
+        assert_eq!(Object::Integer(5), call(abs, &[Object::Integer(5)]));
+        assert_eq!(Object::Float(1.5), call(abs, &[Object::Float(-1.5)]));
+        assert_eq!(
+            Object::Error(format!("integer overflow in `abs` of {}", i32::MIN)),
+            call(abs, &[Object::Integer(i32::MIN)])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `abs` must be INTEGER or FLOAT, got STRING")),
+            call(abs, &[Object::String(String::from("-1"))])
+        );
+    }
+
+    #[test]
+    fn builtin_min_max() {
+        assert_eq!(Object::Integer(7), call(max, &[Object::Integer(1), Object::Integer(7), Object::Integer(3)]));
+        assert_eq!(Object::Integer(2), call(min, &[Object::Integer(4), Object::Integer(2)]));
+        assert_eq!(Object::Integer(-3), call(min, &[Object::Integer(1), Object::Integer(-3), Object::Integer(0)]));
+        assert_eq!(Object::Float(2.5), call(max, &[Object::Integer(1), Object::Float(2.5)]));
+        assert_eq!(Object::Float(1.0), call(min, &[Object::Integer(1), Object::Float(2.5)]));
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=1, want=2 or more")),
+            call(min, &[Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=0, want=2 or more")),
+            call(max, &[])
+        );
+        assert_eq!(
+            Object::Error(String::from("arguments to `max` must be INTEGER or FLOAT, got NULL")),
+            call(max, &[Object::Integer(1), Object::Null])
+        );
+    }
+
+    #[test]
+    fn builtin_reverse() {
+        let string = |s: &str| Object::String(String::from(s));
+
+        assert_eq!(
+            Object::Array(vec![Object::Integer(3), Object::Integer(2), Object::Integer(1)]),
+            call(reverse, &[Object::Array(vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)])])
+        );
+        assert_eq!(Object::Array(vec![]), call(reverse, &[Object::Array(vec![])]));
+        assert_eq!(string("cba"), call(reverse, &[string("abc")]));
+        assert_eq!(string("ölléh"), call(reverse, &[string("héllö")]));
+        assert_eq!(string("🦀日本"), call(reverse, &[string("本日🦀")]));
+        assert_eq!(string(""), call(reverse, &[string("")]));
+        assert_eq!(
+            Object::Error(String::from("argument to `reverse` must be ARRAY or STRING, got HASH")),
+            call(reverse, &[Object::Hash(HashMap::new())])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=2, want=1")),
+            call(reverse, &[string("a"), string("b")])
+        );
+    }
+
+    #[test]
+    fn builtin_sort() {
+        let integers = |integers: &[i32]| Object::Array(integers.iter().map(|&integer| Object::Integer(integer)).collect());
+        let strings = |strings: &[&str]| Object::Array(strings.iter().map(|&string| Object::String(String::from(string))).collect());
+
+        assert_eq!(integers(&[1, 2, 3]), call(sort, &[integers(&[3, 1, 2])]));
+        assert_eq!(integers(&[-4, 0, 0, 9]), call(sort, &[integers(&[0, 9, -4, 0])]));
+        assert_eq!(integers(&[]), call(sort, &[integers(&[])]));
+        assert_eq!(strings(&["a", "b", "c"]), call(sort, &[strings(&["b", "c", "a"])]));
+        assert_eq!(
+            Object::Array(vec![Object::Integer(1), Object::Float(1.5), Object::Integer(2)]),
+            call(sort, &[Object::Array(vec![Object::Integer(2), Object::Float(1.5), Object::Integer(1)])])
+        );
+
+        assert_eq!(
+            Object::Error(String::from("elements passed to `sort` can't be compared, got STRING and INTEGER")),
+            call(sort, &[Object::Array(vec![Object::Integer(1), Object::String(String::from("a"))])])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `sort` must be ARRAY, got INTEGER")),
+            call(sort, &[Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("argument to `sort` must be FUNCTION, got INTEGER")),
+            call(sort, &[integers(&[1]), Object::Integer(1)])
+        );
+        assert_eq!(
+            Object::Error(String::from("wrong number of arguments. got=0, want=1 or 2")),
+            call(sort, &[])
+        );
+    }
+
+    #[test]
+    fn builtin_lookup() {
+        let array = Object::Array(vec![Object::Integer(1)]);
+
+        assert_eq!(Object::Integer(1), call(lookup(0).expect("len"), std::slice::from_ref(&array)));
+        assert_eq!(
+            Object::Array(vec![Object::Integer(1), Object::Integer(1)]),
+            call(lookup_by_name("push").expect("push"), &[array, Object::Integer(1)])
+        );
+        assert!(lookup(BUILTINS.len()).is_none());
+        assert!(lookup_by_name("missing").is_none());
+    }
+
+    /// collects the output, and can only call other builtins
+    struct TestInterpreter {
+        output: Vec<u8>,
+    }
+
+    impl Interpreter for TestInterpreter {
+        fn output(&mut self) -> &mut dyn Write {
+            &mut self.output
+        }
+
+        fn call(&mut self, function: &Object, arguments: Vec<Object>) -> Object {
+            match function {
+                Object::Builtin(index) => {
+                    let arguments: Vec<&Object> = arguments.iter().collect();
+                    lookup(*index).expect("builtin index should be valid")(&arguments, self)
+                },
+                obj => panic!("test interpreter can't call {}", obj.type_name()),
+            }
+        }
+    }
+
+    fn call(builtin: BuiltinFunction, arguments: &[Object]) -> Object {
+        let arguments: Vec<&Object> = arguments.iter().collect();
+        builtin(&arguments, &mut TestInterpreter { output: Vec::new() })
+    }
+}
@@ -1,3 +1,6 @@
+use std::convert::TryFrom;
+
+#[allow(clippy::enum_variant_names)]
 pub enum OpCode {
     OpConstant(u16), // args: pointer to constant table
     OpPop,
@@ -16,42 +19,335 @@ pub enum OpCode {
     OpJump(u16), // args: byte address to jump to
     OpSetGlobal(u16), // args: id of global
     OpGetGlobal(u16), // args: id of global
+    OpNull,
+    OpArray(u16), // args: number of elements
+    OpHash(u16), // args: number of key-value pairs
+    OpIndex,
+    OpCall(u8), // args: number of arguments
+    OpReturnValue,
+    OpReturn,
+    OpSetLocal(u8), // args: id of local
+    OpGetLocal(u8), // args: id of local
+    OpClosure(u16, u8), // args: pointer to function in constant table, number of free variables
+    OpGetFree(u8), // args: id of free variable
+    OpGetBuiltin(u8), // args: id of builtin function
+    OpLessThan,
+    OpMod,
+    OpGreaterThanOrEqual,
+    OpLessThanOrEqual,
+    OpCurrentClosure, // how a function refers to itself, so it never has to capture itself as a free variable
+    OpBitAnd,
+    OpBitOr,
+    OpBitXor,
+    OpShl,
+    OpShr,
+    OpDup,
+    OpSwap,
+    OpDestructure(u16), // args: number of elements the array must have
+    OpTailCall(u8), // args: number of arguments, a call which can reuse the frame of the function it's in
+    OpPopN(u8), // args: number of values to discard, unlike OpPop they don't become the last popped value
+    OpArrayLength, // replaces the array a for loop iterates over with its length
+    OpSlice, // pops end, start and the array or string, a null bound is an omitted one
+    OpIntDiv, // division truncated toward zero, giving an integer even for float operands
+}
+
+/// the byte which identifies each instruction in the encoded bytecode
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum Opcode {
+    Constant = 0x01,
+    Pop = 0x02,
+    Add = 0x03,
+    Sub = 0x04,
+    Mul = 0x05,
+    Div = 0x06,
+    True = 0x07,
+    False = 0x08,
+    Equals = 0x09,
+    NotEquals = 0x0A,
+    GreaterThan = 0x0B,
+    Minus = 0x0C,
+    Bang = 0x0D,
+    JumpNotTrue = 0x0E,
+    Jump = 0x0F,
+    SetGlobal = 0x10,
+    GetGlobal = 0x11,
+    Null = 0x12,
+    Array = 0x13,
+    Hash = 0x14,
+    Index = 0x15,
+    Call = 0x16,
+    ReturnValue = 0x17,
+    Return = 0x18,
+    SetLocal = 0x19,
+    GetLocal = 0x1A,
+    Closure = 0x1B,
+    GetFree = 0x1C,
+    GetBuiltin = 0x1D,
+    LessThan = 0x1E,
+    Mod = 0x1F,
+    GreaterThanOrEqual = 0x20,
+    LessThanOrEqual = 0x21,
+    CurrentClosure = 0x22,
+    BitAnd = 0x23,
+    BitOr = 0x24,
+    BitXor = 0x25,
+    Shl = 0x26,
+    Shr = 0x27,
+    Dup = 0x28,
+    Swap = 0x29,
+    Destructure = 0x2A,
+    TailCall = 0x2B,
+    PopN = 0x2C,
+    ArrayLength = 0x2D,
+    Slice = 0x2E,
+    IntDiv = 0x2F,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnknownOpcode(pub u8);
+
+impl TryFrom<u8> for Opcode {
+    type Error = UnknownOpcode;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0x01 => Ok(Opcode::Constant),
+            0x02 => Ok(Opcode::Pop),
+            0x03 => Ok(Opcode::Add),
+            0x04 => Ok(Opcode::Sub),
+            0x05 => Ok(Opcode::Mul),
+            0x06 => Ok(Opcode::Div),
+            0x07 => Ok(Opcode::True),
+            0x08 => Ok(Opcode::False),
+            0x09 => Ok(Opcode::Equals),
+            0x0A => Ok(Opcode::NotEquals),
+            0x0B => Ok(Opcode::GreaterThan),
+            0x0C => Ok(Opcode::Minus),
+            0x0D => Ok(Opcode::Bang),
+            0x0E => Ok(Opcode::JumpNotTrue),
+            0x0F => Ok(Opcode::Jump),
+            0x10 => Ok(Opcode::SetGlobal),
+            0x11 => Ok(Opcode::GetGlobal),
+            0x12 => Ok(Opcode::Null),
+            0x13 => Ok(Opcode::Array),
+            0x14 => Ok(Opcode::Hash),
+            0x15 => Ok(Opcode::Index),
+            0x16 => Ok(Opcode::Call),
+            0x17 => Ok(Opcode::ReturnValue),
+            0x18 => Ok(Opcode::Return),
+            0x19 => Ok(Opcode::SetLocal),
+            0x1A => Ok(Opcode::GetLocal),
+            0x1B => Ok(Opcode::Closure),
+            0x1C => Ok(Opcode::GetFree),
+            0x1D => Ok(Opcode::GetBuiltin),
+            0x1E => Ok(Opcode::LessThan),
+            0x1F => Ok(Opcode::Mod),
+            0x20 => Ok(Opcode::GreaterThanOrEqual),
+            0x21 => Ok(Opcode::LessThanOrEqual),
+            0x22 => Ok(Opcode::CurrentClosure),
+            0x23 => Ok(Opcode::BitAnd),
+            0x24 => Ok(Opcode::BitOr),
+            0x25 => Ok(Opcode::BitXor),
+            0x26 => Ok(Opcode::Shl),
+            0x27 => Ok(Opcode::Shr),
+            0x28 => Ok(Opcode::Dup),
+            0x29 => Ok(Opcode::Swap),
+            0x2A => Ok(Opcode::Destructure),
+            0x2B => Ok(Opcode::TailCall),
+            0x2C => Ok(Opcode::PopN),
+            0x2D => Ok(Opcode::ArrayLength),
+            0x2E => Ok(Opcode::Slice),
+            0x2F => Ok(Opcode::IntDiv),
+            _ => Err(UnknownOpcode(byte)),
+        }
+    }
+}
+
+impl Opcode {
+    /// the number of bytes taken by each operand following the opcode
+    pub fn operand_widths(&self) -> &'static [usize] {
+        match self {
+            Opcode::Constant | Opcode::JumpNotTrue | Opcode::Jump | Opcode::SetGlobal | Opcode::GetGlobal
+                | Opcode::Array | Opcode::Hash | Opcode::Destructure => &[2],
+            Opcode::Call | Opcode::TailCall | Opcode::SetLocal | Opcode::GetLocal | Opcode::GetFree | Opcode::GetBuiltin
+                | Opcode::PopN => &[1],
+            Opcode::Closure => &[2, 1],
+            _ => &[],
+        }
+    }
 }
 
-fn convert_u16_to_two_u8s_be(integer: u16) -> [u8; 2] {
-    [(integer >> 8) as u8, integer as u8]
+impl OpCode {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            OpCode::OpConstant(_) => Opcode::Constant,
+            OpCode::OpPop => Opcode::Pop,
+            OpCode::OpAdd => Opcode::Add,
+            OpCode::OpSub => Opcode::Sub,
+            OpCode::OpMul => Opcode::Mul,
+            OpCode::OpDiv => Opcode::Div,
+            OpCode::OpTrue => Opcode::True,
+            OpCode::OpFalse => Opcode::False,
+            OpCode::OpEquals => Opcode::Equals,
+            OpCode::OpNotEquals => Opcode::NotEquals,
+            OpCode::OpGreaterThan => Opcode::GreaterThan,
+            OpCode::OpMinus => Opcode::Minus,
+            OpCode::OpBang => Opcode::Bang,
+            OpCode::OpJumpNotTrue(_) => Opcode::JumpNotTrue,
+            OpCode::OpJump(_) => Opcode::Jump,
+            OpCode::OpSetGlobal(_) => Opcode::SetGlobal,
+            OpCode::OpGetGlobal(_) => Opcode::GetGlobal,
+            OpCode::OpNull => Opcode::Null,
+            OpCode::OpArray(_) => Opcode::Array,
+            OpCode::OpHash(_) => Opcode::Hash,
+            OpCode::OpIndex => Opcode::Index,
+            OpCode::OpCall(_) => Opcode::Call,
+            OpCode::OpReturnValue => Opcode::ReturnValue,
+            OpCode::OpReturn => Opcode::Return,
+            OpCode::OpSetLocal(_) => Opcode::SetLocal,
+            OpCode::OpGetLocal(_) => Opcode::GetLocal,
+            OpCode::OpClosure(..) => Opcode::Closure,
+            OpCode::OpGetFree(_) => Opcode::GetFree,
+            OpCode::OpGetBuiltin(_) => Opcode::GetBuiltin,
+            OpCode::OpLessThan => Opcode::LessThan,
+            OpCode::OpMod => Opcode::Mod,
+            OpCode::OpGreaterThanOrEqual => Opcode::GreaterThanOrEqual,
+            OpCode::OpLessThanOrEqual => Opcode::LessThanOrEqual,
+            OpCode::OpCurrentClosure => Opcode::CurrentClosure,
+            OpCode::OpBitAnd => Opcode::BitAnd,
+            OpCode::OpBitOr => Opcode::BitOr,
+            OpCode::OpBitXor => Opcode::BitXor,
+            OpCode::OpShl => Opcode::Shl,
+            OpCode::OpShr => Opcode::Shr,
+            OpCode::OpDup => Opcode::Dup,
+            OpCode::OpSwap => Opcode::Swap,
+            OpCode::OpDestructure(_) => Opcode::Destructure,
+            OpCode::OpTailCall(_) => Opcode::TailCall,
+            OpCode::OpPopN(_) => Opcode::PopN,
+            OpCode::OpArrayLength => Opcode::ArrayLength,
+            OpCode::OpSlice => Opcode::Slice,
+            OpCode::OpIntDiv => Opcode::IntDiv,
+        }
+    }
 }
 
 pub fn convert_two_u8s_be_to_usize(int1: u8, int2: u8) -> usize {
     ((int1 as usize) << 8) | int2 as usize
 }
 
-fn make_three_byte_op(code: u8, data: u16) -> Vec<u8> {
-    let mut output = vec![code];
-    output.extend(&convert_u16_to_two_u8s_be(data));
+/// encodes an instruction, each operand is written big endian in the width operand_widths gives it
+pub fn make(op: Opcode, operands: &[usize]) -> Vec<u8> {
+    let widths = op.operand_widths();
+    assert_eq!(widths.len(), operands.len(), "wrong number of operands for Op{:?}", op);
+
+    let mut output = vec![op as u8];
+    for (&width, &operand) in widths.iter().zip(operands) {
+        match width {
+            1 => output.push(operand as u8),
+            _ => output.extend(&(operand as u16).to_be_bytes()),
+        }
+    }
 
     output
 }
 
 pub fn make_op(op: OpCode) -> Vec<u8> {
-    match op {
-        OpCode::OpConstant(arg) => make_three_byte_op(0x01, arg),
-        OpCode::OpPop => vec![0x02],
-        OpCode::OpAdd => vec![0x03],
-        OpCode::OpSub => vec![0x04],
-        OpCode::OpMul => vec![0x05],
-        OpCode::OpDiv => vec![0x06],
-        OpCode::OpTrue => vec![0x07],
-        OpCode::OpFalse => vec![0x08],
-        OpCode::OpEquals => vec![0x09],
-        OpCode::OpNotEquals => vec![0x0A],
-        OpCode::OpGreaterThan => vec![0x0B],
-        OpCode::OpMinus => vec![0x0C],
-        OpCode::OpBang => vec![0x0D],
-        OpCode::OpJumpNotTrue(address) => make_three_byte_op(0x0E, address),
-        OpCode::OpJump(address) => make_three_byte_op(0x0F, address),
-        OpCode::OpSetGlobal(global_id) => make_three_byte_op(0x10, global_id),
-        OpCode::OpGetGlobal(global_id) => make_three_byte_op(0x11, global_id),
+    let operands = match op {
+        OpCode::OpConstant(operand)
+            | OpCode::OpJumpNotTrue(operand)
+            | OpCode::OpJump(operand)
+            | OpCode::OpSetGlobal(operand)
+            | OpCode::OpGetGlobal(operand)
+            | OpCode::OpArray(operand)
+            | OpCode::OpHash(operand)
+            | OpCode::OpDestructure(operand) => vec![operand as usize],
+        OpCode::OpCall(operand)
+            | OpCode::OpTailCall(operand)
+            | OpCode::OpSetLocal(operand)
+            | OpCode::OpGetLocal(operand)
+            | OpCode::OpGetFree(operand)
+            | OpCode::OpGetBuiltin(operand)
+            | OpCode::OpPopN(operand) => vec![operand as usize],
+        OpCode::OpClosure(const_index, num_free) => vec![const_index as usize, num_free as usize],
+        _ => vec![],
+    };
+
+    make(op.opcode(), &operands)
+}
+
+/// renders the instructions one per line, prefixed with the byte offset of each instruction
+pub fn disassemble(instructions: &[u8]) -> String {
+    let mut output = String::new();
+    let mut position = 0;
+
+    while position < instructions.len() {
+        let (line, next_position) = disassemble_instruction(instructions, position);
+        output.push_str(&line);
+        output.push('\n');
+        position = next_position;
+    }
+
+    output
+}
+
+/// disassembles the single instruction at the given position, returning it along with the position of the next one
+pub fn disassemble_instruction(instructions: &[u8], position: usize) -> (String, usize) {
+    let opcode = match Opcode::try_from(instructions[position]) {
+        Ok(opcode) => opcode,
+        Err(UnknownOpcode(byte)) => return (format!("{:04} ERROR: unknown opcode {:#04x}", position, byte), position + 1),
+    };
+
+    let mut line = format!("{:04} Op{:?}", position, opcode);
+    let mut operand_position = position + 1;
+    for &width in opcode.operand_widths() {
+        let operand = match instructions.get(operand_position..operand_position + width) {
+            Some(&[byte]) => byte as usize,
+            Some(&[byte1, byte2]) => convert_two_u8s_be_to_usize(byte1, byte2),
+            _ => {
+                // the instructions were cut short, nothing after this point can be decoded
+                line.push_str(" ERROR: missing operand");
+                return (line, instructions.len());
+            },
+        };
+        line.push_str(&format!(" {}", operand));
+        operand_position += width;
+    }
+
+    (line, operand_position)
+}
+
+/// maps byte offsets in some instructions to the source line they were compiled from, like cpython's lnotab
+/// there's an entry wherever the line changes, each one covering the offsets up to the next
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LineTable(Vec<(usize, usize)>);
+
+impl LineTable {
+    /// the instructions from offset onwards come from line
+    pub fn add(&mut self, offset: usize, line: usize) {
+        match self.0.last_mut() {
+            Some(&mut (_, last_line)) if last_line == line => {},
+            // nothing was emitted for the previous line
+            Some(last) if last.0 == offset => *last = (offset, line),
+            _ => self.0.push((offset, line)),
+        }
+    }
+
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.0.iter()
+            .take_while(|&&(start, _)| start <= offset)
+            .last()
+            .map(|&(_, line)| line)
+    }
+
+    /// moves every entry to a new offset, after instructions have been rewritten
+    pub fn remap(&self, new_offset: impl Fn(usize) -> usize) -> LineTable {
+        let mut table = LineTable::default();
+        for &(offset, line) in &self.0 {
+            table.add(new_offset(offset), line);
+        }
+
+        table
     }
 }
 
@@ -59,6 +355,35 @@ pub fn make_op(op: OpCode) -> Vec<u8> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn make_instructions() {
+        assert_eq!(vec![0x01, 0xFF, 0xFE], make(Opcode::Constant, &[65534]));
+        assert_eq!(vec![Opcode::Pop as u8], make(Opcode::Pop, &[]));
+        assert_eq!(vec![Opcode::GetLocal as u8, 0xFF], make(Opcode::GetLocal, &[255]));
+        assert_eq!(vec![Opcode::Closure as u8, 0xFF, 0xFE, 0xFF], make(Opcode::Closure, &[65534, 255]));
+        assert_eq!(make(Opcode::Closure, &[65534, 255]), make_op(OpCode::OpClosure(65534, 255)));
+
+        let (disassembled, _) = disassemble_instruction(&make(Opcode::Closure, &[65534, 255]), 0);
+        assert_eq!("0000 OpClosure 65534 255", disassembled);
+    }
+
+    #[test]
+    fn line_table() {
+        let mut table = LineTable::default();
+        table.add(0, 1);
+        table.add(4, 1);
+        table.add(6, 2);
+        table.add(6, 4);
+        table.add(10, 7);
+
+        assert_eq!(LineTable(vec![(0, 1), (6, 4), (10, 7)]), table);
+        assert_eq!(Some(1), table.line(5));
+        assert_eq!(Some(4), table.line(6));
+        assert_eq!(Some(7), table.line(100));
+        assert_eq!(None, LineTable::default().line(0));
+        assert_eq!(LineTable(vec![(0, 1), (1, 7)]), table.remap(|offset| offset / 6));
+    }
+
     #[test]
     fn make_op_constant() {
         assert_eq!(
@@ -70,7 +395,7 @@ mod tests {
     #[test]
     fn make_op_pop() {
         assert_eq!(
-            vec![0x02],
+            vec![Opcode::Pop as u8],
             make_op(OpCode::OpPop)
         );
     }
@@ -78,8 +403,83 @@ mod tests {
     #[test]
     fn make_op_add() {
         assert_eq!(
-            vec![0x03],
+            vec![Opcode::Add as u8],
             make_op(OpCode::OpAdd)
         );
     }
+
+    #[test]
+    fn make_op_get_local() {
+        assert_eq!(
+            vec![0x1A, 255],
+            make_op(OpCode::OpGetLocal(255))
+        );
+    }
+
+    #[test]
+    fn make_op_closure() {
+        assert_eq!(
+            vec![0x1B, 255, 254, 255],
+            make_op(OpCode::OpClosure(65534, 255))
+        );
+    }
+
+    #[test]
+    fn opcode_try_from() {
+        assert_eq!(Ok(Opcode::Constant), Opcode::try_from(0x01));
+        assert_eq!(Ok(Opcode::GetBuiltin), Opcode::try_from(0x1D));
+        assert_eq!(Ok(Opcode::CurrentClosure), Opcode::try_from(0x22));
+        assert_eq!(Ok(Opcode::Shr), Opcode::try_from(0x27));
+        assert_eq!(Ok(Opcode::Dup), Opcode::try_from(0x28));
+        assert_eq!(Ok(Opcode::Swap), Opcode::try_from(0x29));
+        assert_eq!(Ok(Opcode::Destructure), Opcode::try_from(0x2A));
+        assert_eq!(Ok(Opcode::TailCall), Opcode::try_from(0x2B));
+        assert_eq!(Ok(Opcode::PopN), Opcode::try_from(0x2C));
+        assert_eq!(Ok(Opcode::ArrayLength), Opcode::try_from(0x2D));
+        assert_eq!(Ok(Opcode::Slice), Opcode::try_from(0x2E));
+        assert_eq!(Ok(Opcode::IntDiv), Opcode::try_from(0x2F));
+        assert_eq!(Err(UnknownOpcode(0x00)), Opcode::try_from(0x00));
+        assert_eq!(Err(UnknownOpcode(0xFF)), Opcode::try_from(0xFF));
+    }
+
+    #[test]
+    fn make_op_matches_opcode() {
+        // the first byte of every encoded instruction identifies its opcode
+        for op in [OpCode::OpConstant(1), OpCode::OpPop, OpCode::OpClosure(1, 2), OpCode::OpGetBuiltin(3)] {
+            let opcode = op.opcode();
+            assert_eq!(Ok(opcode), Opcode::try_from(make_op(op)[0]));
+        }
+    }
+
+    #[test]
+    fn disassemble_instructions() {
+        let instructions: Vec<u8> = vec![
+            OpCode::OpConstant(1),
+            OpCode::OpPop,
+            OpCode::OpJump(10),
+            OpCode::OpGetLocal(2),
+            OpCode::OpClosure(65535, 3),
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            "0000 OpConstant 1\n0003 OpPop\n0004 OpJump 10\n0007 OpGetLocal 2\n0009 OpClosure 65535 3\n",
+            disassemble(&instructions)
+        );
+    }
+
+    #[test]
+    fn disassemble_invalid_instructions() {
+        assert_eq!(
+            "0000 ERROR: unknown opcode 0xff\n0001 OpConstant ERROR: missing operand\n",
+            disassemble(&[0xFF, 0x01, 0x00])
+        );
+    }
+
+    #[test]
+    fn disassemble_less_than() {
+        assert_eq!("0000 OpLessThan\n", disassemble(&make_op(OpCode::OpLessThan)));
+    }
 }
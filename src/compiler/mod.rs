@@ -1,55 +1,248 @@
-use crate::eval::Object;
-use crate::parser::{Statement, Expr, parse};
-use crate::code::{make_op, OpCode};
-use crate::lexer::lex;
+use crate::eval::{Object, CompiledFunction, shift};
+use crate::parser::{Statement, Expr, Pattern, ParseError, parse_with_lines};
+use crate::code::{disassemble, make_op, convert_two_u8s_be_to_usize, LineTable, OpCode, Opcode};
+use crate::lexer::lex_with_lines;
 use crate::parser::Operator;
 use crate::parser::Prefix;
-use crate::compiler::symbol_table::SymbolTable;
+use crate::parser::Postfix;
+use crate::builtins::BUILTINS;
+use std::rc::Rc;
+use std::convert::TryFrom;
 
 mod symbol_table;
+mod serialize;
+mod optimize;
 
-#[derive(Debug, PartialEq)]
+pub use self::serialize::DeserializeError;
+pub use self::optimize::optimize;
+pub use self::symbol_table::{Symbol, SymbolScope, SymbolTable};
+
+#[derive(Debug)]
 pub struct ByteCode {
     pub instructions: Vec<u8>,
-    pub constants: Vec<Object>
+    pub constants: Vec<Object>,
+    pub lines: LineTable, // for the main program, each compiled function has its own
+}
+
+// lines are only used to report where runtime errors happen, so they aren't compared
+impl PartialEq for ByteCode {
+    fn eq(&self, other: &Self) -> bool {
+        self.instructions == other.instructions && self.constants == other.constants
+    }
 }
 
 impl ByteCode {
+    /// the instructions followed by the numbered constant pool, compiled functions are listed with their own instructions
+    pub fn disassemble(&self) -> String {
+        let mut output = String::from("instructions:\n");
+        output.push_str(&disassemble(&self.instructions));
+
+        output.push_str("constants:\n");
+        for (index, constant) in self.constants.iter().enumerate() {
+            output.push_str(&format!("{} {}\n", index, constant.inspect()));
+            if let Object::CompiledFunction(function) = constant {
+                for line in disassemble(&function.instructions).lines() {
+                    output.push_str(&format!("    {}\n", line));
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    Parse(ParseError),
+    InvalidEscape(char), // the character after the backslash of an unknown escape sequence in a string
+    UndefinedVariable(String),
+    InvalidAssignment(String),
+    InvalidIncrementTarget,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    // the limits set by the size of the operands the instructions are encoded with
+    TooManyConstants,
+    TooManyGlobals,
+    TooManyLocals,
+    TooManyFreeVariables,
+    TooManyArguments,
+    TooManyElements,
+    ProgramTooLarge,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::Parse(error) => write!(f, "{}", error),
+            CompileError::InvalidEscape(c) => write!(f, "unknown escape sequence in string: \\{}", c),
+            CompileError::UndefinedVariable(name) => write!(f, "undefined variable: {}", name),
+            CompileError::InvalidAssignment(name) => write!(f, "cannot assign to {}", name),
+            CompileError::InvalidIncrementTarget => write!(f, "only variables can be incremented or decremented"),
+            CompileError::BreakOutsideLoop => write!(f, "break outside of a loop"),
+            CompileError::ContinueOutsideLoop => write!(f, "continue outside of a loop"),
+            CompileError::TooManyConstants => write!(f, "more than 65536 constants"),
+            CompileError::TooManyGlobals => write!(f, "more than 65536 global variables"),
+            CompileError::TooManyLocals => write!(f, "more than 256 local variables in a function"),
+            CompileError::TooManyFreeVariables => write!(f, "more than 256 variables captured by a closure"),
+            CompileError::TooManyArguments => write!(f, "more than 255 arguments in a call"),
+            CompileError::TooManyElements => write!(f, "more than 65535 elements in an array, hash or pattern"),
+            CompileError::ProgramTooLarge => write!(f, "program too large, jumps can only reach the first 65536 bytes of instructions"),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// a while loop being compiled
+struct Loop {
+    start_position: u16, // where its condition is checked, which continue jumps back to
+    breaks: Vec<usize>, // the positions of the jumps its breaks make, patched once the end of the loop is known
+    pending_values: usize, // the values enclosing expressions had left on the stack when the loop started
+}
+
+/// the instructions being emitted for the main program or for a single function body
+struct CompilationScope {
+    instructions: Vec<u8>,
+    lines: LineTable,
+    // positions are tracked so an operand byte is never mistaken for an OpPop
+    last_instruction_position: Option<usize>,
+    previous_instruction_position: Option<usize>,
+    // positions of the calls a function makes to itself, the ones in tail position become tail calls
+    self_calls: Vec<usize>,
+    // the loops being compiled, innermost last
+    loops: Vec<Loop>,
+    // the values the partly compiled expressions around the current one have left on the stack,
+    //   which a break or continue inside an if expression has to pop before it jumps
+    pending_values: usize,
+}
+
+impl CompilationScope {
     fn new() -> Self {
-        ByteCode {
+        CompilationScope {
             instructions: Vec::new(),
-            constants: Vec::new(),
+            lines: LineTable::default(),
+            last_instruction_position: None,
+            previous_instruction_position: None,
+            self_calls: Vec::new(),
+            loops: Vec::new(),
+            pending_values: 0,
         }
     }
 }
 
 struct Compiler {
-    byte_code: ByteCode,
+    constants: Vec<Object>,
     symbol_table: SymbolTable,
+    scopes: Vec<CompilationScope>,
 }
 
 impl Compiler {
-    fn compile_from_source(input: &str) -> ByteCode {
-        let mut compiler = Compiler {
-            byte_code: ByteCode::new(),
-            symbol_table: SymbolTable::new(),
+    fn new_with_state(symbol_table: SymbolTable, constants: Vec<Object>) -> Self {
+        Compiler {
+            constants,
+            symbol_table,
+            scopes: vec![CompilationScope::new()],
+        }
+    }
+
+    /// the symbol table a program starts with, holding only the builtin functions
+    fn global_symbol_table() -> SymbolTable {
+        let mut symbol_table = SymbolTable::new();
+        for (index, (name, _)) in BUILTINS.iter().enumerate() {
+            symbol_table.define_builtin(index, name.to_string());
+        }
+
+        symbol_table
+    }
+
+    fn compile(mut self, input: &str) -> Result<(ByteCode, SymbolTable), CompileError> {
+        let (tokens, lines) = lex_with_lines(input);
+        let ast = parse_with_lines(tokens, lines).map_err(|error| match error {
+            ParseError::InvalidEscape(c) => CompileError::InvalidEscape(c),
+            error => CompileError::Parse(error),
+        })?;
+        // top-level functions can refer to each other regardless of the order they're defined in
+        for statement in &ast {
+            match statement {
+                Statement::Let { name, .. } => self.symbol_table.declare_global(name.clone()),
+                Statement::Destructure { pattern, .. } => self.declare_pattern(pattern),
+                _ => {},
+            }
+        }
+        self.compile_statements(ast)?;
+
+        let main_scope = self.scopes.pop().expect("main compilation scope should exist");
+        let byte_code = ByteCode {
+            instructions: main_scope.instructions,
+            constants: self.constants,
+            lines: main_scope.lines,
         };
 
-        let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
-        compiler.compile_statements(ast);
+        Ok((byte_code, self.symbol_table))
+    }
+
+    fn declare_pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Ident(name) => self.symbol_table.declare_global(name.clone()),
+            Pattern::Array(elements) => elements.iter().for_each(|element| self.declare_pattern(element)),
+        }
+    }
+
+    fn current_scope(&self) -> &CompilationScope {
+        self.scopes.last().expect("compiler should always have a scope")
+    }
+
+    fn current_scope_mut(&mut self) -> &mut CompilationScope {
+        self.scopes.last_mut().expect("compiler should always have a scope")
+    }
+
+    /// the position the next instruction will be written to in the current scope
+    fn current_position(&self) -> usize {
+        self.current_scope().instructions.len()
+    }
+
+    /// the current position as the operand of a jump to it
+    fn jump_target(&self) -> Result<u16, CompileError> {
+        operand(self.current_position(), CompileError::ProgramTooLarge)
+    }
+
+    fn enter_scope(&mut self) {
+        self.scopes.push(CompilationScope::new());
+
+        let outer = std::mem::replace(&mut self.symbol_table, SymbolTable::new());
+        self.symbol_table = SymbolTable::new_enclosed(outer);
+    }
 
-        compiler.byte_code
+    /// returns the scope being left, the number of locals it defined,
+    /// and the symbols it captured from enclosing scopes
+    fn leave_scope(&mut self) -> (CompilationScope, usize, Vec<Symbol>) {
+        let scope = self.scopes.pop().expect("attempted to leave the main compilation scope");
+
+        let symbol_table = std::mem::replace(&mut self.symbol_table, SymbolTable::new());
+        let num_locals = symbol_table.num_definitions();
+        let free_symbols = symbol_table.free_symbols().to_vec();
+        self.symbol_table = symbol_table.into_outer().expect("attempted to leave the global symbol table");
+
+        (scope, num_locals, free_symbols)
     }
 
-    fn add_constant(&mut self, obj: Object) -> u16 {
-        self.byte_code.constants.push(obj);
-        (self.byte_code.constants.len() - 1) as u16 // cast to u16 because that is the size of our constant pool index
+    fn add_constant(&mut self, obj: Object) -> Result<u16, CompileError> {
+        // literals are immutable, so equal ones can share a slot in the constant pool
+        if let Some(index) = self.constants.iter().position(|constant| same_literal(constant, &obj)) {
+            return operand(index, CompileError::TooManyConstants);
+        }
+
+        self.constants.push(obj);
+        operand(self.constants.len() - 1, CompileError::TooManyConstants)
     }
 
-    fn add_instruction(&mut self, op_code: OpCode) -> u16 {
-        let position_of_new_instruction = self.byte_code.instructions.len() as u16;
-        self.byte_code.instructions.extend(make_op(op_code));
+    fn add_instruction(&mut self, op_code: OpCode) -> usize {
+        let scope = self.current_scope_mut();
+        let position_of_new_instruction = scope.instructions.len();
+        scope.instructions.extend(make_op(op_code));
+
+        scope.previous_instruction_position = scope.last_instruction_position;
+        scope.last_instruction_position = Some(position_of_new_instruction);
 
         position_of_new_instruction
     }
@@ -57,127 +250,646 @@ impl Compiler {
     fn change_op(&mut self, position: usize, op_code: OpCode) {
         let op_bytes = make_op(op_code);
 
-        self.byte_code.instructions.splice(position..position+op_bytes.len(), op_bytes);
+        self.current_scope_mut().instructions.splice(position..position+op_bytes.len(), op_bytes);
+    }
+
+    /// compiles an expression while the given number of values, the operands before it, are on the stack
+    fn compile_operand(&mut self, expr: Expr, pending_values: usize) -> Result<(), CompileError> {
+        self.current_scope_mut().pending_values += pending_values;
+        let result = self.compile_expression(expr);
+        self.current_scope_mut().pending_values -= pending_values;
+
+        result
     }
 
-    fn compile_expression(&mut self, expr: Expr) {
+    fn compile_expression(&mut self, expr: Expr) -> Result<(), CompileError> {
+        let expr = fold_constant(&expr).unwrap_or(expr);
         match expr {
             Expr::Const(num) => {
-                let const_index = self.add_constant(Object::Integer(num));
+                let const_index = self.add_constant(Object::Integer(num))?;
+                self.add_instruction(OpCode::OpConstant(const_index));
+            },
+            Expr::Float(num) => {
+                let const_index = self.add_constant(Object::Float(num))?;
+                self.add_instruction(OpCode::OpConstant(const_index));
+            },
+            Expr::String(string) => {
+                let const_index = self.add_constant(Object::String(string))?;
                 self.add_instruction(OpCode::OpConstant(const_index));
             },
+            Expr::Infix { left, operator: Operator::And, right } => self.compile_and(*left, *right)?,
+            Expr::Infix { left, operator: Operator::Or, right } => self.compile_or(*left, *right)?,
             Expr::Infix { left, operator, right } => {
-                match &operator {
-                    Operator::LessThan => {
-                        // flip left/right order so that less than statements can be re-written as greater than statements
-                        // this allows the vm to only support a greater than instruction
-                        self.compile_expression(*right);
-                        self.compile_expression(*left);
-                    },
-                    _ => {
-                        self.compile_expression(*left);
-                        self.compile_expression(*right);
-                    }
-                }
+                self.compile_expression(*left)?;
+                self.compile_operand(*right, 1)?;
                 match operator {
                     Operator::Plus => self.add_instruction(OpCode::OpAdd),
                     Operator::Minus => self.add_instruction(OpCode::OpSub),
                     Operator::Multiply => self.add_instruction(OpCode::OpMul),
                     Operator::Divide => self.add_instruction(OpCode::OpDiv),
+                    Operator::IntDivide => self.add_instruction(OpCode::OpIntDiv),
+                    Operator::Modulo => self.add_instruction(OpCode::OpMod),
                     Operator::Equals => self.add_instruction(OpCode::OpEquals),
                     Operator::NotEquals => self.add_instruction(OpCode::OpNotEquals),
-                    Operator::GreaterThan | Operator::LessThan => {
-                        // greater than and less than can share one op-code because the
-                        //    order of the operands are flipped when they are pushed on to the stack
-                        self.add_instruction(OpCode::OpGreaterThan)
-                    },
+                    Operator::GreaterThan => self.add_instruction(OpCode::OpGreaterThan),
+                    Operator::LessThan => self.add_instruction(OpCode::OpLessThan),
+                    Operator::GreaterThanOrEqual => self.add_instruction(OpCode::OpGreaterThanOrEqual),
+                    Operator::LessThanOrEqual => self.add_instruction(OpCode::OpLessThanOrEqual),
+                    Operator::BitAnd => self.add_instruction(OpCode::OpBitAnd),
+                    Operator::BitOr => self.add_instruction(OpCode::OpBitOr),
+                    Operator::BitXor => self.add_instruction(OpCode::OpBitXor),
+                    Operator::ShiftLeft => self.add_instruction(OpCode::OpShl),
+                    Operator::ShiftRight => self.add_instruction(OpCode::OpShr),
+                    Operator::And | Operator::Or => unreachable!("logical operators are compiled with jumps"),
                 };
             },
             Expr::Prefix {prefix: Prefix::Minus, value} => {
-                self.compile_expression(*value);
+                self.compile_expression(*value)?;
                 self.add_instruction(OpCode::OpMinus);
             },
             Expr::Prefix {prefix: Prefix::Bang, value} => {
-                self.compile_expression(*value);
+                self.compile_expression(*value)?;
                 self.add_instruction(OpCode::OpBang);
             },
+            Expr::Postfix {postfix, value} => {
+                let symbol = match *value {
+                    Expr::Ident(name) => self.assignable_symbol(name)?,
+                    _ => return Err(CompileError::InvalidIncrementTarget),
+                };
+                // the value from before the update is left on the stack as the value of the expression
+                self.load_symbol(symbol.clone())?;
+                self.add_instruction(OpCode::OpDup);
+                let const_index = self.add_constant(Object::Integer(1))?;
+                self.add_instruction(OpCode::OpConstant(const_index));
+                match postfix {
+                    Postfix::Increment => self.add_instruction(OpCode::OpAdd),
+                    Postfix::Decrement => self.add_instruction(OpCode::OpSub),
+                };
+                self.store_symbol(symbol)?;
+            },
             Expr::Boolean(true) => { self.add_instruction(OpCode::OpTrue); },
             Expr::Boolean(false) => { self.add_instruction(OpCode::OpFalse); },
             Expr::If {condition, consequence, alternative} => {
-                self.compile_expression(*condition);
-                let op_jump_position = self.byte_code.instructions.len();
-                self.add_instruction(OpCode::OpJumpNotTrue(9999));
-                self.compile_statements(consequence);
-                if self.last_instruction_is_pop() {
-                    self.remove_last_pop();
-                }
+                self.compile_expression(*condition)?;
+                let op_jump_not_true_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+                self.compile_block(consequence)?;
+
+                let op_jump_position = self.add_instruction(OpCode::OpJump(9999));
+                self.change_op(
+                    op_jump_not_true_position,
+                    OpCode::OpJumpNotTrue(self.jump_target()?)
+                );
+
                 if alternative.is_empty() {
-                    self.change_op(
-                        op_jump_position,
-                        OpCode::OpJumpNotTrue(self.byte_code.instructions.len() as u16)
-                    );
+                    // an if without an else still has to leave a value on the stack
+                    self.add_instruction(OpCode::OpNull);
                 } else {
-                    self.change_op(
-                        op_jump_position,
-                        OpCode::OpJumpNotTrue(self.byte_code.instructions.len() as u16 + 3) // plus three to account for extra jump at end of if block
-                    );
-
-                    let op_jump_position = self.byte_code.instructions.len();
-                    self.add_instruction(OpCode::OpJump(9999));
-                    self.compile_statements(alternative);
-                    if self.last_instruction_is_pop() {
-                        self.remove_last_pop();
-                    }
-                    self.change_op(
-                        op_jump_position,
-                        OpCode::OpJump(self.byte_code.instructions.len() as u16)
-                    );
+                    self.compile_block(alternative)?;
                 }
+                self.change_op(
+                    op_jump_position,
+                    OpCode::OpJump(self.jump_target()?)
+                );
             },
             Expr::Ident(name) => {
                 match self.symbol_table.resolve(&name) {
-                    None => panic!("attempted to use undefined variable"),
-                    Some(index) => {
-                        self.add_instruction(OpCode::OpGetGlobal(index));
-                    },
+                    None => return Err(CompileError::UndefinedVariable(name)),
+                    Some(symbol) => self.load_symbol(symbol)?,
+                }
+            },
+            Expr::Array(elements) => {
+                let length = operand(elements.len(), CompileError::TooManyElements)?;
+                for (position, element) in elements.into_iter().enumerate() {
+                    self.compile_operand(element, position)?;
+                }
+                self.add_instruction(OpCode::OpArray(length));
+            },
+            Expr::Hash(pairs) => {
+                let length = operand(pairs.len(), CompileError::TooManyElements)?;
+                for (position, (key, value)) in pairs.into_iter().enumerate() {
+                    self.compile_operand(key, 2 * position)?;
+                    self.compile_operand(value, 2 * position + 1)?;
+                }
+                self.add_instruction(OpCode::OpHash(length));
+            },
+            Expr::Index {left, index} => {
+                self.compile_expression(*left)?;
+                self.compile_operand(*index, 1)?;
+                self.add_instruction(OpCode::OpIndex);
+            },
+            Expr::Slice {left, start, end} => {
+                self.compile_expression(*left)?;
+                for (position, bound) in IntoIterator::into_iter([start, end]).enumerate() {
+                    match bound {
+                        Some(bound) => self.compile_operand(*bound, 1 + position)?,
+                        None => { self.add_instruction(OpCode::OpNull); },
+                    }
+                }
+                self.add_instruction(OpCode::OpSlice);
+            },
+            Expr::Function {parameters, body} => self.compile_function(None, parameters, body)?,
+            Expr::Call {function, arguments} => {
+                let num_args = operand(arguments.len(), CompileError::TooManyArguments)?;
+
+                self.compile_expression(*function)?;
+                let is_self_call = self.last_instruction_is(Opcode::CurrentClosure);
+                for (position, argument) in arguments.into_iter().enumerate() {
+                    self.compile_operand(argument, 1 + position)?;
+                }
+                let position = self.add_instruction(OpCode::OpCall(num_args));
+                if is_self_call {
+                    self.current_scope_mut().self_calls.push(position);
                 }
             },
-            _ => panic!("unsupported expression"),
         };
+
+        Ok(())
+    }
+
+    /// the right side is skipped when the left side is false, either side being false pushes false
+    fn compile_and(&mut self, left: Expr, right: Expr) -> Result<(), CompileError> {
+        self.compile_expression(left)?;
+        let left_jump_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+        self.compile_expression(right)?;
+        let right_jump_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+        self.add_instruction(OpCode::OpTrue);
+        let end_jump_position = self.add_instruction(OpCode::OpJump(9999));
+
+        let false_position = self.jump_target()?;
+        self.add_instruction(OpCode::OpFalse);
+        self.change_op(left_jump_position, OpCode::OpJumpNotTrue(false_position));
+        self.change_op(right_jump_position, OpCode::OpJumpNotTrue(false_position));
+        self.change_op(end_jump_position, OpCode::OpJump(self.jump_target()?));
+
+        Ok(())
+    }
+
+    /// the right side is skipped when the left side is true, either side being true pushes true
+    fn compile_or(&mut self, left: Expr, right: Expr) -> Result<(), CompileError> {
+        self.compile_expression(left)?;
+        let left_jump_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+        self.add_instruction(OpCode::OpTrue);
+        let left_end_jump_position = self.add_instruction(OpCode::OpJump(9999));
+
+        self.change_op(left_jump_position, OpCode::OpJumpNotTrue(self.jump_target()?));
+        self.compile_expression(right)?;
+        let right_jump_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+        self.add_instruction(OpCode::OpTrue);
+        let right_end_jump_position = self.add_instruction(OpCode::OpJump(9999));
+
+        self.change_op(right_jump_position, OpCode::OpJumpNotTrue(self.jump_target()?));
+        self.add_instruction(OpCode::OpFalse);
+        let end_position = self.jump_target()?;
+        self.change_op(left_end_jump_position, OpCode::OpJump(end_position));
+        self.change_op(right_end_jump_position, OpCode::OpJump(end_position));
+
+        Ok(())
     }
 
     fn last_instruction_is_pop(&self) -> bool {
-        self.byte_code.instructions.last() == Some(&make_op(OpCode::OpPop)[0])
+        self.last_instruction_is(Opcode::Pop)
+    }
+
+    fn last_instruction_is(&self, opcode: Opcode) -> bool {
+        let scope = self.current_scope();
+        match scope.last_instruction_position {
+            Some(position) => scope.instructions[position] == opcode as u8,
+            None => false,
+        }
     }
 
     fn remove_last_pop(&mut self) {
-        self.byte_code.instructions.pop();
+        let scope = self.current_scope_mut();
+        scope.instructions.pop();
+        scope.last_instruction_position = scope.previous_instruction_position;
+        scope.previous_instruction_position = None;
+    }
+
+    /// compiles the statements of a block so that exactly one value is left on the stack
+    /// blocks which don't end in an expression (empty blocks, or blocks ending in let) produce null
+    fn compile_block(&mut self, statements: Vec<Statement>) -> Result<(), CompileError> {
+        let instructions_before_block = self.current_position();
+        self.compile_statements(statements)?;
+
+        let block_is_empty = self.current_position() == instructions_before_block;
+        if !block_is_empty && self.last_instruction_is_pop() {
+            self.remove_last_pop();
+        } else {
+            self.add_instruction(OpCode::OpNull);
+        }
+
+        Ok(())
+    }
+
+    /// a function bound by let is given its name, which refers to the function itself inside its body
+    fn compile_function(&mut self, name: Option<String>, parameters: Vec<String>, body: Vec<Statement>) -> Result<(), CompileError> {
+        let num_parameters = parameters.len();
+
+        self.enter_scope();
+        if let Some(name) = name {
+            self.symbol_table.define_function_name(name);
+        }
+        // parameters are the first locals of the function, the caller places the arguments in their slots
+        for parameter in parameters {
+            self.symbol_table.define(parameter);
+        }
+        self.compile_statements(body)?;
+        if self.last_instruction_is_pop() {
+            // the value of the final expression is implicitly returned
+            self.remove_last_pop();
+            self.add_instruction(OpCode::OpReturnValue);
+        } else if !self.last_instruction_is(Opcode::ReturnValue) {
+            self.add_instruction(OpCode::OpReturn);
+        }
+        self.mark_tail_calls();
+        let (scope, num_locals, free_symbols) = self.leave_scope();
+
+        // push the captured values so the vm can bundle them with the function into a closure
+        let num_free = operand(free_symbols.len(), CompileError::TooManyFreeVariables)?;
+        for symbol in free_symbols {
+            self.load_symbol(symbol)?;
+        }
+
+        let function = CompiledFunction { instructions: scope.instructions, num_locals, num_parameters, lines: scope.lines };
+        let const_index = self.add_constant(Object::CompiledFunction(Rc::new(function)))?;
+        self.add_instruction(OpCode::OpClosure(const_index, num_free));
+
+        Ok(())
+    }
+
+    /// turns the calls a function makes to itself into tail calls when they're in tail position,
+    /// which is when the instruction after the call is an OpReturnValue, or an OpJump which
+    /// leads to one, possibly through further OpJumps. nothing can happen to the result of
+    /// such a call except being returned, so the vm is free to reuse the caller's frame for it
+    fn mark_tail_calls(&mut self) {
+        let scope = self.current_scope();
+        let instructions = &scope.instructions;
+        let tail_calls: Vec<(usize, u8)> = scope.self_calls.iter()
+            .filter(|&&position| {
+                let mut next = position + 2;
+                // a chain can't be longer than the number of instructions without looping forever
+                for _ in 0..instructions.len() {
+                    match instructions.get(next) {
+                        Some(&op) if op == Opcode::ReturnValue as u8 => return true,
+                        Some(&op) if op == Opcode::Jump as u8 => {
+                            next = convert_two_u8s_be_to_usize(instructions[next + 1], instructions[next + 2]);
+                        },
+                        _ => return false,
+                    }
+                }
+                false
+            })
+            .map(|&position| (position, instructions[position + 1]))
+            .collect();
+
+        for (position, num_args) in tail_calls {
+            self.change_op(position, OpCode::OpTailCall(num_args));
+        }
+    }
+
+    fn load_symbol(&mut self, symbol: Symbol) -> Result<(), CompileError> {
+        let op_code = match symbol.scope {
+            SymbolScope::Global => OpCode::OpGetGlobal(operand(symbol.index, CompileError::TooManyGlobals)?),
+            SymbolScope::Local => OpCode::OpGetLocal(operand(symbol.index, CompileError::TooManyLocals)?),
+            SymbolScope::Free => OpCode::OpGetFree(operand(symbol.index, CompileError::TooManyFreeVariables)?),
+            SymbolScope::Builtin => OpCode::OpGetBuiltin(u8::try_from(symbol.index).expect("there should be fewer than 256 builtins")),
+            SymbolScope::Function => OpCode::OpCurrentClosure,
+        };
+        self.add_instruction(op_code);
+
+        Ok(())
+    }
+
+    /// assignment writes to the slot the variable already has rather than defining a new one
+    fn assignable_symbol(&mut self, name: String) -> Result<Symbol, CompileError> {
+        match self.symbol_table.resolve(&name) {
+            None => Err(CompileError::UndefinedVariable(name)),
+            // closures capture values, so there is no slot in the enclosing function to write back to
+            // nor is there a slot for the function's own name, or for a builtin
+            Some(Symbol { scope: SymbolScope::Free, .. })
+                | Some(Symbol { scope: SymbolScope::Builtin, .. })
+                | Some(Symbol { scope: SymbolScope::Function, .. }) => {
+                Err(CompileError::InvalidAssignment(name))
+            },
+            Some(symbol) => Ok(symbol),
+        }
+    }
+
+    fn store_symbol(&mut self, symbol: Symbol) -> Result<(), CompileError> {
+        let op_code = match symbol.scope {
+            SymbolScope::Global => OpCode::OpSetGlobal(operand(symbol.index, CompileError::TooManyGlobals)?),
+            SymbolScope::Local => OpCode::OpSetLocal(operand(symbol.index, CompileError::TooManyLocals)?),
+            SymbolScope::Free => panic!("attempted to assign to a captured variable"),
+            SymbolScope::Builtin => panic!("attempted to assign to a builtin function"),
+            SymbolScope::Function => panic!("attempted to assign to the function being compiled"),
+        };
+        self.add_instruction(op_code);
+
+        Ok(())
+    }
+
+    /// stores the value on top of the stack into the variables of the pattern
+    fn compile_pattern(&mut self, pattern: Pattern) -> Result<(), CompileError> {
+        match pattern {
+            Pattern::Ident(name) => {
+                let symbol = self.symbol_table.define(name);
+                self.store_symbol(symbol)?;
+            },
+            Pattern::Array(elements) => {
+                // replaces the array with its elements, the first of them on top
+                self.add_instruction(OpCode::OpDestructure(operand(elements.len(), CompileError::TooManyElements)?));
+                for element in elements {
+                    self.compile_pattern(element)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// compiles the body of a loop whose condition has just been checked by the OpJumpNotTrue at the
+    /// given position, jumping back to the start of the loop after each iteration
+    fn compile_loop_body(&mut self, start_position: u16, op_jump_not_true_position: usize, body: Vec<Statement>) -> Result<(), CompileError> {
+        // the body is made of statements, so each iteration leaves the stack as it found it
+        let pending_values = self.current_scope().pending_values;
+        self.current_scope_mut().loops.push(Loop { start_position, breaks: Vec::new(), pending_values });
+        self.compile_statements(body)?;
+        self.add_instruction(OpCode::OpJump(start_position));
+
+        let loop_end_position = self.jump_target()?;
+        self.change_op(op_jump_not_true_position, OpCode::OpJumpNotTrue(loop_end_position));
+        let compiled_loop = self.current_scope_mut().loops.pop().expect("loop should have been entered");
+        for break_position in compiled_loop.breaks {
+            self.change_op(break_position, OpCode::OpJump(loop_end_position));
+        }
+
+        Ok(())
+    }
+
+    /// a loop over the indexes of the array, which is evaluated once before the loop starts.
+    /// the loop variable, and the array, length and index it's read with, only exist inside the loop
+    fn compile_for(&mut self, variable: String, iterable: Expr, body: Vec<Statement>) -> Result<(), CompileError> {
+        self.compile_expression(iterable)?;
+        // these names can't be written in source, so the body can't refer to them
+        let (array, shadowed_array) = self.symbol_table.define_block_scoped(String::from(" for array"));
+        let (length, shadowed_length) = self.symbol_table.define_block_scoped(String::from(" for length"));
+        let (index, shadowed_index) = self.symbol_table.define_block_scoped(String::from(" for index"));
+        self.add_instruction(OpCode::OpDup);
+        self.store_symbol(array.clone())?;
+        self.add_instruction(OpCode::OpArrayLength);
+        self.store_symbol(length.clone())?;
+        let zero = self.add_constant(Object::Integer(0))?;
+        self.add_instruction(OpCode::OpConstant(zero));
+        self.store_symbol(index.clone())?;
+
+        let loop_start_position = self.jump_target()?;
+        self.load_symbol(index.clone())?;
+        self.load_symbol(length.clone())?;
+        self.add_instruction(OpCode::OpLessThan);
+        let op_jump_not_true_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+
+        let (element, shadowed_variable) = self.symbol_table.define_block_scoped(variable.clone());
+        self.load_symbol(array.clone())?;
+        self.load_symbol(index.clone())?;
+        self.add_instruction(OpCode::OpIndex);
+        self.store_symbol(element.clone())?;
+        // the index moves on before the body runs, so continue can jump straight back to the condition
+        self.load_symbol(index.clone())?;
+        let one = self.add_constant(Object::Integer(1))?;
+        self.add_instruction(OpCode::OpConstant(one));
+        self.add_instruction(OpCode::OpAdd);
+        self.store_symbol(index.clone())?;
+        self.compile_loop_body(loop_start_position, op_jump_not_true_position, body)?;
+
+        // the slots aren't reused by later code, so they're cleared rather than keeping the array alive
+        for symbol in IntoIterator::into_iter([array, length, index, element]) {
+            self.add_instruction(OpCode::OpNull);
+            self.store_symbol(symbol)?;
+        }
+
+        self.symbol_table.end_block_scoped(variable, shadowed_variable);
+        self.symbol_table.end_block_scoped(String::from(" for index"), shadowed_index);
+        self.symbol_table.end_block_scoped(String::from(" for length"), shadowed_length);
+        self.symbol_table.end_block_scoped(String::from(" for array"), shadowed_array);
+
+        Ok(())
+    }
+
+    /// statements leave the stack as they found it and locals live in their own slots, so the only
+    /// values a break or continue leaves behind are the operands of an expression it's nested in,
+    /// as in 1 + if (done) { break; } else { 2 }, and those are popped before the jump
+    fn pop_pending_values(&mut self) {
+        let scope = self.current_scope();
+        let innermost = scope.loops.last().expect("break and continue are only compiled inside a loop");
+        let mut pending_values = scope.pending_values - innermost.pending_values;
+        while pending_values > 0 {
+            let count = pending_values.min(u8::MAX as usize);
+            self.add_instruction(OpCode::OpPopN(count as u8));
+            pending_values -= count;
+        }
     }
 
-    fn compile_statements(&mut self, ast: Vec<Statement>) {
+    fn compile_statements(&mut self, ast: Vec<Statement>) -> Result<(), CompileError> {
         for statement in ast {
             match statement {
                 Statement::Let { name, value } => {
-                    self.compile_expression(value);
-                    let symbol_index = self.symbol_table.define(name);
-                    self.add_instruction(OpCode::OpSetGlobal(symbol_index));
+                    match value {
+                        Expr::Function {parameters, body} => self.compile_function(Some(name.clone()), parameters, body)?,
+                        value => self.compile_expression(value)?,
+                    }
+                    let symbol = self.symbol_table.define(name);
+                    self.store_symbol(symbol)?;
+                },
+                Statement::Destructure { pattern, value } => {
+                    self.compile_expression(value)?;
+                    self.compile_pattern(pattern)?;
+                },
+                Statement::Assign { name, value } => {
+                    let symbol = self.assignable_symbol(name)?;
+                    self.compile_expression(value)?;
+                    self.store_symbol(symbol)?;
+                },
+                Statement::Return { value } => {
+                    self.compile_expression(value)?;
+                    self.add_instruction(OpCode::OpReturnValue);
                 },
-                Statement::Return { .. } => unimplemented!(),
                 Statement::Expression(expr) => {
-                    self.compile_expression(expr);
+                    self.compile_expression(expr)?;
 
                     // pop one element from the stack after each expression statement to clean up
                     self.add_instruction(OpCode::OpPop);
                 },
+                Statement::Line(line) => {
+                    let position = self.current_position();
+                    self.current_scope_mut().lines.add(position, line);
+                },
+                Statement::While { condition, body } => {
+                    let loop_start_position = self.jump_target()?;
+                    self.compile_expression(condition)?;
+                    let op_jump_not_true_position = self.add_instruction(OpCode::OpJumpNotTrue(9999));
+                    self.compile_loop_body(loop_start_position, op_jump_not_true_position, body)?;
+                },
+                Statement::For { variable, iterable, body } => self.compile_for(variable, iterable, body)?,
+                Statement::Break => {
+                    // a function can't break out of a loop it is called from, each scope has its own loops
+                    if self.current_scope().loops.is_empty() {
+                        return Err(CompileError::BreakOutsideLoop);
+                    }
+                    self.pop_pending_values();
+                    let break_position = self.add_instruction(OpCode::OpJump(9999));
+                    self.current_scope_mut().loops.last_mut().expect("checked above").breaks.push(break_position);
+                },
+                Statement::Continue => {
+                    let start_position = match self.current_scope().loops.last() {
+                        Some(innermost) => innermost.start_position,
+                        None => return Err(CompileError::ContinueOutsideLoop),
+                    };
+                    self.pop_pending_values();
+                    self.add_instruction(OpCode::OpJump(start_position));
+                },
             }
         }
+
+        Ok(())
+    }
+}
+
+/// the value as an operand of an instruction, or the given error when it's too big to be encoded in one
+fn operand<T: TryFrom<usize>>(value: usize, error: CompileError) -> Result<T, CompileError> {
+    T::try_from(value).map_err(|_| error)
+}
+
+/// the literal an arithmetic, bitwise or comparison expression evaluates to, when it's built only
+/// from integer and boolean literals. && and || are left alone, they're compiled with jumps
+///
+/// nothing is folded which the vm would treat differently, so an operation which overflows,
+/// divides by zero, or mixes types is left for the vm to raise its error at run time
+fn fold_constant(expr: &Expr) -> Option<Expr> {
+    match expr {
+        Expr::Const(num) => Some(Expr::Const(*num)),
+        Expr::Boolean(bool) => Some(Expr::Boolean(*bool)),
+        Expr::Prefix { prefix, value } => match (prefix, fold_constant(value)?) {
+            (Prefix::Minus, Expr::Const(num)) => num.checked_neg().map(Expr::Const),
+            (Prefix::Bang, Expr::Boolean(bool)) => Some(Expr::Boolean(!bool)),
+            _ => None,
+        },
+        Expr::Infix { left, operator, right } => match (fold_constant(left)?, fold_constant(right)?) {
+            (Expr::Const(left), Expr::Const(right)) => fold_integers(left, operator, right),
+            (Expr::Boolean(left), Expr::Boolean(right)) => match operator {
+                Operator::Equals => Some(Expr::Boolean(left == right)),
+                Operator::NotEquals => Some(Expr::Boolean(left != right)),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
     }
+}
+
+fn fold_integers(left: i32, operator: &Operator, right: i32) -> Option<Expr> {
+    let result = match operator {
+        Operator::Plus => left.checked_add(right)?,
+        Operator::Minus => left.checked_sub(right)?,
+        Operator::Multiply => left.checked_mul(right)?,
+        Operator::Divide | Operator::IntDivide => left.checked_div(right)?,
+        Operator::Modulo => left.checked_rem(right)?,
+        Operator::BitAnd => left & right,
+        Operator::BitOr => left | right,
+        Operator::BitXor => left ^ right,
+        Operator::ShiftLeft => match shift(left, right, |left, count| left << count) {
+            Object::Integer(result) => result,
+            _ => return None,
+        },
+        Operator::ShiftRight => match shift(left, right, |left, count| left >> count) {
+            Object::Integer(result) => result,
+            _ => return None,
+        },
+        Operator::Equals => return Some(Expr::Boolean(left == right)),
+        Operator::NotEquals => return Some(Expr::Boolean(left != right)),
+        Operator::GreaterThan => return Some(Expr::Boolean(left > right)),
+        Operator::LessThan => return Some(Expr::Boolean(left < right)),
+        Operator::GreaterThanOrEqual => return Some(Expr::Boolean(left >= right)),
+        Operator::LessThanOrEqual => return Some(Expr::Boolean(left <= right)),
+        Operator::And | Operator::Or => return None,
+    };
+
+    Some(Expr::Const(result))
+}
 
+/// compares floats by their bits so that distinct values like 0.0 and -0.0 are never merged
+fn same_literal(left: &Object, right: &Object) -> bool {
+    match (left, right) {
+        (Object::Integer(left), Object::Integer(right)) => left == right,
+        (Object::Float(left), Object::Float(right)) => left.to_bits() == right.to_bits(),
+        (Object::String(left), Object::String(right)) => left == right,
+        _ => false,
+    }
 }
 
-pub fn compile_from_source(input: &str) -> ByteCode {
+pub fn compile_from_source(input: &str) -> Result<ByteCode, CompileError> {
     // wrap compiler method to hide compiler struct from outside this module
-    Compiler::compile_from_source(input)
+    let compiler = Compiler::new_with_state(Compiler::global_symbol_table(), Vec::new());
+    let (byte_code, _) = compiler.compile(input)?;
+
+    Ok(byte_code)
+}
+
+/// compiles input, also returning the global symbol table it ended with, which holds the builtins
+/// and every global the program defined. locals only exist while their function is being compiled
+pub fn compile_with_symbols(input: &str) -> Result<(ByteCode, SymbolTable), CompileError> {
+    let compiler = Compiler::new_with_state(Compiler::global_symbol_table(), Vec::new());
+
+    compiler.compile(input)
+}
+
+/// the symbol table a program starts with, holding only the builtin functions
+pub fn global_symbol_table() -> SymbolTable {
+    Compiler::global_symbol_table()
+}
+
+/// compiles input on top of the globals and constants left by an earlier compilation,
+/// start from global_symbol_table() and an empty constant pool
+///
+/// the returned constant pool is the one passed in with any new constants appended, and globals
+/// keep the indexes they were given, so a vm reusing the globals of an earlier run stays valid.
+/// the state is consumed even if compilation fails, clone it first to keep it
+pub fn compile_from_source_with_state(
+    input: &str,
+    symbol_table: SymbolTable,
+    constants: Vec<Object>
+) -> Result<(ByteCode, SymbolTable, Vec<Object>), CompileError> {
+    let compiler = Compiler::new_with_state(symbol_table, constants);
+    let (byte_code, symbol_table) = compiler.compile(input)?;
+    let constants = byte_code.constants.clone();
+
+    Ok((byte_code, symbol_table, constants))
+}
+
+/// the state the repl keeps between lines
+#[derive(Clone)]
+pub(crate) struct CompilerState {
+    symbol_table: SymbolTable,
+    constants: Vec<Object>,
+}
+
+impl CompilerState {
+    pub(crate) fn new() -> Self {
+        CompilerState {
+            symbol_table: global_symbol_table(),
+            constants: Vec::new(),
+        }
+    }
+}
+
+/// like compile_from_source_with_state, but the state is left as it was if compilation fails
+pub(crate) fn compile_with_state(input: &str, state: &mut CompilerState) -> Result<ByteCode, CompileError> {
+    let (byte_code, symbol_table, constants) =
+        compile_from_source_with_state(input, state.symbol_table.clone(), state.constants.clone())?;
+
+    state.symbol_table = symbol_table;
+    state.constants = constants;
+
+    Ok(byte_code)
 }
 
 #[cfg(test)]
@@ -190,14 +902,29 @@ mod tests {
         compile_infix_template("-", OpCode::OpSub);
         compile_infix_template("*", OpCode::OpMul);
         compile_infix_template("/", OpCode::OpDiv);
+        compile_infix_template("~/", OpCode::OpIntDiv);
+        compile_infix_template("%", OpCode::OpMod);
+        compile_infix_template("&", OpCode::OpBitAnd);
+        compile_infix_template("|", OpCode::OpBitOr);
+        compile_infix_template("^", OpCode::OpBitXor);
+        compile_infix_template("<<", OpCode::OpShl);
+        compile_infix_template(">>", OpCode::OpShr);
+        compile_infix_template(">", OpCode::OpGreaterThan);
+        // operands keep their source order rather than being swapped into a greater than
+        compile_infix_template("<", OpCode::OpLessThan);
+        compile_infix_template(">=", OpCode::OpGreaterThanOrEqual);
+        compile_infix_template("<=", OpCode::OpLessThanOrEqual);
     }
 
     fn compile_infix_template(infix_str: &str, op_code: OpCode) {
-        let input = format!("1 {} 2;", infix_str);
-        let byte_code = compile_from_source(&input);
+        // a variable operand, since literals on both sides would be folded into one
+        let input = format!("let a = 1; a {} 2;", infix_str);
+        let byte_code = compile_from_source(&input).expect("compile");
 
         let expected_instructions = vec![
             OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
             OpCode::OpConstant(1),
             op_code,
             OpCode::OpPop
@@ -209,24 +936,87 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(1), Object::Integer(2)]
+                constants: vec![Object::Integer(1), Object::Integer(2)],
+                lines: LineTable::default(),
             },
             byte_code
         );
     }
 
     #[test]
-    fn compile_if() {
-        let input = "if (true) { 10; }; 3333;";
-        let byte_code = compile_from_source(input);
+    fn compile_folds_constants() {
+        let instructions = |ops: Vec<OpCode>| ops.into_iter().flat_map(make_op).collect::<Vec<u8>>();
+
+        let folded = [
+            ("2 + 3;", 5),
+            ("2 + 3 * 4;", 14),
+            ("-(7 - 10) % 2;", 1),
+            ("1 << 4 | 3;", 19),
+        ];
+        for &(input, value) in folded.iter() {
+            let byte_code = compile_from_source(input).expect("compile");
+            assert_eq!(instructions(vec![OpCode::OpConstant(0), OpCode::OpPop]), byte_code.instructions, "{}", input);
+            assert_eq!(vec![Object::Integer(value)], byte_code.constants, "{}", input);
+        }
+        assert_eq!(
+            instructions(vec![OpCode::OpTrue, OpCode::OpPop]),
+            compile_from_source("1 + 1 == 2;").expect("compile").instructions
+        );
+        assert_eq!(
+            instructions(vec![OpCode::OpFalse, OpCode::OpPop]),
+            compile_from_source("!(3 >= 4) != true;").expect("compile").instructions
+        );
+
+        // variables, and anything which is an error at run time, are left for the vm
+        let unfolded = [
+            ("let x = 1; x + 1;", "OpAdd"),
+            ("1 / 0;", "OpDiv"),
+            ("1 % 0;", "OpMod"),
+            ("2147483647 + 1;", "OpAdd"),
+            ("-(-2147483647 - 1);", "OpMinus"),
+            ("1 << 32;", "OpShl"),
+            ("1 + true;", "OpAdd"),
+            ("1 + 1.5;", "OpAdd"),
+            ("true > false;", "OpGreaterThan"),
+        ];
+        for &(input, mnemonic) in unfolded.iter() {
+            let byte_code = compile_from_source(input).expect("compile");
+            assert!(byte_code.disassemble().contains(mnemonic), "{}", input);
+        }
+    }
+
+    #[test]
+    fn compile_deduplicates_constants() {
+        let byte_code = compile_from_source("1; 1; 1;").expect("compile");
+        assert_eq!(vec![Object::Integer(1)], byte_code.constants);
+
+        let byte_code = compile_from_source(r#""OK"; "OK"; "OK";"#).expect("compile");
+        assert_eq!(vec![Object::String(String::from("OK"))], byte_code.constants);
+        // strings inside functions share the pool too
+        let byte_code = compile_from_source(r#""OK"; fn() { "OK"; }; "ok";"#).expect("compile");
+        let strings = byte_code.constants.iter().filter(|constant| matches!(constant, Object::String(_))).count();
+        assert_eq!(2, strings);
+
+        let byte_code = compile_from_source(r#""a" + "b" + "a"; 1 + 1.0;"#).expect("compile");
+        assert_eq!(
+            vec![Object::String(String::from("a")), Object::String(String::from("b")), Object::Integer(1), Object::Float(1.0)],
+            byte_code.constants
+        );
+
+        assert!(same_literal(&Object::Float(0.5), &Object::Float(0.5)));
+        assert!(!same_literal(&Object::Float(0.0), &Object::Float(-0.0)));
+    }
+
+    #[test]
+    fn compile_string() {
+        let input = r#""foo" + "bar";"#;
+        let byte_code = compile_from_source(input).expect("compile");
 
         let expected_instructions = vec![
-            OpCode::OpTrue, // 0000
-            OpCode::OpJumpNotTrue(7), // 0001
-            OpCode::OpConstant(0), // 0004
-            OpCode::OpPop, // 0007
-            OpCode::OpConstant(1), // 0008
-            OpCode::OpPop, // 0011
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(1),
+            OpCode::OpAdd,
+            OpCode::OpPop,
         ]
             .into_iter()
             .flat_map(make_op)
@@ -235,24 +1025,25 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(10), Object::Integer(3333)]
+                constants: vec![Object::String(String::from("foo")), Object::String(String::from("bar"))],
+                lines: LineTable::default(),
             },
             byte_code
         );
     }
 
     #[test]
-    fn compile_if_else() {
-        let input = "if (true) { 10; } else { 20; };";
-        let byte_code = compile_from_source(input);
+    fn compile_array() {
+        let input = "[]; [1, 2];";
+        let byte_code = compile_from_source(input).expect("compile");
 
         let expected_instructions = vec![
-            OpCode::OpTrue, // 0000
-            OpCode::OpJumpNotTrue(10), // 0001
-            OpCode::OpConstant(0), // 0004
-            OpCode::OpJump(13), // 0007
-            OpCode::OpConstant(1), // 0010
-            OpCode::OpPop, // 0013
+            OpCode::OpArray(0),
+            OpCode::OpPop,
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(1),
+            OpCode::OpArray(2),
+            OpCode::OpPop,
         ]
             .into_iter()
             .flat_map(make_op)
@@ -261,26 +1052,27 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(10), Object::Integer(20)]
+                constants: vec![Object::Integer(1), Object::Integer(2)],
+                lines: LineTable::default(),
             },
             byte_code
         );
     }
 
     #[test]
-    fn compile_if_else_extra_statement() {
-        let input = "if (true) { 10; } else { 20; }; 3333;";
-        let byte_code = compile_from_source(input);
+    fn compile_hash() {
+        let input = r#"{}; {1: 2, "a": 3};"#;
+        let byte_code = compile_from_source(input).expect("compile");
 
         let expected_instructions = vec![
-            OpCode::OpTrue, // 0000
-            OpCode::OpJumpNotTrue(10), // 0001
-            OpCode::OpConstant(0), // 0004
-            OpCode::OpJump(13), // 0007
-            OpCode::OpConstant(1), // 0010
-            OpCode::OpPop, // 0013
-            OpCode::OpConstant(2), // 0014
-            OpCode::OpPop, // 0017
+            OpCode::OpHash(0),
+            OpCode::OpPop,
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(1),
+            OpCode::OpConstant(2),
+            OpCode::OpConstant(3),
+            OpCode::OpHash(2),
+            OpCode::OpPop,
         ]
             .into_iter()
             .flat_map(make_op)
@@ -289,16 +1081,748 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(10), Object::Integer(20), Object::Integer(3333)]
+                constants: vec![
+                    Object::Integer(1),
+                    Object::Integer(2),
+                    Object::String(String::from("a")),
+                    Object::Integer(3),
+                ],
+                lines: LineTable::default(),
             },
             byte_code
         );
     }
 
     #[test]
-    fn compile_let_single_var() {
+    fn compile_slice() {
+        let byte_code = compile_from_source("[1][:0];").expect("compile");
+
+        let expected_instructions: Vec<u8> = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpArray(1),
+            OpCode::OpNull,
+            OpCode::OpConstant(1),
+            OpCode::OpSlice,
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(expected_instructions, byte_code.instructions);
+    }
+
+    #[test]
+    fn compile_index() {
+        let input = "[1][0];";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpArray(1),
+            OpCode::OpConstant(1),
+            OpCode::OpIndex,
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(1), Object::Integer(0)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn disassemble_byte_code() {
+        let byte_code = compile_from_source(r#""a" + "b";"#).expect("compile");
+        assert_eq!(
+            "instructions:\n0000 OpConstant 0\n0003 OpConstant 1\n0006 OpAdd\n0007 OpPop\nconstants:\n0 \"a\"\n1 \"b\"\n",
+            byte_code.disassemble()
+        );
+
+        let byte_code = compile_from_source(r#"let f = fn(x) { x; }; "a";"#).expect("compile");
+        assert_eq!(
+            concat!(
+                "instructions:\n0000 OpClosure 0 0\n0004 OpSetGlobal 0\n0007 OpConstant 1\n0010 OpPop\n",
+                "constants:\n0 fn(...) { ... }\n    0000 OpGetLocal 0\n    0002 OpReturnValue\n1 \"a\"\n",
+            ),
+            byte_code.disassemble()
+        );
+    }
+
+    #[test]
+    fn compile_function() {
+        let input = "fn() { 5 + 10; }; fn() { }; fn() { 1; }();";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpClosure(1, 0),
+            OpCode::OpPop,
+            OpCode::OpClosure(2, 0),
+            OpCode::OpPop,
+            OpCode::OpClosure(4, 0),
+            OpCode::OpCall(0),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        let function_instructions = |ops: Vec<OpCode>| ops.into_iter().flat_map(make_op).collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::Integer(15),
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: function_instructions(vec![
+                            OpCode::OpConstant(0),
+                            OpCode::OpReturnValue,
+                        ]),
+                        num_locals: 0,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: function_instructions(vec![OpCode::OpReturn]),
+                        num_locals: 0,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                    Object::Integer(1),
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: function_instructions(vec![OpCode::OpConstant(3), OpCode::OpReturnValue]),
+                        num_locals: 0,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_function_return() {
+        let input = "fn() { return 1; 2; }; fn() { let a = 1; };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let function_instructions = |ops: Vec<OpCode>| ops.into_iter().flat_map(make_op).collect();
+
+        assert_eq!(
+            vec![
+                Object::Integer(1),
+                Object::Integer(2),
+                Object::CompiledFunction(Rc::new(CompiledFunction {
+                    instructions: function_instructions(vec![
+                        OpCode::OpConstant(0),
+                        OpCode::OpReturnValue,
+                        OpCode::OpConstant(1),
+                        OpCode::OpReturnValue,
+                    ]),
+                    num_locals: 0,
+                    num_parameters: 0,
+                    lines: LineTable::default(),
+                })),
+                Object::CompiledFunction(Rc::new(CompiledFunction {
+                    instructions: function_instructions(vec![
+                        OpCode::OpConstant(0),
+                        OpCode::OpSetLocal(0),
+                        OpCode::OpReturn,
+                    ]),
+                    num_locals: 1,
+                    num_parameters: 0,
+                    lines: LineTable::default(),
+                })),
+            ],
+            byte_code.constants
+        );
+    }
+
+    #[test]
+    fn compile_function_locals() {
+        let input = "let a = 1; fn() { let b = 2; let c = a; b + c; };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpClosure(2, 0),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::Integer(1),
+                    Object::Integer(2),
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: vec![
+                            OpCode::OpConstant(1),
+                            OpCode::OpSetLocal(0),
+                            OpCode::OpGetGlobal(0),
+                            OpCode::OpSetLocal(1),
+                            OpCode::OpGetLocal(0),
+                            OpCode::OpGetLocal(1),
+                            OpCode::OpAdd,
+                            OpCode::OpReturnValue,
+                        ]
+                            .into_iter()
+                            .flat_map(make_op)
+                            .collect(),
+                        num_locals: 2,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_function_arguments() {
+        let input = "let f = fn(a, b) { let c = a; c + b; }; f(1, 2);";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpClosure(0, 0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpConstant(1),
+            OpCode::OpConstant(2),
+            OpCode::OpCall(2),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: vec![
+                            OpCode::OpGetLocal(0),
+                            OpCode::OpSetLocal(2),
+                            OpCode::OpGetLocal(2),
+                            OpCode::OpGetLocal(1),
+                            OpCode::OpAdd,
+                            OpCode::OpReturnValue,
+                        ]
+                            .into_iter()
+                            .flat_map(make_op)
+                            .collect(),
+                        num_locals: 3,
+                        num_parameters: 2,
+                        lines: LineTable::default(),
+                    })),
+                    Object::Integer(1),
+                    Object::Integer(2),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_function_counts() {
+        let byte_code = compile_from_source("fn(a) { let b = a; b; };").expect("compile");
+
+        match &byte_code.constants[..] {
+            [Object::CompiledFunction(function)] => {
+                assert_eq!(1, function.num_parameters);
+                // parameters are locals too
+                assert_eq!(2, function.num_locals);
+            },
+            constants => panic!("expected a single compiled function, got {:?}", constants),
+        }
+    }
+
+    #[test]
+    fn compile_recursive_function() {
+        let input = "let countdown = fn(x) { countdown(x - 1); }; countdown(1);";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_function: Vec<u8> = vec![
+            OpCode::OpCurrentClosure,
+            OpCode::OpGetLocal(0),
+            OpCode::OpConstant(0),
+            OpCode::OpSub,
+            OpCode::OpTailCall(1),
+            OpCode::OpReturnValue,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let expected_instructions = vec![
+            OpCode::OpClosure(1, 0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpConstant(0),
+            OpCode::OpCall(1),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::Integer(1),
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: expected_function,
+                        num_locals: 1,
+                        num_parameters: 1,
+                        lines: LineTable::default(),
+                    })),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_tail_calls() {
+        let function_instructions = |input| {
+            let byte_code = compile_from_source(input).expect("compile");
+            match byte_code.constants.iter().find(|constant| matches!(constant, Object::CompiledFunction(_))) {
+                Some(Object::CompiledFunction(function)) => disassemble(&function.instructions),
+                _ => panic!("expected a compiled function for {}", input),
+            }
+        };
+
+        // both branches of a conditional, and an explicit return, are in tail position
+        let tail_calls = [
+            "let f = fn(x) { x == 0 ? 0 : f(x - 1); };",
+            "let f = fn(x) { if (x > 0) { f(x - 1); } else { 0; }; };",
+            "let f = fn(x) { if (x > 0) { return f(x - 1); }; 0; };",
+        ];
+        for input in tail_calls.iter() {
+            assert!(function_instructions(input).contains("OpTailCall 1"), "{}", input);
+        }
+
+        // the result is still used, or the call isn't to the function itself
+        let other_calls = [
+            "let f = fn(x) { x == 0 ? 0 : x + f(x - 1); };",
+            "let f = fn(x) { f(x - 1); 0; };",
+            "let g = fn(x) { x; }; let f = fn(x) { g(x); };",
+        ];
+        for input in other_calls.iter() {
+            assert!(!function_instructions(input).contains("OpTailCall"), "{}", input);
+        }
+    }
+
+    #[test]
+    fn compile_closures() {
+        let input = "fn(a) { fn(b) { fn(c) { a + b + c; }; }; };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let compiled_function = |ops: Vec<OpCode>, num_locals, num_parameters| {
+            Object::CompiledFunction(Rc::new(CompiledFunction {
+                instructions: ops.into_iter().flat_map(make_op).collect(),
+                num_locals,
+                num_parameters,
+                lines: LineTable::default(),
+            }))
+        };
+
+        assert_eq!(
+            ByteCode {
+                instructions: vec![OpCode::OpClosure(2, 0), OpCode::OpPop].into_iter().flat_map(make_op).collect(),
+                constants: vec![
+                    compiled_function(vec![
+                        OpCode::OpGetFree(0),
+                        OpCode::OpGetFree(1),
+                        OpCode::OpAdd,
+                        OpCode::OpGetLocal(0),
+                        OpCode::OpAdd,
+                        OpCode::OpReturnValue,
+                    ], 1, 1),
+                    compiled_function(vec![
+                        OpCode::OpGetFree(0),
+                        OpCode::OpGetLocal(0),
+                        OpCode::OpClosure(0, 2),
+                        OpCode::OpReturnValue,
+                    ], 1, 1),
+                    compiled_function(vec![
+                        OpCode::OpGetLocal(0),
+                        OpCode::OpClosure(1, 1),
+                        OpCode::OpReturnValue,
+                    ], 1, 1),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_builtins() {
+        let input = "len([]); fn() { len; };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        assert_eq!(
+            ByteCode {
+                instructions: vec![
+                    OpCode::OpGetBuiltin(0),
+                    OpCode::OpArray(0),
+                    OpCode::OpCall(1),
+                    OpCode::OpPop,
+                    OpCode::OpClosure(0, 0),
+                    OpCode::OpPop,
+                ]
+                    .into_iter()
+                    .flat_map(make_op)
+                    .collect(),
+                constants: vec![
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: vec![OpCode::OpGetBuiltin(0), OpCode::OpReturnValue].into_iter().flat_map(make_op).collect(),
+                        num_locals: 0,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_if() {
+        let input = "if (true) { 10; }; 3333;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(10), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpJump(11), // 0007
+            OpCode::OpNull, // 0010
+            OpCode::OpPop, // 0011
+            OpCode::OpConstant(1), // 0012
+            OpCode::OpPop, // 0015
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(10), Object::Integer(3333)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_while() {
+        let input = "while (true) { 10; } 20;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(11), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpPop, // 0007
+            OpCode::OpJump(0), // 0008
+            OpCode::OpConstant(1), // 0011
+            OpCode::OpPop, // 0014
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(10), Object::Integer(20)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_break() {
+        let input = "while (true) { break; 10; } 20;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(14), // 0001
+            OpCode::OpJump(14), // 0004
+            OpCode::OpConstant(0), // 0007
+            OpCode::OpPop, // 0010
+            OpCode::OpJump(0), // 0011
+            OpCode::OpConstant(1), // 0014
+            OpCode::OpPop, // 0017
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect::<Vec<u8>>();
+        assert_eq!(expected_instructions, byte_code.instructions);
+
+        // the operand already pushed by the expression the break is in is popped first
+        let byte_code = compile_from_source("while (true) { 1 + if (true) { break; }; }").expect("compile");
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(26), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpTrue, // 0007
+            OpCode::OpJumpNotTrue(20), // 0008
+            OpCode::OpPopN(1), // 0011
+            OpCode::OpJump(26), // 0013
+            OpCode::OpNull, // 0016
+            OpCode::OpJump(21), // 0017
+            OpCode::OpNull, // 0020
+            OpCode::OpAdd, // 0021
+            OpCode::OpPop, // 0022
+            OpCode::OpJump(0), // 0023
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect::<Vec<u8>>();
+        assert_eq!(expected_instructions, byte_code.instructions);
+
+        assert_eq!(Err(CompileError::BreakOutsideLoop), compile_from_source("break;"));
+        assert_eq!(Err(CompileError::BreakOutsideLoop), compile_from_source("if (true) { break; };"));
+        // a function called from a loop can't leave it
+        assert_eq!(Err(CompileError::BreakOutsideLoop), compile_from_source("while (true) { fn() { break; }; }"));
+    }
+
+    #[test]
+    fn compile_continue() {
+        let input = "while (true) { continue; 10; } 20;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(14), // 0001
+            OpCode::OpJump(0), // 0004
+            OpCode::OpConstant(0), // 0007
+            OpCode::OpPop, // 0010
+            OpCode::OpJump(0), // 0011
+            OpCode::OpConstant(1), // 0014
+            OpCode::OpPop, // 0017
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect::<Vec<u8>>();
+        assert_eq!(expected_instructions, byte_code.instructions);
+
+        // the operands already pushed by the expression the continue is in are popped first
+        let byte_code = compile_from_source("while (true) { [1, 2, if (true) { continue; }]; }").expect("compile");
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(31), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpConstant(1), // 0007
+            OpCode::OpTrue, // 0010
+            OpCode::OpJumpNotTrue(23), // 0011
+            OpCode::OpPopN(2), // 0014
+            OpCode::OpJump(0), // 0016
+            OpCode::OpNull, // 0019
+            OpCode::OpJump(24), // 0020
+            OpCode::OpNull, // 0023
+            OpCode::OpArray(3), // 0024
+            OpCode::OpPop, // 0027
+            OpCode::OpJump(0), // 0028
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect::<Vec<u8>>();
+        assert_eq!(expected_instructions, byte_code.instructions);
+
+        assert_eq!(Err(CompileError::ContinueOutsideLoop), compile_from_source("continue;"));
+        assert_eq!(Err(CompileError::ContinueOutsideLoop), compile_from_source("while (true) { fn() { continue; }; }"));
+    }
+
+    #[test]
+    fn compile_for_scope() {
+        // the loop variable doesn't outlive the loop
+        assert_eq!(Err(CompileError::UndefinedVariable(String::from("x"))), compile_from_source("for (x in [1]) { } x;"));
+        assert!(compile_from_source("let x = 1; for (x in [1]) { } x;").is_ok());
+        assert_eq!(Err(CompileError::BreakOutsideLoop), compile_from_source("for (x in [1]) { } break;"));
+
+        // inside a function the loop variable and the hidden array, length and index are locals
+        let byte_code = compile_from_source("fn(a) { for (x in a) { x; } };").expect("compile");
+        match byte_code.constants.iter().find(|constant| matches!(constant, Object::CompiledFunction(_))) {
+            Some(Object::CompiledFunction(function)) => assert_eq!(5, function.num_locals),
+            _ => panic!("expected a compiled function"),
+        }
+    }
+
+    #[test]
+    fn compile_and() {
+        let input = "true && false;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(12), // 0001
+            OpCode::OpFalse, // 0004
+            OpCode::OpJumpNotTrue(12), // 0005
+            OpCode::OpTrue, // 0008
+            OpCode::OpJump(13), // 0009
+            OpCode::OpFalse, // 0012
+            OpCode::OpPop, // 0013
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_or() {
+        let input = "false || true;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpFalse, // 0000
+            OpCode::OpJumpNotTrue(8), // 0001
+            OpCode::OpTrue, // 0004
+            OpCode::OpJump(17), // 0005
+            OpCode::OpTrue, // 0008
+            OpCode::OpJumpNotTrue(16), // 0009
+            OpCode::OpTrue, // 0012
+            OpCode::OpJump(17), // 0013
+            OpCode::OpFalse, // 0016
+            OpCode::OpPop, // 0017
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_if_else() {
+        let input = "if (true) { 10; } else { 20; };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(10), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpJump(13), // 0007
+            OpCode::OpConstant(1), // 0010
+            OpCode::OpPop, // 0013
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(10), Object::Integer(20)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_if_else_extra_statement() {
+        let input = "if (true) { 10; } else { 20; }; 3333;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(10), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpJump(13), // 0007
+            OpCode::OpConstant(1), // 0010
+            OpCode::OpPop, // 0013
+            OpCode::OpConstant(2), // 0014
+            OpCode::OpPop, // 0017
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(10), Object::Integer(20), Object::Integer(3333)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_if_block_without_value() {
+        let input = "if (true) { let a = 2; } else { };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpTrue, // 0000
+            OpCode::OpJumpNotTrue(14), // 0001
+            OpCode::OpConstant(0), // 0004
+            OpCode::OpSetGlobal(0), // 0007
+            OpCode::OpNull, // 0010
+            OpCode::OpJump(15), // 0011
+            OpCode::OpNull, // 0014
+            OpCode::OpPop, // 0015
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(2)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_let_single_var() {
         let input = "let one = 1;";
-        let byte_code = compile_from_source(input);
+        let byte_code = compile_from_source(input).expect("compile");
 
         let expected_instructions = vec![
             OpCode::OpConstant(0),
@@ -311,7 +1835,8 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(1),]
+                constants: vec![Object::Integer(1),],
+                lines: LineTable::default(),
             },
             byte_code
         );
@@ -320,7 +1845,7 @@ mod tests {
     #[test]
     fn compile_let_multiple_var() {
         let input = "let one = 1; let two = 2;";
-        let byte_code = compile_from_source(input);
+        let byte_code = compile_from_source(input).expect("compile");
 
         let expected_instructions = vec![
             OpCode::OpConstant(0),
@@ -335,16 +1860,347 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(1), Object::Integer(2),]
+                constants: vec![Object::Integer(1), Object::Integer(2),],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_assign() {
+        let input = "let x = 1; x = 5; x;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpConstant(1),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(1), Object::Integer(5)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_errors() {
+        assert_eq!(Err(CompileError::UndefinedVariable(String::from("x"))), compile_from_source("x;"));
+        assert_eq!(Err(CompileError::UndefinedVariable(String::from("x"))), compile_from_source("x = 1;"));
+        assert_eq!(Err(CompileError::InvalidAssignment(String::from("len"))), compile_from_source("len = 1;"));
+        assert_eq!(
+            Err(CompileError::InvalidAssignment(String::from("a"))),
+            compile_from_source("fn(a) { fn() { a = 1; }; };")
+        );
+        assert_eq!(Err(CompileError::UndefinedVariable(String::from("x"))), compile_from_source("x += 1;"));
+        assert_eq!(Err(CompileError::InvalidAssignment(String::from("len"))), compile_from_source("len *= 2;"));
+        assert_eq!(Err(CompileError::InvalidIncrementTarget), compile_from_source("5++;"));
+        assert_eq!(Err(CompileError::InvalidIncrementTarget), compile_from_source("let a = [1]; a[0]--;"));
+        assert_eq!(Err(CompileError::InvalidAssignment(String::from("len"))), compile_from_source("len++;"));
+        // top-level code runs in order, so it can't use a global before its let
+        assert_eq!(Err(CompileError::UndefinedVariable(String::from("x"))), compile_from_source("x; let x = 1;"));
+        assert_eq!(Err(CompileError::UndefinedVariable(String::from("x"))), compile_from_source("x = 2; let x = 1;"));
+        assert_eq!(Err(CompileError::InvalidEscape('q')), compile_from_source(r#"let s = "a\qb";"#));
+        assert_eq!(
+            "unknown escape sequence in string: \\q",
+            compile_from_source(r#"puts("\q");"#).expect_err("should fail to compile").to_string()
+        );
+    }
+
+    /// a distinct name for each index, identifiers can only be made of letters
+    fn variable_name(mut index: usize) -> String {
+        let mut name = String::from("v");
+        loop {
+            name.push((b'a' + (index % 26) as u8) as char);
+            index /= 26;
+            if index == 0 {
+                return name;
+            }
+        }
+    }
+
+    #[test]
+    fn compile_errors_for_operands_too_big_to_encode() {
+        let lets = |range: std::ops::Range<usize>| -> String {
+            range.map(|index| format!("let {} = 1;", variable_name(index))).collect()
+        };
+        assert!(compile_from_source(&format!("fn() {{ {} }};", lets(0..256))).is_ok());
+        assert_eq!(Err(CompileError::TooManyLocals), compile_from_source(&format!("fn() {{ {} }};", lets(0..257))));
+
+        // the innermost function captures the locals of both functions around it
+        let references: String = (0..300).map(|index| format!("{};", variable_name(index))).collect();
+        let program = format!("fn() {{ {} fn() {{ {} fn() {{ {} }}; }}; }};", lets(0..200), lets(200..300), references);
+        assert_eq!(Err(CompileError::TooManyFreeVariables), compile_from_source(&program));
+
+        let arguments = |count: usize| vec!["1"; count].join(", ");
+        assert!(compile_from_source(&format!("puts({});", arguments(255))).is_ok());
+        assert_eq!(Err(CompileError::TooManyArguments), compile_from_source(&format!("puts({});", arguments(256))));
+
+        // parsing programs this long takes a while, so these are compiled from their syntax trees
+        let compile_ast = |ast: Vec<Statement>| {
+            Compiler::new_with_state(Compiler::global_symbol_table(), Vec::new()).compile_statements(ast)
+        };
+        let array = |length: usize| vec![Statement::Expression(Expr::Array(vec![Expr::Const(1); length]))];
+        assert!(compile_ast(array(65535)).is_ok());
+        assert_eq!(Err(CompileError::TooManyElements), compile_ast(array(65536)));
+
+        // an if whose consequence ends beyond the reach of the jump over it, or a loop which starts there
+        let statements = vec![Statement::Expression(Expr::Const(1)); 20000];
+        let if_expression = Expr::If { condition: Box::new(Expr::Boolean(true)), consequence: statements.clone(), alternative: Vec::new() };
+        assert_eq!(Err(CompileError::ProgramTooLarge), compile_ast(vec![Statement::Expression(if_expression)]));
+        let mut program = statements;
+        program.push(Statement::While { condition: Expr::Boolean(true), body: Vec::new() });
+        assert_eq!(Err(CompileError::ProgramTooLarge), compile_ast(program));
+
+        let constants: Vec<Object> = (0..65536).map(Object::Integer).collect();
+        let result = compile_from_source_with_state("65535;", global_symbol_table(), constants.clone());
+        assert!(result.is_ok());
+        let result = compile_from_source_with_state("65536;", global_symbol_table(), constants);
+        assert_eq!(Some(CompileError::TooManyConstants), result.err());
+
+        let mut symbol_table = global_symbol_table();
+        (0..65536).for_each(|index| { symbol_table.define(variable_name(index)); });
+        let result = compile_from_source_with_state("let x = 1;", symbol_table, Vec::new());
+        assert_eq!(Some(CompileError::TooManyGlobals), result.err());
+    }
+
+    #[test]
+    fn compile_ignores_comments() {
+        let with_comments = r#"
+            // doubles its argument
+            let double = fn(x) {
+                x * 2; /* implicitly returned */
+            };
+            /* double(1);
+               /* nested */ double(2); */
+            double(3) // trailing comment
+            ;
+            "// kept" + "/* kept */";
+        "#;
+        let without_comments = r#"let double = fn(x) { x * 2; }; double(3); "// kept" + "/* kept */";"#;
+
+        assert_eq!(compile_from_source(without_comments), compile_from_source(with_comments));
+    }
+
+    #[test]
+    fn compile_destructure() {
+        let input = "let [a, [b, c]] = x; fn() { let [d] = [a]; };";
+        let symbol_table = {
+            let mut symbol_table = global_symbol_table();
+            symbol_table.define(String::from("x"));
+            symbol_table
+        };
+        let (byte_code, _, _) = compile_from_source_with_state(input, symbol_table, vec![]).expect("compile");
+
+        let expected_function: Vec<u8> = vec![
+            OpCode::OpGetGlobal(1),
+            OpCode::OpArray(1),
+            OpCode::OpDestructure(1),
+            OpCode::OpSetLocal(0),
+            OpCode::OpReturn,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let expected_instructions = vec![
+            OpCode::OpGetGlobal(0),
+            OpCode::OpDestructure(2),
+            OpCode::OpSetGlobal(1),
+            OpCode::OpDestructure(2),
+            OpCode::OpSetGlobal(2),
+            OpCode::OpSetGlobal(3),
+            OpCode::OpClosure(0, 0),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: expected_function,
+                        num_locals: 1,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_forward_reference() {
+        let input = "let f = fn() { g; }; let g = 1;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_function: Vec<u8> = vec![
+            OpCode::OpGetGlobal(1),
+            OpCode::OpReturnValue,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let expected_instructions = vec![
+            OpCode::OpClosure(0, 0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpConstant(1),
+            OpCode::OpSetGlobal(1),
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: expected_function,
+                        num_locals: 0,
+                        num_parameters: 0,
+                        lines: LineTable::default(),
+                    })),
+                    Object::Integer(1),
+                ],
+                lines: LineTable::default(),
             },
             byte_code
         );
     }
 
+    #[test]
+    fn compile_compound_assign() {
+        let input = "let x = 10; x -= 5;";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpConstant(1),
+            OpCode::OpSub,
+            OpCode::OpSetGlobal(0),
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![Object::Integer(10), Object::Integer(5)],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_postfix() {
+        let input = "let i = 5; i++; fn(j) { j--; };";
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let expected_function: Vec<u8> = vec![
+            OpCode::OpGetLocal(0),
+            OpCode::OpDup,
+            OpCode::OpConstant(1),
+            OpCode::OpSub,
+            OpCode::OpSetLocal(0),
+            OpCode::OpReturnValue,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpDup,
+            OpCode::OpConstant(1),
+            OpCode::OpAdd,
+            OpCode::OpSetGlobal(0),
+            OpCode::OpPop,
+            OpCode::OpClosure(2, 0),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(
+            ByteCode {
+                instructions: expected_instructions,
+                constants: vec![
+                    Object::Integer(5),
+                    Object::Integer(1),
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions: expected_function,
+                        num_locals: 1,
+                        num_parameters: 1,
+                        lines: LineTable::default(),
+                    })),
+                ],
+                lines: LineTable::default(),
+            },
+            byte_code
+        );
+    }
+
+    #[test]
+    fn compile_with_symbols_returns_globals() {
+        let (_, symbol_table) = compile_with_symbols("let x = 1; let y = 2;").expect("compile");
+        let symbols = symbol_table.symbols();
+
+        let global = |index| Symbol { scope: SymbolScope::Global, index };
+        assert!(symbols.contains(&("x", &global(0))));
+        assert!(symbols.contains(&("y", &global(1))));
+        assert!(symbols.contains(&("len", &Symbol { scope: SymbolScope::Builtin, index: 0 })));
+        assert_eq!(BUILTINS.len() + 2, symbols.len());
+    }
+
+    #[test]
+    fn compile_with_state_shares_globals() {
+        let (first, symbol_table, constants) =
+            compile_from_source_with_state("let a = 1; let b = 2;", global_symbol_table(), Vec::new()).expect("compile");
+        let (second, _, constants) =
+            compile_from_source_with_state("b + 3;", symbol_table, constants).expect("compile");
+
+        let expected_instructions: Vec<u8> = vec![
+            OpCode::OpGetGlobal(1),
+            OpCode::OpConstant(2),
+            OpCode::OpAdd,
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        assert_eq!(vec![Object::Integer(1), Object::Integer(2)], first.constants);
+        assert_eq!(expected_instructions, second.instructions);
+        assert_eq!(vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)], constants);
+        assert_eq!(constants, second.constants);
+    }
+
     #[test]
     fn compile_let_get() {
         let input = "let one = 1; one;";
-        let byte_code = compile_from_source(input);
+        let byte_code = compile_from_source(input).expect("compile");
 
         let expected_instructions = vec![
             OpCode::OpConstant(0),
@@ -359,7 +2215,8 @@ mod tests {
         assert_eq!(
             ByteCode {
                 instructions: expected_instructions,
-                constants: vec![Object::Integer(1),]
+                constants: vec![Object::Integer(1),],
+                lines: LineTable::default(),
             },
             byte_code
         );
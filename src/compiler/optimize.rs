@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::rc::Rc;
+use crate::code::{convert_two_u8s_be_to_usize, make, LineTable, Opcode};
+use crate::compiler::ByteCode;
+use crate::eval::Object;
+
+struct Instruction {
+    position: usize,
+    opcode: Opcode,
+    operands: Vec<usize>,
+}
+
+/// a peephole pass over the main program and every compiled function, it is not run by default
+///
+/// jumps which land on another jump go straight to the final target, and constants which are
+/// pushed only to be popped again are removed. the result of the program (the last popped value)
+/// is unchanged, because a pair is only removed when another pop is certain to follow it
+pub fn optimize(byte_code: ByteCode) -> ByteCode {
+    let constants = byte_code.constants
+        .into_iter()
+        .map(|constant| match constant {
+            Object::CompiledFunction(mut function) => {
+                let (instructions, lines) = optimize_instructions(&function.instructions, &function.lines);
+                let function_mut = Rc::make_mut(&mut function);
+                function_mut.instructions = instructions;
+                function_mut.lines = lines;
+                Object::CompiledFunction(function)
+            },
+            constant => constant,
+        })
+        .collect();
+    let (instructions, lines) = optimize_instructions(&byte_code.instructions, &byte_code.lines);
+
+    ByteCode {
+        instructions,
+        constants,
+        lines,
+    }
+}
+
+fn optimize_instructions(instructions: &[u8], lines: &LineTable) -> (Vec<u8>, LineTable) {
+    let mut decoded = match decode(instructions) {
+        Some(decoded) => decoded,
+        // leave anything we can't make sense of untouched
+        None => return (instructions.to_vec(), lines.clone()),
+    };
+
+    let index_of_position: HashMap<usize, usize> = decoded
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.position, index))
+        .collect();
+
+    // jump to jump collapsing, the guard stops a loop of jumps from running forever
+    let targets: Vec<Option<usize>> = decoded
+        .iter()
+        .map(|instruction| {
+            if !is_jump(instruction.opcode) {
+                return None;
+            }
+            let mut target = instruction.operands[0];
+            for _ in 0..decoded.len() {
+                match index_of_position.get(&target).map(|&index| &decoded[index]) {
+                    Some(next) if next.opcode == Opcode::Jump => target = next.operands[0],
+                    _ => break,
+                }
+            }
+            Some(target)
+        })
+        .collect();
+    for (instruction, target) in decoded.iter_mut().zip(targets) {
+        if let Some(target) = target {
+            instruction.operands[0] = target;
+        }
+    }
+
+    let jump_targets: HashSet<usize> = decoded
+        .iter()
+        .filter(|instruction| is_jump(instruction.opcode))
+        .map(|instruction| instruction.operands[0])
+        .collect();
+
+    let mut removed = vec![false; decoded.len()];
+    for index in 0..decoded.len().saturating_sub(1) {
+        let pushes_constant = decoded[index].opcode == Opcode::Constant && !removed[index];
+        let popped_next = decoded[index + 1].opcode == Opcode::Pop
+            && !jump_targets.contains(&decoded[index + 1].position);
+        if pushes_constant && popped_next && pop_follows(&decoded, &jump_targets, index + 2) {
+            removed[index] = true;
+            removed[index + 1] = true;
+        }
+    }
+
+    // removed instructions map to wherever the next kept instruction ends up
+    let mut new_positions = HashMap::new();
+    let mut new_position = 0;
+    for (instruction, removed) in decoded.iter().zip(&removed) {
+        new_positions.insert(instruction.position, new_position);
+        if !removed {
+            new_position += 1 + instruction.opcode.operand_widths().iter().sum::<usize>();
+        }
+    }
+    new_positions.insert(instructions.len(), new_position);
+
+    let mut output = Vec::new();
+    for (instruction, removed) in decoded.iter().zip(&removed) {
+        if *removed {
+            continue;
+        }
+        let operands: Vec<usize> = if is_jump(instruction.opcode) {
+            match new_positions.get(&instruction.operands[0]) {
+                Some(&target) => vec![target],
+                // a jump into the middle of an instruction, or past the end, can't be moved along with its target
+                None => return (instructions.to_vec(), lines.clone()),
+            }
+        } else {
+            instruction.operands.clone()
+        };
+        output.extend(make(instruction.opcode, &operands));
+    }
+
+    // the line table only has offsets where a statement starts, which are always instruction boundaries
+    let lines = lines.remap(|offset| new_positions.get(&offset).copied().unwrap_or(offset));
+
+    (output, lines)
+}
+
+/// whether an OpPop is reached from the given instruction without any jump or return in between
+fn pop_follows(decoded: &[Instruction], jump_targets: &HashSet<usize>, start: usize) -> bool {
+    for instruction in &decoded[start..] {
+        if jump_targets.contains(&instruction.position) {
+            return false;
+        }
+        match instruction.opcode {
+            Opcode::Pop => return true,
+            Opcode::Jump | Opcode::JumpNotTrue | Opcode::ReturnValue | Opcode::Return => return false,
+            _ => {},
+        }
+    }
+
+    false
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+    opcode == Opcode::Jump || opcode == Opcode::JumpNotTrue
+}
+
+fn decode(instructions: &[u8]) -> Option<Vec<Instruction>> {
+    let mut decoded = Vec::new();
+    let mut position = 0;
+
+    while position < instructions.len() {
+        let opcode = Opcode::try_from(instructions[position]).ok()?;
+        let mut operands = Vec::new();
+        let mut operand_position = position + 1;
+        for &width in opcode.operand_widths() {
+            let operand = match instructions.get(operand_position..operand_position + width)? {
+                [byte] => *byte as usize,
+                [byte1, byte2] => convert_two_u8s_be_to_usize(*byte1, *byte2),
+                _ => return None,
+            };
+            operands.push(operand);
+            operand_position += width;
+        }
+
+        decoded.push(Instruction { position, opcode, operands });
+        position = operand_position;
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{make_op, OpCode};
+    use crate::compiler::compile_from_source;
+    use crate::vm::run_from_bytecode;
+
+    #[test]
+    fn optimize_removes_unused_constants() {
+        let byte_code = optimize(compile_from_source("1; 2; 3;").expect("compile"));
+
+        let expected_instructions: Vec<u8> = vec![OpCode::OpConstant(2), OpCode::OpPop]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        assert_eq!(expected_instructions, byte_code.instructions);
+    }
+
+    #[test]
+    fn optimize_collapses_jumps() {
+        let instructions: Vec<u8> = vec![
+            OpCode::OpJump(3), // 0000
+            OpCode::OpJump(6), // 0003
+            OpCode::OpNull, // 0006
+            OpCode::OpPop, // 0007
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        let expected_instructions: Vec<u8> = vec![
+            OpCode::OpJump(6),
+            OpCode::OpJump(6),
+            OpCode::OpNull,
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let (optimized, _) = optimize_instructions(&instructions, &LineTable::default());
+        assert_eq!(expected_instructions, optimized);
+    }
+
+    #[test]
+    fn optimize_leaves_jumps_between_instructions_alone() {
+        let instructions: Vec<u8> = vec![
+            OpCode::OpConstant(0), // 0000
+            OpCode::OpPop, // 0003
+            OpCode::OpJump(1), // 0004
+            OpCode::OpConstant(0), // 0007
+            OpCode::OpPop, // 0010
+            OpCode::OpJumpNotTrue(100), // 0011
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let mut lines = LineTable::default();
+        lines.add(0, 1);
+
+        let (optimized, optimized_lines) = optimize_instructions(&instructions, &lines);
+        assert_eq!(instructions, optimized);
+        assert_eq!(lines, optimized_lines);
+    }
+
+    #[test]
+    fn optimize_keeps_results() {
+        let inputs = vec![
+            "1; 2; 3;",
+            "1; let a = 2;",
+            "5; if (true) { 1; 2; } else { 3; };",
+            "if (true) { if (false) { 1; } else { 2; }; } else { 3; };",
+            "let f = fn(x) { 1; 2; x; }; 4; f(3);",
+            "let i = 0; while (i < 3) { 7; i = i + 1; } 8; i;",
+            "9; while (false) { 1; }",
+            "true && false || true;",
+        ];
+
+        for input in inputs {
+            let unoptimized = compile_from_source(input).expect("compile");
+            let optimized = optimize(compile_from_source(input).expect("compile"));
+
+            assert!(optimized.instructions.len() <= unoptimized.instructions.len(), "{}", input);
+            assert_eq!(run_from_bytecode(unoptimized), run_from_bytecode(optimized), "{}", input);
+        }
+    }
+
+    #[test]
+    fn optimize_shrinks_functions_and_main() {
+        let input = "let f = fn() { 1; 2; 3; }; 4; 5; f();";
+        let unoptimized = compile_from_source(input).expect("compile");
+        let optimized = optimize(compile_from_source(input).expect("compile"));
+
+        let function_length = |byte_code: &ByteCode| byte_code.constants
+            .iter()
+            .find_map(|constant| match constant {
+                Object::CompiledFunction(function) => Some(function.instructions.len()),
+                _ => None,
+            })
+            .expect("compiled function constant");
+
+        assert!(optimized.instructions.len() < unoptimized.instructions.len());
+        assert!(function_length(&optimized) < function_length(&unoptimized));
+        assert_eq!(Ok(Object::Integer(3)), run_from_bytecode(optimized));
+    }
+}
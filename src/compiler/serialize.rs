@@ -0,0 +1,227 @@
+use std::convert::TryInto;
+use std::rc::Rc;
+use crate::compiler::ByteCode;
+use crate::code::LineTable;
+use crate::eval::{Object, CompiledFunction};
+
+const MAGIC : &[u8; 4] = b"MNKY";
+const VERSION : u8 = 1;
+
+// every constant is written as one of these tags followed by its payload
+const TAG_INTEGER : u8 = 0;
+const TAG_FLOAT : u8 = 1;
+const TAG_STRING : u8 = 2;
+const TAG_BOOLEAN : u8 = 3;
+const TAG_NULL : u8 = 4;
+const TAG_FUNCTION : u8 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum DeserializeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    UnknownConstantTag(u8),
+    InvalidString,
+    TrailingBytes,
+}
+
+impl std::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeserializeError::BadMagic => write!(f, "not a monkey bytecode file"),
+            DeserializeError::UnsupportedVersion(version) => write!(f, "unsupported bytecode version: {}", version),
+            DeserializeError::UnexpectedEnd => write!(f, "bytecode ended unexpectedly"),
+            DeserializeError::UnknownConstantTag(tag) => write!(f, "unknown constant tag: {}", tag),
+            DeserializeError::InvalidString => write!(f, "string constant is not valid utf8"),
+            DeserializeError::TrailingBytes => write!(f, "unexpected bytes after the constant pool"),
+        }
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl ByteCode {
+    /// encodes the bytecode as a header (magic number and version), the instructions, then the constant pool
+    /// all lengths and numbers are big endian, like the operands in the instructions
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output = MAGIC.to_vec();
+        output.push(VERSION);
+
+        write_bytes(&mut output, &self.instructions);
+        write_u32(&mut output, self.constants.len());
+        for constant in &self.constants {
+            write_constant(&mut output, constant);
+        }
+
+        output
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<ByteCode, DeserializeError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.read(MAGIC.len())? != MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+
+        let instructions = reader.read_bytes()?.to_vec();
+        let num_constants = reader.read_u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..num_constants {
+            constants.push(reader.read_constant()?);
+        }
+
+        if reader.position != bytes.len() {
+            return Err(DeserializeError::TrailingBytes);
+        }
+
+        // line tables aren't written, so errors in a loaded program can't say which line they came from
+        Ok(ByteCode { instructions, constants, lines: LineTable::default() })
+    }
+}
+
+fn write_u32(output: &mut Vec<u8>, value: usize) {
+    output.extend(&(value as u32).to_be_bytes());
+}
+
+fn write_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(output, bytes.len());
+    output.extend(bytes);
+}
+
+fn write_constant(output: &mut Vec<u8>, constant: &Object) {
+    match constant {
+        Object::Integer(num) => {
+            output.push(TAG_INTEGER);
+            output.extend(&num.to_be_bytes());
+        },
+        Object::Float(num) => {
+            output.push(TAG_FLOAT);
+            output.extend(&num.to_bits().to_be_bytes());
+        },
+        Object::String(string) => {
+            output.push(TAG_STRING);
+            write_bytes(output, string.as_bytes());
+        },
+        Object::Boolean(val) => {
+            output.push(TAG_BOOLEAN);
+            output.push(*val as u8);
+        },
+        Object::Null => output.push(TAG_NULL),
+        Object::CompiledFunction(function) => {
+            output.push(TAG_FUNCTION);
+            write_bytes(output, &function.instructions);
+            write_u32(output, function.num_locals);
+            write_u32(output, function.num_parameters);
+        },
+        // the compiler only ever places literals and functions in the constant pool
+        obj => panic!("{} constants cannot be serialized", obj.type_name()),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, length: usize) -> Result<&'a [u8], DeserializeError> {
+        let bytes = self.bytes
+            .get(self.position..self.position + length)
+            .ok_or(DeserializeError::UnexpectedEnd)?;
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<usize, DeserializeError> {
+        let bytes = self.read(4)?.try_into().expect("read returns the requested length");
+        Ok(u32::from_be_bytes(bytes) as usize)
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], DeserializeError> {
+        let length = self.read_u32()?;
+        self.read(length)
+    }
+
+    fn read_constant(&mut self) -> Result<Object, DeserializeError> {
+        match self.read_u8()? {
+            TAG_INTEGER => {
+                let bytes = self.read(4)?.try_into().expect("read returns the requested length");
+                Ok(Object::Integer(i32::from_be_bytes(bytes)))
+            },
+            TAG_FLOAT => {
+                let bytes = self.read(8)?.try_into().expect("read returns the requested length");
+                Ok(Object::Float(f64::from_bits(u64::from_be_bytes(bytes))))
+            },
+            TAG_STRING => {
+                let string = std::str::from_utf8(self.read_bytes()?).map_err(|_| DeserializeError::InvalidString)?;
+                Ok(Object::String(string.to_owned()))
+            },
+            TAG_BOOLEAN => Ok(Object::Boolean(self.read_u8()? != 0)),
+            TAG_NULL => Ok(Object::Null),
+            TAG_FUNCTION => {
+                let instructions = self.read_bytes()?.to_vec();
+                let num_locals = self.read_u32()?;
+                let num_parameters = self.read_u32()?;
+                Ok(Object::CompiledFunction(Rc::new(CompiledFunction {
+                    instructions,
+                    num_locals,
+                    num_parameters,
+                    lines: LineTable::default(),
+                })))
+            },
+            tag => Err(DeserializeError::UnknownConstantTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile_from_source;
+    use crate::vm::run_from_bytecode;
+
+    #[test]
+    fn serialize_round_trip() {
+        let input = r#"
+            let greet = fn(name) { "hello " + name; };
+            let values = [1, 2.5, true, greet("monkey")];
+            values[3];
+        "#;
+        let byte_code = compile_from_source(input).expect("compile");
+        let bytes = byte_code.serialize();
+
+        let deserialized = ByteCode::deserialize(&bytes).expect("deserialize");
+        assert_eq!(byte_code, deserialized);
+
+        let expected = run_from_bytecode(compile_from_source(input).expect("compile"));
+        assert_eq!(Ok(Object::String(String::from("hello monkey"))), expected);
+        assert_eq!(expected, run_from_bytecode(deserialized));
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        let bytes = compile_from_source("1;").expect("compile").serialize();
+
+        assert_eq!(Err(DeserializeError::BadMagic), ByteCode::deserialize(b"JUNK\x01"));
+        assert_eq!(Err(DeserializeError::UnexpectedEnd), ByteCode::deserialize(b"MN"));
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 99;
+        assert_eq!(Err(DeserializeError::UnsupportedVersion(99)), ByteCode::deserialize(&wrong_version));
+
+        assert_eq!(Err(DeserializeError::UnexpectedEnd), ByteCode::deserialize(&bytes[..bytes.len() - 1]));
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(Err(DeserializeError::TrailingBytes), ByteCode::deserialize(&trailing));
+    }
+}
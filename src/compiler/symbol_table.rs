@@ -1,11 +1,33 @@
 use std::collections::HashMap;
 
 type SymbolName = String;
-type SymbolIndex = u16;
+type SymbolIndex = usize;
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SymbolScope {
+    Global,
+    Local,
+    Free, // a local of an enclosing function, captured by a closure
+    Builtin,
+    Function, // the function being compiled, so that it can call itself
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub scope: SymbolScope,
+    pub index: SymbolIndex,
+}
+
+#[derive(Clone, Default)]
 pub struct SymbolTable {
-    store: HashMap<SymbolName, SymbolIndex>,
+    store: HashMap<SymbolName, Symbol>,
     next_index: SymbolIndex,
+    // the symbol table of the enclosing function, or None for the global symbol table
+    outer: Option<Box<SymbolTable>>,
+    // the symbols of enclosing functions referenced by this one, in the order they are captured
+    free_symbols: Vec<Symbol>,
+    // globals given a slot ahead of the let that defines them
+    declared: HashMap<SymbolName, Symbol>,
 }
 
 impl SymbolTable {
@@ -14,20 +36,156 @@ impl SymbolTable {
         SymbolTable {
             store: HashMap::new(),
             next_index: 0,
+            outer: None,
+            free_symbols: Vec::new(),
+            declared: HashMap::new(),
+        }
+    }
+
+    pub fn new_enclosed(outer: SymbolTable) -> Self {
+        SymbolTable {
+            outer: Some(Box::new(outer)),
+            ..SymbolTable::new()
+        }
+    }
+
+    /// consumes an enclosed symbol table, returning the table it was enclosed by
+    pub fn into_outer(self) -> Option<SymbolTable> {
+        self.outer.map(|outer| *outer)
+    }
+
+    pub fn define(&mut self, name: SymbolName) -> Symbol {
+        if let Some(symbol) = self.declared.remove(&name) {
+            self.store.insert(name, symbol.clone());
+            return symbol;
         }
+
+        self.define_new(name)
+    }
+
+    /// defines a name in a slot of its own until end_block_scoped is called with it, returning
+    /// what the name referred to in this table before, which it shadows until then
+    pub fn define_block_scoped(&mut self, name: SymbolName) -> (Symbol, Option<Symbol>) {
+        let shadowed = self.store.get(&name).cloned();
+
+        (self.define_new(name), shadowed)
+    }
+
+    /// takes a name defined by define_block_scoped out of scope, its slot isn't reused
+    pub fn end_block_scoped(&mut self, name: SymbolName, shadowed: Option<Symbol>) {
+        match shadowed {
+            Some(symbol) => self.store.insert(name, symbol),
+            None => self.store.remove(&name),
+        };
     }
 
-    pub fn define(&mut self, name: SymbolName) -> SymbolIndex {
-        let index = self.next_index;
-        self.store.insert(name, index);
+    fn define_new(&mut self, name: SymbolName) -> Symbol {
+        let scope = match self.outer {
+            None => SymbolScope::Global,
+            Some(_) => SymbolScope::Local,
+        };
+        let symbol = Symbol {
+            scope,
+            index: self.next_index,
+        };
+        self.store.insert(name, symbol.clone());
 
         self.next_index += 1;
 
-        index
+        symbol
     }
 
-    pub fn resolve(&self, name: &SymbolName) -> Option<SymbolIndex> {
-        self.store.get(name).map(|&index| index)
+    /// reserves a global slot for a name so that function bodies can refer to it before its let,
+    /// the next define of the name takes the reserved slot
+    pub fn declare_global(&mut self, name: SymbolName) {
+        if self.outer.is_some() || self.declared.contains_key(&name) {
+            return;
+        }
+
+        let symbol = Symbol {
+            scope: SymbolScope::Global,
+            index: self.next_index,
+        };
+        self.declared.insert(name, symbol);
+
+        self.next_index += 1;
+    }
+
+    pub fn resolve(&mut self, name: &SymbolName) -> Option<Symbol> {
+        self.resolve_from(name, false)
+    }
+
+    fn resolve_from(&mut self, name: &SymbolName, enclosed: bool) -> Option<Symbol> {
+        if let Some(symbol) = self.store.get(name) {
+            return Some(symbol.clone());
+        }
+        // a function body runs after the let that defines a declared global, top-level code
+        //   before it would read a slot that was never set
+        if enclosed {
+            if let Some(symbol) = self.declared.get(name) {
+                return Some(symbol.clone());
+            }
+        }
+
+        let symbol = self.outer.as_mut()?.resolve_from(name, true)?;
+        match symbol.scope {
+            SymbolScope::Global | SymbolScope::Builtin => Some(symbol),
+            // anything local to an enclosing function has to be captured when the closure is created
+            SymbolScope::Local | SymbolScope::Free | SymbolScope::Function => Some(self.define_free(name.clone(), symbol)),
+        }
+    }
+
+    pub fn define_builtin(&mut self, index: SymbolIndex, name: SymbolName) -> Symbol {
+        let symbol = Symbol {
+            scope: SymbolScope::Builtin,
+            index,
+        };
+        self.store.insert(name, symbol.clone());
+
+        symbol
+    }
+
+    /// the name a function is bound to, defined in the function's own table before its parameters
+    /// so that a parameter with the same name shadows it
+    pub fn define_function_name(&mut self, name: SymbolName) -> Symbol {
+        let symbol = Symbol {
+            scope: SymbolScope::Function,
+            index: 0,
+        };
+        self.store.insert(name, symbol.clone());
+
+        symbol
+    }
+
+    fn define_free(&mut self, name: SymbolName, original: Symbol) -> Symbol {
+        self.free_symbols.push(original);
+        let symbol = Symbol {
+            scope: SymbolScope::Free,
+            index: self.free_symbols.len() - 1,
+        };
+        self.store.insert(name, symbol.clone());
+
+        symbol
+    }
+
+    /// the symbols, as resolved in the enclosing scope, which this scope captures
+    pub fn free_symbols(&self) -> &[Symbol] {
+        &self.free_symbols
+    }
+
+    /// the number of symbols defined directly in this table
+    pub fn num_definitions(&self) -> usize {
+        self.next_index
+    }
+
+    /// the names defined directly in this table along with their symbols, sorted by name
+    pub fn symbols(&self) -> Vec<(&str, &Symbol)> {
+        let mut symbols: Vec<(&str, &Symbol)> = self.store.iter()
+            .map(|(name, symbol)| (name.as_str(), symbol))
+            .collect();
+        symbols.sort_by_key(|&(name, _)| name);
+
+        symbols
     }
 
 }
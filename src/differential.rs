@@ -0,0 +1,130 @@
+//! runs the same programs through the tree-walking evaluator and the vm, the evaluator
+//! is the reference semantics so any difference is a bug in the compiler or the vm
+
+use crate::compiler::compile_from_source;
+use crate::eval::eval_from_source;
+use crate::vm::run_from_bytecode;
+
+// add snippets here as features land. the evaluator panics where the vm returns an error,
+// and its functions only see their own arguments, so snippets stick to what both support
+const PROGRAMS : &[&str] = &[
+    // arithmetic
+    "1;",
+    "1 + 2 * 3 - 4 / 2;",
+    "(1 + 2) * -3;",
+    "7 % 3;",
+    "-7 / 2;",
+    "1.5 + 2;",
+    "10 / 4.0;",
+    "7 ~/ 2; -7.5 ~/ 2;",
+    "10 ~/ 4.0;",
+    "0x1F + 0b101 + 1_000;",
+    "6 & 3; 6 | 3;",
+    "6 ^ 3;",
+    "1 << 4 >> 2;",
+    "1 << 32;",
+    r#""foo" + "bar";"#,
+    r#""ab" * 3;"#,
+    r#""héllo"[1];"#,
+    r#""tab\there\n" + "\"quoted\"";"#,
+    // booleans and comparisons
+    "true;",
+    "!true;",
+    "!!false;",
+    "1 < 2;",
+    "1 > 2;",
+    "1 <= 1;",
+    "2 >= 3;",
+    "1 == 1;",
+    "1 != 1;",
+    "true == false;",
+    "(1 < 2) == true;",
+    r#""a" == "a";"#,
+    r#"1 == "1";"#,
+    "1 == 1.0;",
+    "[1, [2]] == [1, [2]];",
+    "{1: 2} == {1: 2};",
+    "true && false;",
+    "false || true;",
+    "false && 1;",
+    // conditionals
+    "if (true) { 10; };",
+    "if (false) { 10; };",
+    "if (1 > 2) { 10; } else { 20; };",
+    "if (1 < 2) { 10; } else { 20; };",
+    "true ? 1 : 2;",
+    "false ? 1 : true ? 2 : 3;",
+    // bindings
+    "let a = 5; a;",
+    "let a = 5; let b = a * 2; a + b;",
+    "let a = 1; a = a + 1; a += 3; a;",
+    "let a = 1; a++; a++; a--; a;",
+    "let [a, [b, c]] = [1, [2, 3]]; a + b * c;",
+    "let [a, b] = [1];",
+    "let i = 0; let total = 0; while (i < 10) { total += i; i++; } total;",
+    "let i = 0; while (true) { i++; if (i == 7) { break; }; } i;",
+    "let sum = 0; for (x in range(0, 5)) { if (x == 1) { continue; }; sum += x * x; } sum;",
+    "for (x in {}) { }",
+    // arrays and hashes
+    "[1, 2 + 3, true];",
+    "[1, 2, 3][1];",
+    "[1, 2, 3][5];",
+    "[1, 2, 3][-1];",
+    "[1, 2, 3, 4][1:3]; [1, 2][:1];",
+    r#""héllo"[-2:2] + "hello"[3:];"#,
+    r#"[1][1:]; [1]["a":];"#,
+    r#"{"a": 1, 2: true}["a"];"#,
+    "{1: 2}[3];",
+    "{[1]: 2};",
+    // functions
+    "let add = fn(a, b) { a + b; }; add(1, 2);",
+    "let f = fn() { return 1; 2; }; f();",
+    "let f = fn(x) { x * 2; }; f(f(3));",
+    "let f = fn() { }; f();",
+    "let f = fn(x) { if (x > 0) { return x; }; 0; }; f(-1) + f(5);",
+    "return 5; 6;",
+    // builtins
+    r#"len("hello");"#,
+    "len([1, 2, 3]);",
+    "len(1);",
+    "first([1, 2]); last([1, 2]);",
+    "rest([1, 2, 3]);",
+    "push([1], 2);",
+    r#"type(1); type("a");"#,
+    "str([1, 2]);",
+    r#"int("42") + int(1.9);"#,
+    r#"int("abc");"#,
+    r#"keys({"b": 1, "a": 2});"#,
+    r#"values({"b": 1, "a": 2});"#,
+    "contains([1, 2], 2);",
+    "map([1, 2, 3], fn(x) { x * 2; });",
+    "filter([1, 2, 3, 4], fn(x) { x % 2 == 0; });",
+    "reduce([1, 2, 3], fn(acc, x) { acc + x; }, 0);",
+    r#"join(split("a,b,c", ","), "-");"#,
+    "range(5);",
+    "range(10, 0, -3);",
+    "abs(-5) + max(1, 7, 3) * min(4, 2);",
+    "max(1, 2.5); min(0);",
+    r#"sort([3, 1, 2]); sort(["b", "a"]);"#,
+    "sort([1, 3, 2], fn(a, b) { a > b; });",
+    r#"reverse([1, [2]]); reverse("héllo");"#,
+];
+
+#[test]
+fn eval_and_vm_agree() {
+    let mut disagreements = Vec::new();
+
+    for program in PROGRAMS {
+        let evaluated = eval_from_source(program);
+        let ran = compile_from_source(program)
+            .map_err(|err| format!("{:?}", err))
+            .and_then(|byte_code| run_from_bytecode(byte_code).map_err(|err| format!("{:?}", err)));
+
+        match ran {
+            Ok(ran) if ran == evaluated => {},
+            ran => disagreements.push(format!("{}\n    eval: {:?}\n    vm:   {:?}", program, evaluated, ran)),
+        }
+    }
+
+    assert!(disagreements.is_empty(), "eval and vm disagree on:\n{}", disagreements.join("\n"));
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use crate::eval::Object;
 
+#[derive(Default)]
 pub struct Env {
     env: HashMap<String, Object>,
 }
@@ -17,6 +18,10 @@ impl Env {
     }
 
     pub fn get(&self, key: &str) -> Option<Object> {
-        self.env.get(key).map(|val| val.clone())
+        self.env.get(key).cloned()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Object> {
+        self.env.remove(key)
     }
 }
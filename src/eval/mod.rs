@@ -1,25 +1,256 @@
 use crate::parser::Statement;
 use crate::parser::Expr;
 use crate::parser::Prefix;
+use crate::parser::Postfix;
 use crate::parser::Operator;
+use crate::parser::Pattern;
+use crate::builtins;
+use crate::code::LineTable;
+use crate::lexer::lex;
+use crate::parser::parse;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
 
 mod env;
 pub use self::env::Env;
 
+/// equality is structural, arrays and hashes are equal when their contents are,
+/// and objects of different types are never equal, so 1 == 1.0 is false
 #[derive(Debug, PartialEq, Clone)]
 pub enum Object {
     Null,
     Integer(i32),
+    Float(f64),
     String(String),
     Boolean(bool),
     Return(Box<Object>),
+    Break, // a break statement on its way out to the loop it leaves
+    Continue, // likewise for continue
     Function{parameters: Vec<String>, body: Vec<Statement>},
+    Array(Vec<Object>),
+    Hash(HashMap<HashKey, Object>),
+    CompiledFunction(Rc<CompiledFunction>),
+    // the function and free variables are shared rather than copied, so calling a closure is cheap.
+    //   a function refers to itself through OpCurrentClosure rather than capturing itself, so closures can't form a cycle
+    Closure{function: Rc<CompiledFunction>, free: Vec<Rc<Object>>},
+    Builtin(usize), // index into the builtins table
+    Error(String),
+}
+
+impl Object {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Null => "NULL",
+            Object::Integer(_) => "INTEGER",
+            Object::Float(_) => "FLOAT",
+            Object::String(_) => "STRING",
+            Object::Boolean(_) => "BOOLEAN",
+            Object::Return(_) => "RETURN_VALUE",
+            Object::Break => "BREAK",
+            Object::Continue => "CONTINUE",
+            Object::Function{..} | Object::CompiledFunction(_) | Object::Closure{..} => "FUNCTION",
+            Object::Array(_) => "ARRAY",
+            Object::Hash(_) => "HASH",
+            Object::Builtin(_) => "BUILTIN",
+            Object::Error(_) => "ERROR",
+        }
+    }
+}
+
+impl Object {
+    /// the representation used inside arrays and hashes, strings are quoted
+    pub fn inspect(&self) -> String {
+        match self {
+            Object::String(string) => format!("{:?}", string),
+            obj => obj.to_string(),
+        }
+    }
+}
+
+/// the representation puts and the repl use, which is inspect except that strings are written without quotes
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Object::Null => write!(f, "null"),
+            Object::Integer(num) => write!(f, "{}", num),
+            Object::Float(num) => write!(f, "{}", num),
+            Object::String(string) => write!(f, "{}", string),
+            Object::Boolean(val) => write!(f, "{}", val),
+            Object::Return(obj) => write!(f, "{}", obj),
+            Object::Break => write!(f, "break"),
+            Object::Continue => write!(f, "continue"),
+            Object::Function{parameters, ..} => write!(f, "fn({}) {{ ... }}", parameters.join(", ")),
+            Object::CompiledFunction(_) | Object::Closure{..} => write!(f, "fn(...) {{ ... }}"),
+            Object::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(Object::inspect).collect();
+                write!(f, "[{}]", elements.join(", "))
+            },
+            Object::Hash(hash) => {
+                // sorted so the output doesn't depend on the order of the hash map
+                let mut pairs: Vec<String> = hash
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key.inspect(), value.inspect()))
+                    .collect();
+                pairs.sort();
+                write!(f, "{{{}}}", pairs.join(", "))
+            },
+            Object::Builtin(_) => write!(f, "builtin function"),
+            Object::Error(message) => write!(f, "ERROR: {}", message),
+        }
+    }
+}
+
+/// the operands as floats when either of them is a float and both are numbers, integers are promoted
+pub fn float_operands(left: &Object, right: &Object) -> Option<(f64, f64)> {
+    match (left, right) {
+        (Object::Float(left), Object::Float(right)) => Some((*left, *right)),
+        (Object::Integer(left), Object::Float(right)) => Some((*left as f64, *right)),
+        (Object::Float(left), Object::Integer(right)) => Some((*left, *right as f64)),
+        _ => None,
+    }
+}
+
+/// the quotient truncated toward zero as an integer, which ~/ gives when either operand is a float
+pub fn truncated_quotient(left: f64, right: f64) -> Object {
+    let quotient = (left / right).trunc();
+    if quotient >= i32::MIN as f64 && quotient <= i32::MAX as f64 {
+        Object::Integer(quotient as i32)
+    } else {
+        Object::Error(format!("could not convert {} to INTEGER", left / right))
+    }
+}
+
+// the longest string repeating is allowed to build, so a huge count can't exhaust memory
+const MAX_REPEAT_LENGTH : usize = 1 << 20;
+
+/// the string repeated count times, a count of zero or less gives the empty string
+pub fn repeat_string(string: &str, count: i32) -> Object {
+    let count = count.max(0) as usize;
+    if string.len().saturating_mul(count) > MAX_REPEAT_LENGTH {
+        return Object::Error(format!("repeated string would be longer than {} bytes", MAX_REPEAT_LENGTH));
+    }
+
+    Object::String(string.repeat(count))
+}
+
+/// the character at the index as a string of its own, or null when out of bounds
+/// strings are indexed by unicode scalar value rather than by byte
+pub fn string_index(string: &str, index: i32) -> Object {
+    if index < 0 {
+        return Object::Null;
+    }
+
+    string.chars()
+        .nth(index as usize)
+        .map(|character| Object::String(character.to_string()))
+        .unwrap_or(Object::Null)
+}
+
+/// the part of an array or string from start up to but not including end, a null bound means
+/// the beginning or the end and bounds outside the collection are clamped to it
+pub fn slice(collection: &Object, start: &Object, end: &Object) -> Object {
+    let length = match collection {
+        Object::Array(elements) => elements.len(),
+        Object::String(string) => string.chars().count(),
+        collection => return Object::Error(format!("unsupported type for slice: {}", collection.type_name())),
+    };
+    let clamp = |bound: &Object, default: usize| match bound {
+        Object::Null => Ok(default),
+        Object::Integer(bound) => Ok((*bound).max(0).min(length as i32) as usize),
+        bound => Err(Object::Error(format!("slice bounds must be INTEGER, got {}", bound.type_name()))),
+    };
+    let (start, end) = match (clamp(start, 0), clamp(end, length)) {
+        (Ok(start), Ok(end)) => (start, end.max(start)),
+        (Err(err), _) | (_, Err(err)) => return err,
+    };
+
+    match collection {
+        Object::Array(elements) => Object::Array(elements[start..end].to_vec()),
+        Object::String(string) => Object::String(string.chars().skip(start).take(end - start).collect()),
+        _ => unreachable!(),
+    }
+}
+
+/// a function body compiled to bytecode, along with what the vm needs to set up a call to it
+#[derive(Debug, Clone)]
+pub struct CompiledFunction {
+    pub instructions: Vec<u8>,
+    pub num_locals: usize,
+    pub num_parameters: usize,
+    pub lines: LineTable,
+}
+
+// where a function was written doesn't change what it does, so lines aren't compared
+impl PartialEq for CompiledFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.instructions == other.instructions
+            && self.num_locals == other.num_locals
+            && self.num_parameters == other.num_parameters
+    }
+}
+
+/// the subset of objects which can be used as keys in a hash
+/// keys are ordered integers first, then strings, then booleans
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub enum HashKey {
+    Integer(i32),
+    String(String),
+    Boolean(bool),
+}
+
+/// the error for an object which can't be used as a hash key, holding its type name
+#[derive(Debug, PartialEq)]
+pub struct UnusableHashKey(pub &'static str);
+
+impl fmt::Display for UnusableHashKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unusable as hash key: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnusableHashKey {}
+
+impl TryFrom<Object> for HashKey {
+    type Error = UnusableHashKey;
+
+    fn try_from(obj: Object) -> Result<Self, Self::Error> {
+        match obj {
+            Object::Integer(num) => Ok(HashKey::Integer(num)),
+            Object::String(string) => Ok(HashKey::String(string)),
+            Object::Boolean(val) => Ok(HashKey::Boolean(val)),
+            obj => Err(UnusableHashKey(obj.type_name())),
+        }
+    }
+}
+
+impl From<HashKey> for Object {
+    fn from(key: HashKey) -> Self {
+        match key {
+            HashKey::Integer(num) => Object::Integer(num),
+            HashKey::String(string) => Object::String(string),
+            HashKey::Boolean(val) => Object::Boolean(val),
+        }
+    }
+}
+
+impl HashKey {
+    fn inspect(&self) -> String {
+        match self {
+            HashKey::Integer(num) => num.to_string(),
+            HashKey::String(string) => format!("{:?}", string),
+            HashKey::Boolean(val) => val.to_string(),
+        }
+    }
 }
 
 fn eval_expr(expression: Expr, env: &mut Env) -> Object {
     match expression {
         Expr::String(string) => Object::String(string),
         Expr::Const(num) => Object::Integer(num),
+        Expr::Float(num) => Object::Float(num),
         Expr::Boolean(val) => Object::Boolean(val),
         Expr::Prefix { prefix: Prefix::Bang, value: expr } => {
             match eval_expr(*expr, env) {
@@ -30,32 +261,97 @@ fn eval_expr(expression: Expr, env: &mut Env) -> Object {
         Expr::Prefix { prefix: Prefix::Minus, value: expr } => {
             match eval_expr(*expr, env) {
                 Object::Integer(val) => Object::Integer(-val),
-                _ => panic!("minus operator only valid for integer type"),
+                Object::Float(val) => Object::Float(-val),
+                _ => panic!("minus operator only valid for number types"),
             }
         },
+        Expr::Postfix { postfix, value } => {
+            let name = match *value {
+                Expr::Ident(name) => name,
+                _ => panic!("only variables can be incremented or decremented"),
+            };
+            let old_value = env.get(&name).expect("attempted to increment undefined variable");
+            let operator = match postfix {
+                Postfix::Increment => Operator::Plus,
+                Postfix::Decrement => Operator::Minus,
+            };
+            let new_value = eval_expr(
+                Expr::Infix { left: Box::new(Expr::Ident(name.clone())), operator, right: Box::new(Expr::Const(1)) },
+                env
+            );
+            env.set(name, new_value);
+
+            old_value
+        },
         Expr::Infix { left, operator: Operator::Plus, right } => {
             match (eval_expr(*left, env), eval_expr(*right, env)) {
                 (Object::Integer(left), Object::Integer(right)) => Object::Integer(left + right),
                 (Object::String(left), Object::String(right)) => Object::String(left + &right),
-                _ => panic!("plus operator used on invalid types")
+                (left, right) => match float_operands(&left, &right) {
+                    Some((left, right)) => Object::Float(left + right),
+                    None => panic!("plus operator used on invalid types"),
+                },
             }
         },
         Expr::Infix { left, operator: Operator::Minus, right } => {
             match (eval_expr(*left, env), eval_expr(*right, env)) {
                 (Object::Integer(left), Object::Integer(right)) => Object::Integer(left - right),
-                _ => panic!("minus operator only valid on integer types")
+                (left, right) => match float_operands(&left, &right) {
+                    Some((left, right)) => Object::Float(left - right),
+                    None => panic!("minus operator only valid on number types"),
+                },
             }
         },
         Expr::Infix { left, operator: Operator::Multiply, right } => {
             match (eval_expr(*left, env), eval_expr(*right, env)) {
                 (Object::Integer(left), Object::Integer(right)) => Object::Integer(left * right),
-                _ => panic!("multiply operator only valid on integer types")
+                (Object::String(string), Object::Integer(count)) | (Object::Integer(count), Object::String(string)) => {
+                    repeat_string(&string, count)
+                },
+                (left, right) => match float_operands(&left, &right) {
+                    Some((left, right)) => Object::Float(left * right),
+                    None => panic!("multiply operator only valid on number types"),
+                },
             }
         },
         Expr::Infix { left, operator: Operator::Divide, right } => {
             match (eval_expr(*left, env), eval_expr(*right, env)) {
                 (Object::Integer(left), Object::Integer(right)) => Object::Integer(left / right),
-                _ => panic!("divide operator only valid on integer types")
+                (left, right) => match float_operands(&left, &right) {
+                    Some((left, right)) => Object::Float(left / right),
+                    None => panic!("divide operator only valid on number types"),
+                },
+            }
+        },
+        Expr::Infix { left, operator: Operator::IntDivide, right } => {
+            match (eval_expr(*left, env), eval_expr(*right, env)) {
+                (Object::Integer(left), Object::Integer(right)) => Object::Integer(left / right),
+                (left, right) => match float_operands(&left, &right) {
+                    Some((left, right)) => truncated_quotient(left, right),
+                    None => panic!("integer divide operator only valid on number types"),
+                },
+            }
+        },
+        Expr::Infix { left, operator: Operator::Modulo, right } => {
+            match (eval_expr(*left, env), eval_expr(*right, env)) {
+                (Object::Integer(left), Object::Integer(right)) => Object::Integer(left % right),
+                _ => panic!("modulo operator only valid on integer types")
+            }
+        },
+        Expr::Infix {
+            left,
+            operator: operator @ (Operator::BitAnd | Operator::BitOr | Operator::BitXor | Operator::ShiftLeft | Operator::ShiftRight),
+            right
+        } => {
+            match (eval_expr(*left, env), eval_expr(*right, env)) {
+                (Object::Integer(left), Object::Integer(right)) => match operator {
+                    Operator::BitAnd => Object::Integer(left & right),
+                    Operator::BitOr => Object::Integer(left | right),
+                    Operator::BitXor => Object::Integer(left ^ right),
+                    Operator::ShiftLeft => shift(left, right, |left, count| left << count),
+                    _ => shift(left, right, |left, count| left >> count),
+                },
+                _ => panic!("bitwise operators only valid on integer types")
             }
         },
         Expr::Infix { left, operator: Operator::LessThan, right } => {
@@ -64,6 +360,18 @@ fn eval_expr(expression: Expr, env: &mut Env) -> Object {
                 _ => panic!("less than operator only valid on integer types")
             }
         },
+        Expr::Infix { left, operator: Operator::LessThanOrEqual, right } => {
+            match (eval_expr(*left, env), eval_expr(*right, env)) {
+                (Object::Integer(left), Object::Integer(right)) => Object::Boolean(left <= right),
+                _ => panic!("less than or equal operator only valid on integer types")
+            }
+        },
+        Expr::Infix { left, operator: Operator::GreaterThanOrEqual, right } => {
+            match (eval_expr(*left, env), eval_expr(*right, env)) {
+                (Object::Integer(left), Object::Integer(right)) => Object::Boolean(left >= right),
+                _ => panic!("greater than or equal operator only valid on integer types")
+            }
+        },
         Expr::Infix { left, operator: Operator::GreaterThan, right } => {
             match (eval_expr(*left, env), eval_expr(*right, env)) {
                 (Object::Integer(left), Object::Integer(right)) => Object::Boolean(left > right),
@@ -71,36 +379,88 @@ fn eval_expr(expression: Expr, env: &mut Env) -> Object {
             }
         },
         Expr::Infix { left, operator: Operator::Equals, right } => {
-            match (eval_expr(*left, env), eval_expr(*right, env)) {
-                (Object::Integer(left), Object::Integer(right)) => Object::Boolean(left == right),
-                (Object::Boolean(left), Object::Boolean(right)) => Object::Boolean(left == right),
-                _ => panic!("equals operator used on invalid types")
-            }
+            Object::Boolean(eval_expr(*left, env) == eval_expr(*right, env))
         },
         Expr::Infix { left, operator: Operator::NotEquals, right } => {
-            match (eval_expr(*left, env), eval_expr(*right, env)) {
-                (Object::Integer(left), Object::Integer(right)) => Object::Boolean(left != right),
-                (Object::Boolean(left), Object::Boolean(right)) => Object::Boolean(left != right),
-                _ => panic!("not equals operator used on invalid types")
+            Object::Boolean(eval_expr(*left, env) != eval_expr(*right, env))
+        },
+        Expr::Infix { left, operator: Operator::And, right } => {
+            // the right side is only evaluated when it can change the result
+            match eval_expr(*left, env) {
+                Object::Boolean(false) => Object::Boolean(false),
+                Object::Boolean(true) => match eval_expr(*right, env) {
+                    Object::Boolean(val) => Object::Boolean(val),
+                    _ => panic!("and operator only valid on boolean types"),
+                },
+                _ => panic!("and operator only valid on boolean types"),
+            }
+        },
+        Expr::Infix { left, operator: Operator::Or, right } => {
+            match eval_expr(*left, env) {
+                Object::Boolean(true) => Object::Boolean(true),
+                Object::Boolean(false) => match eval_expr(*right, env) {
+                    Object::Boolean(val) => Object::Boolean(val),
+                    _ => panic!("or operator only valid on boolean types"),
+                },
+                _ => panic!("or operator only valid on boolean types"),
             }
         },
         Expr::If { condition, consequence, alternative } => {
-            if eval_expr(*condition, env) == Object::Boolean(true) {
-                eval_statements(consequence, env)
-            } else {
-                eval_statements(alternative, env)
+            match eval_expr(*condition, env) {
+                Object::Boolean(true) => eval_statements(consequence, env),
+                Object::Boolean(false) => eval_statements(alternative, env),
+                obj => condition_type_mismatch(&obj),
             }
         },
         Expr::Ident(name) => env.get(&name).expect("attempted access to invalid binding"),
         Expr::Function{parameters, body} => Object::Function {parameters, body},
+        Expr::Array(elements) => Object::Array(elements.into_iter().map(|expr| eval_expr(expr, env)).collect()),
+        Expr::Hash(pairs) => {
+            let mut hash = HashMap::new();
+            for (key, value) in pairs {
+                let key = match HashKey::try_from(eval_expr(key, env)) {
+                    Ok(key) => key,
+                    Err(err) => return Object::Error(err.to_string()),
+                };
+                hash.insert(key, eval_expr(value, env));
+            }
+
+            Object::Hash(hash)
+        },
+        Expr::Index {left, index} => {
+            match (eval_expr(*left, env), eval_expr(*index, env)) {
+                (Object::Array(elements), Object::Integer(index)) => {
+                    if index < 0 {
+                        Object::Null
+                    } else {
+                        elements.get(index as usize).cloned().unwrap_or(Object::Null)
+                    }
+                },
+                (Object::String(string), Object::Integer(index)) => string_index(&string, index),
+                (Object::Hash(hash), key) => match HashKey::try_from(key) {
+                    Ok(key) => hash.get(&key).cloned().unwrap_or(Object::Null),
+                    Err(err) => Object::Error(err.to_string()),
+                },
+                _ => panic!("index operator not supported"),
+            }
+        },
+        Expr::Slice {left, start, end} => {
+            let collection = eval_expr(*left, env);
+            let mut bound = |bound: Option<Box<Expr>>| bound.map(|bound| eval_expr(*bound, env)).unwrap_or(Object::Null);
+            let start = bound(start);
+            let end = bound(end);
+            slice(&collection, &start, &end)
+        },
         Expr::Call{function, arguments} => {
             let (parameters, body) = match *function {
                 Expr::Ident(func_name) => {
                     match env.get(&func_name) {
                         Some(Object::Function {parameters, body}) => (parameters, body),
                         None => {
-                            let arguments = arguments.into_iter().map(|expr| eval_expr(expr, env)).collect();
-                            return eval_builtin(&func_name, arguments).expect("error calling function");
+                            let builtin = builtins::lookup_by_name(&func_name).expect("attempted to call undefined function");
+                            let arguments: Vec<Object> = arguments.into_iter().map(|expr| eval_expr(expr, env)).collect();
+                            let arguments: Vec<&Object> = arguments.iter().collect();
+                            return builtin(&arguments, &mut Evaluator { output: std::io::stdout() });
                         },
                         _ => panic!("attempted to call non-function"),
                     }
@@ -113,7 +473,7 @@ fn eval_expr(expression: Expr, env: &mut Env) -> Object {
             assert_eq!(parameters.len(), arguments.len(), "called function with wrong number of parameters");
 
             let mut env_func = Env::new();
-            for (parameter, arg_value) in parameters.into_iter().zip(arguments.into_iter()) {
+            for (parameter, arg_value) in parameters.into_iter().zip(arguments) {
                 env_func.set(parameter, eval_expr(arg_value, env));
             }
 
@@ -122,10 +482,35 @@ fn eval_expr(expression: Expr, env: &mut Env) -> Object {
     }
 }
 
-fn eval_builtin(func_name: &str, arguments: Vec<Object>) -> Option<Object> {
-    match (func_name, arguments.as_slice()) {
-        ("len", [Object::String(string)]) => Some(Object::Integer(string.len() as i32)),
-        _ => None,
+/// conditions are strict, as in the vm there's no truthiness for other types
+fn condition_type_mismatch(condition: &Object) -> Object {
+    Object::Error(format!("condition must be BOOLEAN, got {}", condition.type_name()))
+}
+
+fn bind_pattern(pattern: Pattern, value: Object, env: &mut Env) -> Result<(), Object> {
+    match (pattern, value) {
+        (Pattern::Ident(name), value) => env.set(name, value),
+        (Pattern::Array(patterns), Object::Array(elements)) if patterns.len() == elements.len() => {
+            for (pattern, element) in patterns.into_iter().zip(elements) {
+                bind_pattern(pattern, element, env)?;
+            }
+        },
+        (Pattern::Array(patterns), Object::Array(elements)) => return Err(Object::Error(format!(
+            "wrong number of values to destructure: want={}, got={}", patterns.len(), elements.len()
+        ))),
+        (Pattern::Array(_), value) => return Err(Object::Error(format!("cannot destructure {}", value.type_name()))),
+    }
+
+    Ok(())
+}
+
+/// shift counts outside 0 to 31 are an error rather than being masked,
+/// bits shifted out are discarded and >> keeps the sign
+pub fn shift(left: i32, count: i32, op: fn(i32, u32) -> i32) -> Object {
+    if (0..32).contains(&count) {
+        Object::Integer(op(left, count as u32))
+    } else {
+        Object::Error(format!("shift count out of range: {}", count))
     }
 }
 
@@ -137,7 +522,66 @@ fn eval_statement(statement: Statement, env: &mut Env) -> Object {
             env.set(name, value.clone());
             value
         },
+        Statement::Destructure{pattern, value} => {
+            let value = eval_expr(value, env);
+            match bind_pattern(pattern, value.clone(), env) {
+                Ok(()) => value,
+                Err(err) => err,
+            }
+        },
+        Statement::Assign{name, value} => {
+            if env.get(&name).is_none() {
+                panic!("attempted to assign to undefined variable");
+            }
+            let value = eval_expr(value, env);
+            env.set(name, value.clone());
+            value
+        },
         Statement::Return{value: expr} => Object::Return(Box::new(eval_expr(expr, env))),
+        Statement::Break => Object::Break,
+        Statement::Continue => Object::Continue,
+        Statement::Line(_) => Object::Null,
+        Statement::While{condition, body} => {
+            loop {
+                match eval_expr(condition.clone(), env) {
+                    Object::Boolean(true) => {},
+                    Object::Boolean(false) => break,
+                    obj => return condition_type_mismatch(&obj),
+                }
+                match eval_statements(body.clone(), env) {
+                    result @ Object::Return(_) => return result,
+                    Object::Break => break,
+                    // on continue the rest of the body has already been skipped
+                    _ => {},
+                }
+            }
+            Object::Null
+        },
+        Statement::For{variable, iterable, body} => {
+            let elements = match eval_expr(iterable, env) {
+                Object::Array(elements) => elements,
+                obj => return Object::Error(format!("for loops iterate over ARRAY, got {}", obj.type_name())),
+            };
+            // the loop variable only exists inside the loop, whatever it shadowed is put back afterwards
+            let shadowed = env.get(&variable);
+            let mut result = Object::Null;
+            for element in elements {
+                env.set(variable.clone(), element);
+                match eval_statements(body.clone(), env) {
+                    Object::Return(value) => {
+                        result = Object::Return(value);
+                        break;
+                    },
+                    Object::Break => break,
+                    _ => {},
+                }
+            }
+            match shadowed {
+                Some(value) => env.set(variable, value),
+                None => { env.remove(&variable); },
+            }
+            result
+        },
     }
 }
 
@@ -149,7 +593,7 @@ fn eval_statements(statements: Vec<Statement>, env: &mut Env) -> Object {
     for statement in statements {
         result = eval_statement(statement, env);
 
-        if let &Object::Return(_) = &result {
+        if let Object::Return(_) | Object::Break | Object::Continue = &result {
             return result;
         }
     }
@@ -157,21 +601,103 @@ fn eval_statements(statements: Vec<Statement>, env: &mut Env) -> Object {
     result
 }
 
+/// runs the functions builtins call, printing to stdout
+struct Evaluator {
+    output: std::io::Stdout,
+}
+
+impl builtins::Interpreter for Evaluator {
+    fn output(&mut self) -> &mut dyn std::io::Write {
+        &mut self.output
+    }
+
+    fn call(&mut self, function: &Object, arguments: Vec<Object>) -> Object {
+        match function {
+            Object::Function {parameters, body} => {
+                if parameters.len() != arguments.len() {
+                    return Object::Error(format!(
+                        "wrong number of arguments: want={}, got={}", parameters.len(), arguments.len()
+                    ));
+                }
+
+                let mut env_func = Env::new();
+                for (parameter, argument) in parameters.iter().zip(arguments) {
+                    env_func.set(parameter.clone(), argument);
+                }
+
+                eval_return_scope(body.clone(), &mut env_func)
+            },
+            Object::Builtin(index) => {
+                let builtin = builtins::lookup(*index).expect("builtin index should be valid");
+                let arguments: Vec<&Object> = arguments.iter().collect();
+                builtin(&arguments, self)
+            },
+            obj => Object::Error(format!("calling non-function: {}", obj.type_name())),
+        }
+    }
+}
+
 pub fn eval_return_scope(statements: Vec<Statement>, env: &mut Env) -> Object {
     let result = eval_statements(statements, env);
 
     match result {
         // unwrap Return type
         Object::Return(res) => *res,
+        Object::Break => Object::Error(String::from("break outside of a loop")),
+        Object::Continue => Object::Error(String::from("continue outside of a loop")),
         _ => result,
     }
 }
 
+/// lexes, parses and evaluates a whole program in a fresh environment, a syntax error is returned as an error object
+pub fn eval_from_source(input: &str) -> Object {
+    let mut tokens = lex(input);
+    match parse(&mut tokens) {
+        Ok(ast) => eval_return_scope(ast, &mut Env::new()),
+        Err(error) => Object::Error(error.to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::lexer::lex;
-    use crate::parser::parse;
+
+    #[test]
+    fn object_display() {
+        let mut hash = HashMap::new();
+        hash.insert(HashKey::String(String::from("b")), Object::Array(vec![Object::Integer(2)]));
+        hash.insert(HashKey::String(String::from("a")), Object::Integer(1));
+        let mut key_types = HashMap::new();
+        key_types.insert(HashKey::Integer(1), Object::Boolean(false));
+        key_types.insert(HashKey::Boolean(true), Object::Null);
+
+        assert_eq!("5", Object::Integer(5).to_string());
+        assert_eq!("2.5", Object::Float(2.5).to_string());
+        assert_eq!("true", Object::Boolean(true).to_string());
+        assert_eq!("null", Object::Null.to_string());
+        assert_eq!("hello", Object::String(String::from("hello")).to_string());
+        assert_eq!(r#""hello""#, Object::String(String::from("hello")).inspect());
+        assert_eq!("[1, 2, 3]", Object::Array(vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)]).to_string());
+        assert_eq!(
+            r#"[[1, "a"], []]"#,
+            Object::Array(vec![
+                Object::Array(vec![Object::Integer(1), Object::String(String::from("a"))]),
+                Object::Array(vec![]),
+            ]).to_string()
+        );
+        assert_eq!(r#"{"a": 1, "b": [2]}"#, Object::Hash(hash).to_string());
+        assert_eq!("{1: false, true: null}", Object::Hash(key_types).to_string());
+        assert_eq!("{}", Object::Hash(HashMap::new()).to_string());
+        assert_eq!(
+            "fn(x, y) { ... }",
+            Object::Function{parameters: vec![String::from("x"), String::from("y")], body: vec![]}.to_string()
+        );
+        assert_eq!(
+            "fn(...) { ... }",
+            Object::CompiledFunction(Rc::new(CompiledFunction { instructions: vec![], num_locals: 0, num_parameters: 1, lines: LineTable::default() })).to_string()
+        );
+        assert_eq!("ERROR: oops", Object::Error(String::from("oops")).to_string());
+    }
 
     #[test]
     fn eval_int_literal() {
@@ -208,13 +734,37 @@ mod tests {
         test_eval("5 - 5;", Object::Integer(0));
         test_eval("5 * 5;", Object::Integer(25));
         test_eval("5 / 5;", Object::Integer(1));
+        test_eval("10 % 3;", Object::Integer(1));
         test_eval("5 > 1;", Object::Boolean(true));
         test_eval("5 < 1;", Object::Boolean(false));
+        test_eval("5 >= 5;", Object::Boolean(true));
+        test_eval("5 <= 4;", Object::Boolean(false));
         test_eval("5 == 1;", Object::Boolean(false));
         test_eval("5 != 1;", Object::Boolean(true));
         test_eval("true == true;", Object::Boolean(true));
         test_eval("true != true;", Object::Boolean(false));
         test_eval("(1 > 2) == false;", Object::Boolean(true));
+        test_eval("true && false;", Object::Boolean(false));
+        test_eval("false || 1 < 2;", Object::Boolean(true));
+    }
+
+    #[test]
+    fn eval_bitwise() {
+        test_eval("6 & 3;", Object::Integer(2));
+        test_eval("6 | 1;", Object::Integer(7));
+        test_eval("5 ^ 1;", Object::Integer(4));
+        test_eval("1 << 4;", Object::Integer(16));
+        test_eval("256 >> 2;", Object::Integer(64));
+        test_eval("-16 >> 2;", Object::Integer(-4));
+        test_eval("1 << 32;", Object::Error(String::from("shift count out of range: 32")));
+    }
+
+    #[test]
+    fn eval_structural_equality() {
+        test_eval("[1, [2]] == [1, [2]];", Object::Boolean(true));
+        test_eval(r#"{"a": 1, "b": 2} == {"b": 2, "a": 1};"#, Object::Boolean(true));
+        test_eval(r#""a" != "b";"#, Object::Boolean(true));
+        test_eval(r#"1 == "1";"#, Object::Boolean(false));
     }
 
     #[test]
@@ -222,6 +772,18 @@ mod tests {
         test_eval(r#""hello " + "world";"#, Object::String(String::from("hello world")));
     }
 
+    #[test]
+    fn eval_float() {
+        test_eval("1.5 + 2.5;", Object::Float(4.0));
+        test_eval("3 * 2.0;", Object::Float(6.0));
+        test_eval("-0.5 - 1;", Object::Float(-1.5));
+        test_eval("6 / 2;", Object::Integer(3));
+        test_eval("7 / 2.0;", Object::Float(3.5));
+        test_eval("7 ~/ 2;", Object::Integer(3));
+        test_eval("7.5 ~/ 2;", Object::Integer(3));
+        test_eval("-7.0 ~/ 2;", Object::Integer(-3));
+    }
+
     #[test]
     fn eval_infix_nested_types() {
         test_eval("(1 + 2) + 3;", Object::Integer(6));
@@ -243,6 +805,63 @@ mod tests {
         test_eval("if (1 < 2) { 10; } else { 11; };", Object::Integer(10));
     }
 
+    #[test]
+    fn eval_conditions_must_be_boolean() {
+        test_eval("if (5) { 1; };", Object::Error(String::from("condition must be BOOLEAN, got INTEGER")));
+        test_eval(r#"if ("yes") { 1; };"#, Object::Error(String::from("condition must be BOOLEAN, got STRING")));
+        test_eval(
+            "if (if (false) { 1; }) { 1; };",
+            Object::Error(String::from("condition must be BOOLEAN, got NULL"))
+        );
+        test_eval("while (1) { 1; }", Object::Error(String::from("condition must be BOOLEAN, got INTEGER")));
+    }
+
+    #[test]
+    fn eval_assign() {
+        test_eval("let x = 1; x = 5; x;", Object::Integer(5));
+        test_eval("let x = 1; x = x + 1; x;", Object::Integer(2));
+    }
+
+    #[test]
+    fn eval_while() {
+        test_eval("let i = 0; while (i < 5) { let i = i + 1; }; i;", Object::Integer(5));
+        test_eval("while (false) { 1; } 2;", Object::Integer(2));
+        test_eval("while (true) { return 3; } 4;", Object::Integer(3));
+    }
+
+    #[test]
+    fn eval_break() {
+        test_eval("let i = 0; while (true) { i = i + 1; if (i == 5) { break; }; } i;", Object::Integer(5));
+        // only the innermost loop is left
+        test_eval(
+            "let i = 0; let n = 0; while (i < 3) { i = i + 1; while (true) { n = n + 1; break; } } n;",
+            Object::Integer(3),
+        );
+        test_eval("break;", Object::Error(String::from("break outside of a loop")));
+    }
+
+    #[test]
+    fn eval_for() {
+        test_eval("let sum = 0; for (x in [1, 2, 3]) { sum = sum + x; } sum;", Object::Integer(6));
+        test_eval("let sum = 0; for (x in range(0, 3)) { sum = sum + x; } sum;", Object::Integer(3));
+        test_eval(
+            "let sum = 0; for (x in range(0, 10)) { if (x == 5) { break; }; if (x % 2 == 0) { continue; }; sum = sum + x; } sum;",
+            Object::Integer(4),
+        );
+        test_eval("let f = fn() { for (x in [1, 2]) { return x; } }; f();", Object::Integer(1));
+        test_eval("let x = 10; for (x in [1, 2]) { } x;", Object::Integer(10));
+        test_eval("for (x in 5) { }", Object::Error(String::from("for loops iterate over ARRAY, got INTEGER")));
+    }
+
+    #[test]
+    fn eval_continue() {
+        test_eval(
+            "let i = 0; let sum = 0; while (i < 10) { i = i + 1; if (i % 2 == 0) { continue; }; sum = sum + i; } sum;",
+            Object::Integer(25),
+        );
+        test_eval("continue;", Object::Error(String::from("continue outside of a loop")));
+    }
+
     #[test]
     fn eval_return() {
         test_eval("return 10;", Object::Integer(10));
@@ -268,6 +887,15 @@ mod tests {
         test_eval("let a = 10;", Object::Integer(10)); // useful for repl
     }
 
+    #[test]
+    fn eval_destructure() {
+        test_eval("let [a, [b, c]] = [1, [2, 3]]; a + b * c;", Object::Integer(7));
+        test_eval(
+            "let [a, b] = [1];",
+            Object::Error(String::from("wrong number of values to destructure: want=2, got=1"))
+        );
+    }
+
     #[test]
     fn eval_function() {
         test_eval("fn(x) { x; };", Object::Function {
@@ -282,20 +910,127 @@ mod tests {
         test_eval("let add = fn(x, y) { return x + y; }; let three = add(1, 2); 5;", Object::Integer(5)); // return value inside the function should not cause the entire program to return
     }
 
-    fn test_eval(input: &str, expected: Object) {
-        let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
-        let mut env = Env::new();
-        let obj = eval_return_scope(ast, &mut env);
+    #[test]
+    fn eval_array() {
+        test_eval("[];", Object::Array(vec![]));
+        test_eval("[1, 2 * 2, [3]];", Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(4),
+            Object::Array(vec![Object::Integer(3)]),
+        ]));
+    }
+
+    #[test]
+    fn eval_hash() {
+        let mut expected = HashMap::new();
+        expected.insert(HashKey::String(String::from("one")), Object::Integer(1));
+        expected.insert(HashKey::Integer(2), Object::Integer(3));
+        expected.insert(HashKey::Boolean(true), Object::Integer(4));
+
+        test_eval(r#"{"one": 1, 2: 2, true: 4, 2: 3};"#, Object::Hash(expected));
+    }
+
+    #[test]
+    fn eval_index() {
+        test_eval("[1, 2, 3][1];", Object::Integer(2));
+        test_eval("[1, 2, 3][99];", Object::Null);
+        test_eval("[1, 2, 3][-1];", Object::Null);
+        test_eval(r#"{"a": 5}["a"];"#, Object::Integer(5));
+        test_eval(r#"{"a": 5}["b"];"#, Object::Null);
+    }
+
+    #[test]
+    fn eval_slices() {
+        test_eval("[1, 2, 3, 4][1:3];", Object::Array(vec![Object::Integer(2), Object::Integer(3)]));
+        test_eval("[1, 2, 3][2:];", Object::Array(vec![Object::Integer(3)]));
+        test_eval("[1, 2, 3][:-1];", Object::Array(vec![]));
+        test_eval(r#""hello"[1:3];"#, Object::String(String::from("el")));
+        test_eval(r#""hello"[:10];"#, Object::String(String::from("hello")));
+    }
 
+    #[test]
+    fn eval_postfix() {
+        test_eval("let i = 0; i++;", Object::Integer(0));
+        test_eval("let i = 0; i++; i;", Object::Integer(1));
+        test_eval("let i = 0; i--; i--; i;", Object::Integer(-2));
+    }
+
+    #[test]
+    fn eval_string_repeat() {
+        test_eval(r#""ab" * 3;"#, Object::String(String::from("ababab")));
+        test_eval(r#"2 * "c";"#, Object::String(String::from("cc")));
+        test_eval(r#""d" * -1;"#, Object::String(String::new()));
+    }
+
+    #[test]
+    fn eval_string_index() {
+        test_eval(r#""hello"[1];"#, Object::String(String::from("e")));
+        test_eval(r#""héllo"[1];"#, Object::String(String::from("é")));
+        test_eval(r#""hello"[5];"#, Object::Null);
+        test_eval(r#""hello"[-1];"#, Object::Null);
+    }
+
+    #[test]
+    fn hash_key_try_from() {
+        assert_eq!(Ok(HashKey::Integer(1)), HashKey::try_from(Object::Integer(1)));
+        assert_eq!(Ok(HashKey::String(String::from("a"))), HashKey::try_from(Object::String(String::from("a"))));
+        assert_eq!(Err(UnusableHashKey("ARRAY")), HashKey::try_from(Object::Array(vec![])));
+        assert_eq!("unusable as hash key: NULL", UnusableHashKey("NULL").to_string());
+
+        test_eval("{[1]: 2};", Object::Error(String::from("unusable as hash key: ARRAY")));
+        test_eval("{1: 2}[[1]];", Object::Error(String::from("unusable as hash key: ARRAY")));
+    }
+
+    fn test_eval(input: &str, expected: Object) {
         assert_eq!(
             expected,
-            obj
+            eval_from_source(input)
         );
     }
 
     #[test]
     fn eval_builtin_len() {
         test_eval(r#"len("hello");"#, Object::Integer(5));
+        test_eval(r#"len("héllo");"#, Object::Integer(5));
+        test_eval("len([1, 2, 3]);", Object::Integer(3));
+        test_eval("len(1);", Object::Error(String::from("argument to `len` not supported, got INTEGER")));
+    }
+
+    #[test]
+    fn eval_builtin_type() {
+        test_eval("type(1);", Object::String(String::from("INTEGER")));
+        test_eval(r#"type("a");"#, Object::String(String::from("STRING")));
+        test_eval("type(fn(x) { x; });", Object::String(String::from("FUNCTION")));
+    }
+
+    #[test]
+    fn eval_builtin_str() {
+        test_eval(r#""n = " + str(1 + 2);"#, Object::String(String::from("n = 3")));
+    }
+
+    #[test]
+    fn eval_builtin_higher_order_functions() {
+        test_eval("map([1, 2, 3], fn(x) { x * 2; });", Object::Array(vec![
+            Object::Integer(2),
+            Object::Integer(4),
+            Object::Integer(6),
+        ]));
+        test_eval("let odd = fn(x) { x % 2 == 1; }; filter([1, 2, 3], odd);", Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(3),
+        ]));
+        test_eval("reduce([1, 2, 3, 4], fn(sum, x) { sum + x; }, 0);", Object::Integer(10));
+        test_eval(
+            "map([1], fn(x, y) { x; });",
+            Object::Error(String::from("wrong number of arguments: want=2, got=1"))
+        );
+    }
+
+    #[test]
+    fn eval_builtin_array_functions() {
+        test_eval("first([1, 2]);", Object::Integer(1));
+        test_eval("last([1, 2]);", Object::Integer(2));
+        test_eval("rest([1, 2]);", Object::Array(vec![Object::Integer(2)]));
+        test_eval("push([1], 2);", Object::Array(vec![Object::Integer(1), Object::Integer(2)]));
     }
 }
@@ -1,38 +1,72 @@
-use logos::Logos;
+use logos::{Filter, Lexer, Logos};
 
 #[derive(Logos, Debug, PartialEq, Clone)]
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[logos(trivia = r"\p{Whitespace}")]
 pub enum Token {
     #[error]
     ERROR,
     EOF,
+    #[regex(r"//[^\n]*", logos::skip)]
+    #[token("/*", block_comment)]
+    UNTERMINATED_COMMENT, // a block comment still open at the end of the input, other comments are skipped
     #[regex("[a-zA-Z]+", |lexer| lexer.slice().to_owned())]
     IDENT(String),
-    #[regex("[0-9]+", |lexer| lexer.slice().parse())]
+    #[regex("[0-9][0-9_]*", |lexer| parse_integer(lexer.slice(), 10))]
+    #[regex("0x[0-9a-zA-Z_]*", |lexer| parse_integer(&lexer.slice()[2..], 16))]
+    #[regex("0b[0-9a-zA-Z_]*", |lexer| parse_integer(&lexer.slice()[2..], 2))]
     INT(i32),
-    #[regex(r#""[^"]*""#, |lexer| lexer.slice()[1..(lexer.slice().len()-1)].to_owned())]
-    STRING(String), // string literal, let x = "my string";
+    #[regex(r"[0-9]+\.[0-9]+", |lexer| lexer.slice().parse())]
+    FLOAT(f64),
+    #[regex(r#""([^"\\]|\\[ntr0"\\])*""#, |lexer| unescape(&lexer.slice()[1..(lexer.slice().len()-1)]))]
+    #[regex(r#"r#*""#, raw_string)]
+    STRING(String), // string literal, let x = "my string"; or a raw one without escapes, r"C:\path" or r#"say "hi""#
+    #[regex(r#""([^"\\]|\\[ntr0"\\])*\\"#, invalid_escape)]
+    INVALID_ESCAPE(char), // the character after the backslash in a string with an unknown escape like \q
     #[token = "="]
     ASSIGN,
+    #[token = "+="]
+    PLUS_ASSIGN,
+    #[token = "-="]
+    MINUS_ASSIGN,
+    #[token = "*="]
+    ASTERISK_ASSIGN,
+    #[token = "/="]
+    SLASH_ASSIGN,
     #[token = "+"]
     PLUS,
     #[token = "-"]
     MINUS,
+    #[token = "++"]
+    INCREMENT,
+    #[token = "--"]
+    DECREMENT,
     #[token = "/"]
     SLASH,
+    #[token = "~/"]
+    TILDE_SLASH, // integer division, // can't be used because it starts a line comment
     #[token = "*"]
     ASTERISK,
+    #[token = "%"]
+    PERCENT,
     #[token = "<"]
     LT,
     #[token = ">"]
     GT,
+    #[token = "<="]
+    LT_EQ,
+    #[token = ">="]
+    GT_EQ,
     #[token = "!"]
     BANG,
     #[token = ","]
     COMMA,
     #[token = ";"]
     SEMICOLON,
+    #[token = ":"]
+    COLON,
+    #[token = "?"]
+    QUESTION,
     #[token = "("]
     LPAREN,
     #[token = ")"]
@@ -41,6 +75,10 @@ pub enum Token {
     LBRACE,
     #[token = "}"]
     RBRACE,
+    #[token = "["]
+    LBRACKET,
+    #[token = "]"]
+    RBRACKET,
     #[token = "fn"]
     FUNCTION,
     #[token = "let"]
@@ -49,8 +87,18 @@ pub enum Token {
     IF,
     #[token = "else"]
     ELSE,
+    #[token = "while"]
+    WHILE,
+    #[token = "for"]
+    FOR,
+    #[token = "in"]
+    IN,
     #[token = "return"]
     RETURN,
+    #[token = "break"]
+    BREAK,
+    #[token = "continue"]
+    CONTINUE,
     #[token = "true"]
     TRUE,
     #[token = "false"]
@@ -59,6 +107,111 @@ pub enum Token {
     EQ,
     #[token = "!="]
     NOT_EQ,
+    #[token = "&&"]
+    AND,
+    #[token = "||"]
+    OR,
+    #[token = "&"]
+    AMPERSAND,
+    #[token = "|"]
+    PIPE,
+    #[token = "^"]
+    CARET,
+    #[token = "<<"]
+    SHIFT_LEFT,
+    #[token = ">>"]
+    SHIFT_RIGHT,
+}
+
+/// underscores can separate digits, a literal with no digits or one too large for an i32 is an error
+fn parse_integer(digits: &str, radix: u32) -> Option<i32> {
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return None;
+    }
+
+    i32::from_str_radix(&digits, radix).ok()
+}
+
+/// the contents of a string literal with its escape sequences replaced, the regex only lets through known ones
+fn unescape(contents: &str) -> String {
+    let mut unescaped = String::with_capacity(contents.len());
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c) => c, // a quote or a backslash
+            None => break,
+        });
+    }
+
+    unescaped
+}
+
+/// a raw string ends at the first quote followed by as many #s as it started with, so r#"..."# can contain a quote
+fn raw_string(lexer: &mut Lexer<Token>) -> Option<String> {
+    let closing = format!("\"{}", &lexer.slice()[1..lexer.slice().len() - 1]);
+    let length = match lexer.remainder().find(&closing) {
+        Some(length) => length,
+        None => {
+            // never closed, the rest of the input is part of the error
+            lexer.bump(lexer.remainder().len());
+            return None;
+        },
+    };
+    let contents = lexer.remainder()[..length].to_owned();
+    lexer.bump(length + closing.len());
+
+    Some(contents)
+}
+
+/// the regex stops at the backslash, a string which only fails to lex because it's never closed can end up here too
+/// takes the rest of the string along with the bad escape, so lexing carries on after it
+fn invalid_escape(lexer: &mut Lexer<Token>) -> Option<char> {
+    let escaped = lexer.remainder().chars().next().filter(|c| !"ntr0\"\\".contains(*c))?;
+    let mut chars = lexer.remainder().char_indices().skip(1);
+    while let Some((position, c)) = chars.next() {
+        match c {
+            '"' => {
+                lexer.bump(position + 1);
+                break;
+            },
+            '\\' => {
+                chars.next();
+            },
+            _ => {},
+        }
+    }
+
+    Some(escaped)
+}
+
+/// skips over a block comment, block comments nest so that code containing one can be commented out
+fn block_comment(lexer: &mut Lexer<Token>) -> Filter<()> {
+    let mut depth = 1;
+    let mut chars = lexer.remainder().char_indices().peekable();
+    while let Some((position, c)) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some(&(_, '*'))) => depth += 1,
+            ('*', Some(&(_, '/'))) => depth -= 1,
+            _ => continue,
+        }
+        chars.next();
+        if depth == 0 {
+            lexer.bump(position + 2);
+            return Filter::Skip;
+        }
+    }
+
+    lexer.bump(lexer.remainder().len());
+    Filter::Emit(())
 }
 
 pub fn lex(input: &str) -> Vec<Token> {
@@ -69,6 +222,27 @@ pub fn lex(input: &str) -> Vec<Token> {
     tokens
 }
 
+/// like lex, along with the line each token starts on, counting from 1
+pub fn lex_with_lines(input: &str) -> (Vec<Token>, Vec<usize>) {
+    let mut tokens = Vec::new();
+    let mut lines = Vec::new();
+    let mut lexer = Token::lexer(input);
+    let (mut line, mut counted) = (1, 0);
+
+    while let Some(token) = lexer.next() {
+        let start = lexer.span().start;
+        line += input[counted..start].matches('\n').count();
+        counted = start;
+
+        tokens.push(token);
+        lines.push(line);
+    }
+    tokens.push(Token::EOF);
+    lines.push(line + input[counted..].matches('\n').count());
+
+    (tokens, lines)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -208,7 +382,7 @@ mod tests {
 
     #[test]
     fn lex_additional_opeations() {
-        let input = "- / * < > !";
+        let input = "- / * % < > <= >= !";
         let tokens = lex(input);
 
         assert_eq!(
@@ -216,8 +390,11 @@ mod tests {
                 Token::MINUS,
                 Token::SLASH,
                 Token::ASTERISK,
+                Token::PERCENT,
                 Token::LT,
                 Token::GT,
+                Token::LT_EQ,
+                Token::GT_EQ,
                 Token::BANG,
                 Token::EOF,
             ],
@@ -301,4 +478,290 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lex_string_escapes() {
+        assert_eq!(
+            vec![Token::STRING(String::from("a\nb\tc\rd\0e\"f\\g")), Token::EOF],
+            lex(r#""a\nb\tc\rd\0e\"f\\g""#)
+        );
+        assert_eq!(vec![Token::STRING(String::from("\"")), Token::EOF], lex(r#""\"""#));
+        assert_eq!(vec![Token::STRING(String::from("\\")), Token::SEMICOLON, Token::EOF], lex(r#""\\";"#));
+        assert_eq!(vec![Token::STRING(String::from("héllo\n")), Token::EOF], lex(r#""héllo\n""#));
+
+        assert_eq!(vec![Token::INVALID_ESCAPE('q'), Token::SEMICOLON, Token::EOF], lex(r#""a\qb\"c";"#));
+        assert_eq!(vec![Token::INVALID_ESCAPE('é'), Token::EOF], lex(r#""\é""#));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex(r#""never closed"#));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex(r#""never \n closed"#));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex(r#""ends in a backslash\"#));
+    }
+
+    #[test]
+    fn lex_raw_strings() {
+        assert_eq!(vec![Token::STRING(String::from(r"C:\path\to")), Token::EOF], lex(r#"r"C:\path\to""#));
+        assert_eq!(vec![Token::STRING(String::from(r"\n\q\")), Token::SEMICOLON, Token::EOF], lex(r#"r"\n\q\";"#));
+        assert_eq!(vec![Token::STRING(String::from(r#"say "hi""#)), Token::EOF], lex(r###"r#"say "hi""#"###));
+        assert_eq!(vec![Token::STRING(String::from(r##"a "# b"##)), Token::EOF], lex(r###"r##"a "# b"##"###));
+        assert_eq!(vec![Token::STRING(String::new()), Token::EOF], lex(r#"r"""#));
+        assert_eq!(
+            vec![Token::IDENT(String::from("r")), Token::PLUS, Token::IDENT(String::from("rest")), Token::EOF],
+            lex("r + rest")
+        );
+
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex(r#"r"never closed"#));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex(r##"r#"closed without the hash""##));
+    }
+
+    #[test]
+    fn lex_array() {
+        let input = "[1, 2];";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::LBRACKET,
+                Token::INT(1),
+                Token::COMMA,
+                Token::INT(2),
+                Token::RBRACKET,
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_hash() {
+        let input = r#"{"a": 1};"#;
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::LBRACE,
+                Token::STRING(String::from("a")),
+                Token::COLON,
+                Token::INT(1),
+                Token::RBRACE,
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_float() {
+        let input = "2.5 + 2;";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::FLOAT(2.5),
+                Token::PLUS,
+                Token::INT(2),
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_logical_operators() {
+        let input = "true && false || true;";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::TRUE,
+                Token::AND,
+                Token::FALSE,
+                Token::OR,
+                Token::TRUE,
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_increment_decrement() {
+        let input = "i++; j--; 1 - -1;";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::IDENT(String::from("i")),
+                Token::INCREMENT,
+                Token::SEMICOLON,
+                Token::IDENT(String::from("j")),
+                Token::DECREMENT,
+                Token::SEMICOLON,
+                Token::INT(1),
+                Token::MINUS,
+                Token::MINUS,
+                Token::INT(1),
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_compound_assign() {
+        let input = "x += 1; x -= 2; x *= 3; x /= 4;";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::IDENT(String::from("x")),
+                Token::PLUS_ASSIGN,
+                Token::INT(1),
+                Token::SEMICOLON,
+                Token::IDENT(String::from("x")),
+                Token::MINUS_ASSIGN,
+                Token::INT(2),
+                Token::SEMICOLON,
+                Token::IDENT(String::from("x")),
+                Token::ASTERISK_ASSIGN,
+                Token::INT(3),
+                Token::SEMICOLON,
+                Token::IDENT(String::from("x")),
+                Token::SLASH_ASSIGN,
+                Token::INT(4),
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_comments() {
+        let input = r#"
+            // a line comment
+            let x = 1; // trailing
+            /* a block
+               comment */ x /* inline */ + 2;
+            /* nested /* block */ comment */
+            "not // a comment /* either */";
+        "#;
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::LET,
+                Token::IDENT(String::from("x")),
+                Token::ASSIGN,
+                Token::INT(1),
+                Token::SEMICOLON,
+                Token::IDENT(String::from("x")),
+                Token::PLUS,
+                Token::INT(2),
+                Token::SEMICOLON,
+                Token::STRING(String::from("not // a comment /* either */")),
+                Token::SEMICOLON,
+                Token::EOF,
+            ],
+            tokens
+        );
+        assert_eq!(vec![Token::INT(1), Token::SLASH, Token::INT(2), Token::EOF], lex("1 / 2"));
+        assert_eq!(vec![Token::INT(1), Token::EOF], lex("1 // no newline at the end"));
+
+        // // always starts a line comment, so integer division is spelled ~/ instead
+        assert_eq!(vec![Token::INT(7), Token::EOF], lex("7 // 2;"));
+        assert_eq!(vec![Token::INT(7), Token::TILDE_SLASH, Token::INT(2), Token::EOF], lex("7 ~/ 2"));
+    }
+
+    #[test]
+    fn lex_unterminated_comment() {
+        assert_eq!(vec![Token::INT(1), Token::UNTERMINATED_COMMENT, Token::EOF], lex("1 /* never closed"));
+        assert_eq!(vec![Token::UNTERMINATED_COMMENT, Token::EOF], lex("/* outer /* inner */ still open"));
+    }
+
+    #[test]
+    fn lex_integer_literals() {
+        assert_eq!(vec![Token::INT(255), Token::EOF], lex("0xFF"));
+        assert_eq!(vec![Token::INT(255), Token::EOF], lex("0xff"));
+        assert_eq!(vec![Token::INT(10), Token::EOF], lex("0b1010"));
+        assert_eq!(vec![Token::INT(1000000), Token::EOF], lex("1_000_000"));
+        assert_eq!(vec![Token::INT(0xABCD), Token::EOF], lex("0xAB_CD"));
+        assert_eq!(vec![Token::INT(5), Token::EOF], lex("0b_101"));
+        assert_eq!(vec![Token::INT(7), Token::EOF], lex("007"));
+        assert_eq!(vec![Token::FLOAT(1.5), Token::EOF], lex("1.5"));
+
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("0x"));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("0b"));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("0x_"));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("0b102"));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("0xFG"));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("0x80000000"));
+        assert_eq!(vec![Token::ERROR, Token::EOF], lex("2147483648"));
+    }
+
+    #[test]
+    fn lex_bitwise_operators() {
+        let input = "a & b | c ^ d << 1 >> 2 && e || f <= g";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::IDENT(String::from("a")),
+                Token::AMPERSAND,
+                Token::IDENT(String::from("b")),
+                Token::PIPE,
+                Token::IDENT(String::from("c")),
+                Token::CARET,
+                Token::IDENT(String::from("d")),
+                Token::SHIFT_LEFT,
+                Token::INT(1),
+                Token::SHIFT_RIGHT,
+                Token::INT(2),
+                Token::AND,
+                Token::IDENT(String::from("e")),
+                Token::OR,
+                Token::IDENT(String::from("f")),
+                Token::LT_EQ,
+                Token::IDENT(String::from("g")),
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_lines() {
+        let input = "let x = 1;\n\n/* a\ncomment */ x +\n  2;\n";
+        let (tokens, lines) = lex_with_lines(input);
+
+        assert_eq!(lex(input), tokens);
+        assert_eq!(vec![1, 1, 1, 1, 1, 4, 4, 5, 5, 6], lines);
+    }
+
+    #[test]
+    fn lex_while() {
+        let input = "while (x) { y; break; continue; }";
+        let tokens = lex(input);
+
+        assert_eq!(
+            vec![
+                Token::WHILE,
+                Token::LPAREN,
+                Token::IDENT(String::from("x")),
+                Token::RPAREN,
+                Token::LBRACE,
+                Token::IDENT(String::from("y")),
+                Token::SEMICOLON,
+                Token::BREAK,
+                Token::SEMICOLON,
+                Token::CONTINUE,
+                Token::SEMICOLON,
+                Token::RBRACE,
+                Token::EOF,
+            ],
+            tokens
+        );
+    }
 }
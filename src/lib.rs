@@ -0,0 +1,26 @@
+//! a lexer, parser, tree-walking evaluator, bytecode compiler and virtual machine for the monkey language
+//!
+//! to embed the vm, compile source into bytecode and run it:
+//!
+//! ```
+//! use monkey::compiler::compile_from_source;
+//! use monkey::eval::Object;
+//! use monkey::vm::run_from_bytecode;
+//!
+//! let byte_code = compile_from_source("let add = fn(a, b) { a + b; }; add(1, 2);").expect("compile");
+//! assert_eq!(Ok(Object::Integer(3)), run_from_bytecode(byte_code));
+//! ```
+//!
+//! use `vm::VM` directly to send the output of `puts` somewhere other than stdout
+
+pub mod lexer;
+pub mod parser;
+pub mod eval;
+pub mod builtins;
+pub mod code;
+pub mod compiler;
+pub mod vm;
+pub mod repl;
+
+#[cfg(test)]
+mod differential;
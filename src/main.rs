@@ -1,51 +1,38 @@
-mod parser;
-use crate::parser::parse;
-
-mod lexer;
-use crate::lexer::lex;
-
-mod eval;
-use crate::eval::{eval_return_scope, Object, Env};
-
-mod code;
-mod compiler;
-mod vm;
-
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use monkey::eval::Object;
+use monkey::repl;
+use monkey::vm::{compile_file, run_file};
 
 fn main() {
-    let mut rl = Editor::<()>::new();
-    let mut env = Env::new();
-    loop {
-        let readline = rl.readline(">> ");
-        match readline {
-            Ok(line) => {
-                let mut tokens = lex(&line);
-                let ast = parse(&mut tokens);
-                display_object(eval_return_scope(ast, &mut env));
-            },
-            Err(ReadlineError::Interrupted) => {
-                break
-            },
-            Err(ReadlineError::Eof) => {
-                break
-            },
-            Err(err) => {
-                eprintln!("Error: {:?}", err);
-                break
+    // given a file, run it through the vm, or print what it compiles to, rather than starting the repl
+    let args: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+    match args.as_slice() {
+        [] => {},
+        [flag, path] if flag == "--dump-bytecode" => {
+            match compile_file(path.as_ref()) {
+                Ok(byte_code) => {
+                    print!("{}", byte_code.disassemble());
+                    return;
+                },
+                Err(err) => eprintln!("{}", err),
+            }
+            std::process::exit(1);
+        },
+        [path] => {
+            match run_file(path.as_ref()) {
+                Ok(Object::Error(message)) => eprintln!("{}: {}", path.to_string_lossy(), message),
+                Ok(_) => return,
+                Err(err) => eprintln!("{}", err),
             }
-        }
+            std::process::exit(1);
+        },
+        _ => {
+            eprintln!("usage: monkey [[--dump-bytecode] file]");
+            std::process::exit(2);
+        },
     }
-}
 
-fn display_object(obj: Object) {
-    match obj {
-        Object::Integer(num) => println!("{}", num),
-        Object::String(string) => println!("{}", string),
-        Object::Boolean(val) => println!("{}", val),
-        Object::Function{parameters: _, body: _} => println!("function"),
-        Object::Null => println!("null"),
-        Object::Return(obj) => display_object(*obj),
+    if let Err(err) = repl::start(std::io::stdin(), std::io::stdout()) {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(1);
     }
 }
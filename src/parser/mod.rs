@@ -1,23 +1,45 @@
 use crate::lexer::Token;
 
+use std::ops::{Deref, DerefMut};
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
     Let{ name: String, value: Expr},
+    Destructure{ pattern: Pattern, value: Expr }, // let [a, b] = value;
+    Assign{ name: String, value: Expr },
     Return{ value: Expr },
+    While{ condition: Expr, body: Vec<Statement> },
+    For{ variable: String, iterable: Expr, body: Vec<Statement> }, // for (variable in iterable) { body }
+    Break, // leaves the innermost while loop
+    Continue, // starts the next iteration of the innermost while loop, checking its condition first
     Expression(Expr),
+    Line(usize), // the following statements start on this source line, only parse_with_lines adds these
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
     Const(i32),
+    Float(f64),
     String(String),
     Boolean(bool),
     Ident(String),
     Prefix{prefix: Prefix, value: Box<Expr>},
+    Postfix{postfix: Postfix, value: Box<Expr>},
     Infix{left: Box<Expr>, operator: Operator, right: Box<Expr>},
     If{condition: Box<Expr>, consequence: Vec<Statement>, alternative: Vec<Statement>},
     Function{parameters: Vec<String>, body: Vec<Statement>},
     Call{function: Box<Expr>, arguments: Vec<Expr>},
+    Array(Vec<Expr>),
+    Hash(Vec<(Expr, Expr)>),
+    Index{left: Box<Expr>, index: Box<Expr>},
+    Slice{left: Box<Expr>, start: Option<Box<Expr>>, end: Option<Box<Expr>>}, // left[start:end], either bound can be left out
+}
+
+/// what a destructuring let binds, each element of an array pattern binds the element of the array at the same position
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    Ident(String),
+    Array(Vec<Pattern>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -26,136 +48,322 @@ pub enum Prefix {
     Minus,
 }
 
+/// i++ and i--, which evaluate to the value before it is incremented or decremented
+#[derive(Debug, PartialEq, Clone)]
+pub enum Postfix {
+    Increment,
+    Decrement,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Operator {
     Plus,
     Minus,
     Multiply,
     Divide,
+    IntDivide,
+    Modulo,
     GreaterThan,
     LessThan,
+    GreaterThanOrEqual,
+    LessThanOrEqual,
     Equals,
     NotEquals,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+/// why a program couldn't be parsed
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+    Expected { expected: Token, found: Token }, // the syntax only allows one token here
+    Unexpected { found: Token, parsing: &'static str }, // a token which can't appear in what was being parsed
+    InvalidEscape(char), // a string literal with an unknown escape sequence, the character after the backslash
 }
 
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::Expected { expected, found } => write!(f, "expected {:?}, got {:?}", expected, found),
+            ParseError::Unexpected { found, parsing } => write!(f, "unexpected {:?} in {}", found, parsing),
+            ParseError::InvalidEscape(c) => write!(f, "unknown escape sequence in string: \\{}", c),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 #[derive(PartialOrd, PartialEq)]
 enum Precedence {
     Lowest,
+    Ternary,     // X ? Y : Z
+    Or,          // ||
+    And,         // &&
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
     Equals ,     // ==
     LessGreater, // > or <
+    Shift,       // << or >>
     Sum,         // +
     Product,     // *
     Prefix,      // -X or !X
-    // Call,        // myFunction(X) - not used
+    Call,        // myFunction(X)
+    Index,       // array[index]
+    Postfix,     // X++ or X--
 }
 
-pub fn parse(input: &mut Vec<Token>) -> Vec<Statement> {
+/// the tokens left to parse, tokens are only ever removed from the front,
+/// so the number removed so far gives the position of the next one in lines
+struct Tokens {
+    tokens: Vec<Token>,
+    lines: Vec<usize>,
+}
+
+impl Tokens {
+    /// the source line of the next token, if lines were given
+    fn line(&self) -> Option<usize> {
+        self.lines.get(self.lines.len().checked_sub(self.tokens.len())?).copied()
+    }
+
+    /// removes the next token, which has to be the expected one
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        match self.tokens.remove(0) {
+            found if found == expected => Ok(()),
+            found => Err(ParseError::Expected { expected, found }),
+        }
+    }
+}
+
+impl Deref for Tokens {
+    type Target = Vec<Token>;
+
+    fn deref(&self) -> &Vec<Token> {
+        &self.tokens
+    }
+}
+
+impl DerefMut for Tokens {
+    fn deref_mut(&mut self) -> &mut Vec<Token> {
+        &mut self.tokens
+    }
+}
+
+pub fn parse(input: &mut Vec<Token>) -> Result<Vec<Statement>, ParseError> {
+    let mut tokens = Tokens { tokens: std::mem::take(input), lines: Vec::new() };
+
+    parse_statements(&mut tokens)
+}
+
+/// like parse, with a Statement::Line before each statement which starts on a new line
+pub fn parse_with_lines(tokens: Vec<Token>, lines: Vec<usize>) -> Result<Vec<Statement>, ParseError> {
+    parse_statements(&mut Tokens { tokens, lines })
+}
+
+fn parse_statements(input: &mut Tokens) -> Result<Vec<Statement>, ParseError> {
     let mut program = vec![];
+    let mut last_line = None;
 
     loop {
         let token = &input[0];
+        if *token == Token::EOF || *token == Token::RBRACE {
+            break;
+        }
+        if let Some(line) = input.line().filter(|&line| last_line != Some(line)) {
+            program.push(Statement::Line(line));
+            last_line = Some(line);
+        }
 
-        match token {
-            Token::EOF => break,
-            Token::LET => parse_let(input, &mut program),
-            Token::RETURN => parse_return(input, &mut program),
-            Token::RBRACE => {
-                break;
+        match &input[0] {
+            Token::LET => parse_let(input, &mut program)?,
+            Token::RETURN => parse_return(input, &mut program)?,
+            Token::BREAK => {
+                input.remove(0);
+                program.push(Statement::Break);
+            },
+            Token::CONTINUE => {
+                input.remove(0);
+                program.push(Statement::Continue);
+            },
+            Token::IDENT(_) if input[1] == Token::ASSIGN => parse_assign(input, &mut program)?,
+            Token::IDENT(_) if input[1].compound_assign_operator().is_some() => parse_compound_assign(input, &mut program)?,
+            Token::WHILE | Token::FOR => {
+                if input[0] == Token::WHILE {
+                    parse_while(input, &mut program)?;
+                } else {
+                    parse_for(input, &mut program)?;
+                }
+                // the closing brace already ends a loop, so the semicolon after it is optional
+                if input[0] == Token::SEMICOLON {
+                    input.remove(0);
+                }
+                continue;
             },
             _ => program.push(
                 Statement::Expression(
-                    parse_expression(input, Precedence::Lowest)
+                    parse_expression(input, Precedence::Lowest)?
                 )
             )
         }
-        assert_eq!(Token::SEMICOLON, input.remove(0));
+        // the semicolon is optional after the last statement of a program or block
+        if input[0] != Token::EOF && input[0] != Token::RBRACE {
+            input.expect(Token::SEMICOLON)?;
+        }
+    }
+
+    Ok(program)
+}
 
+fn parse_let(input: &mut Tokens, program: &mut Vec<Statement>) -> Result<(), ParseError> {
+    input.expect(Token::LET)?;
+    let pattern = parse_pattern(input)?;
+    input.expect(Token::ASSIGN)?;
+    let value = parse_expression(input, Precedence::Lowest)?;
+    match pattern {
+        Pattern::Ident(name) => program.push(Statement::Let {name, value}),
+        pattern => program.push(Statement::Destructure {pattern, value}),
     }
 
-    program
+    Ok(())
+}
+
+fn parse_pattern(input: &mut Tokens) -> Result<Pattern, ParseError> {
+    match input.remove(0) {
+        Token::IDENT(name) => Ok(Pattern::Ident(name)),
+        Token::LBRACKET => {
+            let mut elements = vec![];
+            if input[0] == Token::RBRACKET {
+                input.remove(0);
+                return Ok(Pattern::Array(elements));
+            }
+            loop {
+                elements.push(parse_pattern(input)?);
+                match input.remove(0) {
+                    Token::COMMA => continue,
+                    Token::RBRACKET => break,
+                    found => return Err(ParseError::Unexpected { found, parsing: "array pattern" }),
+                }
+            }
+
+            Ok(Pattern::Array(elements))
+        },
+        found => Err(ParseError::Unexpected { found, parsing: "let statement" }),
+    }
+}
+
+fn parse_assign(input: &mut Tokens, program: &mut Vec<Statement>) -> Result<(), ParseError> {
+    let name = match input.remove(0) {
+        Token::IDENT(name) => name,
+        found => return Err(ParseError::Unexpected { found, parsing: "assignment" }),
+    };
+    input.expect(Token::ASSIGN)?;
+    let value = parse_expression(input, Precedence::Lowest)?;
+    program.push(Statement::Assign {name, value});
+
+    Ok(())
 }
 
-fn parse_let(input: &mut Vec<Token>, program: &mut Vec<Statement>) {
-    assert_eq!(Token::LET, input.remove(0));
+/// x += y is parsed as x = x + y, so it compiles to a load, the operator, and a store back to the same slot
+fn parse_compound_assign(input: &mut Tokens, program: &mut Vec<Statement>) -> Result<(), ParseError> {
     let name = match input.remove(0) {
         Token::IDENT(name) => name,
-        _ => panic!("parse error at let statement"),
+        found => return Err(ParseError::Unexpected { found, parsing: "assignment" }),
+    };
+    let operator = input.remove(0).compound_assign_operator().expect("parse_statements checked for a compound assignment operator");
+    let value = Expr::Infix {
+        left: Box::new(Expr::Ident(name.clone())),
+        operator,
+        right: Box::new(parse_expression(input, Precedence::Lowest)?),
     };
-    assert_eq!(Token::ASSIGN, input.remove(0));
-    let value = parse_expression(input, Precedence::Lowest);
-    program.push(Statement::Let {name, value});
+    program.push(Statement::Assign {name, value});
+
+    Ok(())
 }
 
-fn parse_return(input: &mut Vec<Token>, program: &mut Vec<Statement>) {
-    assert_eq!(Token::RETURN, input.remove(0));
-    let value = parse_expression(input, Precedence::Lowest);
+fn parse_return(input: &mut Tokens, program: &mut Vec<Statement>) -> Result<(), ParseError> {
+    input.expect(Token::RETURN)?;
+    let value = parse_expression(input, Precedence::Lowest)?;
     program.push(Statement::Return {value});
+
+    Ok(())
+}
+
+fn parse_while(input: &mut Tokens, program: &mut Vec<Statement>) -> Result<(), ParseError> {
+    input.expect(Token::WHILE)?;
+    input.expect(Token::LPAREN)?;
+    let condition = parse_expression(input, Precedence::Lowest)?;
+    input.expect(Token::RPAREN)?;
+
+    input.expect(Token::LBRACE)?;
+    let body = parse_statements(input)?;
+    input.expect(Token::RBRACE)?;
+
+    program.push(Statement::While {condition, body});
+
+    Ok(())
 }
 
-fn parse_expression(input: &mut Vec<Token>, precedence: Precedence) -> Expr {
+fn parse_for(input: &mut Tokens, program: &mut Vec<Statement>) -> Result<(), ParseError> {
+    input.expect(Token::FOR)?;
+    input.expect(Token::LPAREN)?;
+    let variable = match input.remove(0) {
+        Token::IDENT(name) => name,
+        found => return Err(ParseError::Unexpected { found, parsing: "for loop variable" }),
+    };
+    input.expect(Token::IN)?;
+    let iterable = parse_expression(input, Precedence::Lowest)?;
+    input.expect(Token::RPAREN)?;
+
+    input.expect(Token::LBRACE)?;
+    let body = parse_statements(input)?;
+    input.expect(Token::RBRACE)?;
+
+    program.push(Statement::For {variable, iterable, body});
+
+    Ok(())
+}
+
+fn parse_expression(input: &mut Tokens, precedence: Precedence) -> Result<Expr, ParseError> {
     let mut left_expr = match input.remove(0) {
         Token::INT(value) => Expr::Const(value),
+        Token::FLOAT(value) => Expr::Float(value),
         Token::TRUE => Expr::Boolean(true),
         Token::FALSE => Expr::Boolean(false),
-        Token::IDENT(value) => {
-            if &input[0] == &Token::LPAREN {
-                input.remove(0);
-                let mut args = vec![];
-                // must be expressions separated by comma, or RPAREN
-                loop {
-                    match &input[0] {
-                        Token::RPAREN => {
-                            input.remove(0);
-                            break
-                        },
-                        _ => {
-                            args.push(parse_expression(input, Precedence::Lowest));
-                        },
-                    }
-
-                    match input.remove(0) {
-                        Token::RPAREN => break,
-                        Token::COMMA => continue,
-                        _ => panic!("unexpected parameter found while parsing function args"),
-                    }
-                }
-                Expr::Call {
-                    function: Box::new(Expr::Ident(value)),
-                    arguments: args
-                }
-            } else {
-                Expr::Ident(value)
-            }
-        },
+        Token::IDENT(value) => Expr::Ident(value),
         Token::BANG => Expr::Prefix{
             prefix: Prefix::Bang,
-            value: Box::new(parse_expression(input, Precedence::Prefix))
+            value: Box::new(parse_expression(input, Precedence::Prefix)?)
         },
         Token::MINUS => Expr::Prefix{
             prefix: Prefix::Minus,
-            value: Box::new(parse_expression(input, Precedence::Prefix))
+            value: Box::new(parse_expression(input, Precedence::Prefix)?)
         },
         Token::LPAREN => {
-            let expr = parse_expression(input, Precedence::Lowest);
-            assert_eq!(Token::RPAREN, input.remove(0));
+            let expr = parse_expression(input, Precedence::Lowest)?;
+            input.expect(Token::RPAREN)?;
 
             expr
         },
         Token::IF => {
-            assert_eq!(Token::LPAREN, input.remove(0));
-            let condition = parse_expression(input, Precedence::Lowest);
-            assert_eq!(Token::RPAREN, input.remove(0));
+            input.expect(Token::LPAREN)?;
+            let condition = parse_expression(input, Precedence::Lowest)?;
+            input.expect(Token::RPAREN)?;
 
-            assert_eq!(Token::LBRACE, input.remove(0));
-            let consequence = parse(input);
-            assert_eq!(Token::RBRACE, input.remove(0));
+            input.expect(Token::LBRACE)?;
+            let consequence = parse_statements(input)?;
+            input.expect(Token::RBRACE)?;
 
-            let alternative = if &input[0] == &Token::ELSE {
+            let alternative = if input[0] == Token::ELSE {
                 input.remove(0);
 
-                assert_eq!(Token::LBRACE, input.remove(0));
-                let alternative = parse(input);
-                assert_eq!(Token::RBRACE, input.remove(0));
+                input.expect(Token::LBRACE)?;
+                let alternative = parse_statements(input)?;
+                input.expect(Token::RBRACE)?;
 
                 alternative
             } else {
@@ -170,7 +378,7 @@ fn parse_expression(input: &mut Vec<Token>, precedence: Precedence) -> Expr {
         },
         Token::FUNCTION => {
             let mut parameters = vec![];
-            assert_eq!(Token::LPAREN, input.remove(0));
+            input.expect(Token::LPAREN)?;
             // must be idents seperated by comma, or RPAREN
             loop {
                 match input.remove(0) {
@@ -180,16 +388,16 @@ fn parse_expression(input: &mut Vec<Token>, precedence: Precedence) -> Expr {
                         match input.remove(0) {
                             Token::RPAREN => break,
                             Token::COMMA => continue,
-                            _ => panic!("unexpected parameter found while parsing function parameters"),
+                            found => return Err(ParseError::Unexpected { found, parsing: "function parameters" }),
                         }
                     },
-                    _ => panic!("unexpected parameter found while parsing function parameters"),
+                    found => return Err(ParseError::Unexpected { found, parsing: "function parameters" }),
                 }
             }
 
-            assert_eq!(Token::LBRACE, input.remove(0));
-            let body = parse(input);
-            assert_eq!(Token::RBRACE, input.remove(0));
+            input.expect(Token::LBRACE)?;
+            let body = parse_statements(input)?;
+            input.expect(Token::RBRACE)?;
 
             Expr::Function {
                 parameters,
@@ -197,49 +405,189 @@ fn parse_expression(input: &mut Vec<Token>, precedence: Precedence) -> Expr {
             }
         },
         Token::STRING(string) => Expr::String(string),
-        _ => panic!("parse error at expression"),
+        Token::LBRACKET => Expr::Array(parse_expression_list(input, Token::RBRACKET)?),
+        Token::LBRACE => {
+            let mut pairs = vec![];
+            // must be key: value pairs separated by comma, or RBRACE
+            loop {
+                if input[0] == Token::RBRACE {
+                    input.remove(0);
+                    break;
+                }
+
+                let key = parse_expression(input, Precedence::Lowest)?;
+                input.expect(Token::COLON)?;
+                let value = parse_expression(input, Precedence::Lowest)?;
+                pairs.push((key, value));
+
+                match input.remove(0) {
+                    Token::RBRACE => break,
+                    Token::COMMA => continue,
+                    found => return Err(ParseError::Unexpected { found, parsing: "hash literal" }),
+                }
+            }
+
+            Expr::Hash(pairs)
+        },
+        Token::INVALID_ESCAPE(c) => return Err(ParseError::InvalidEscape(c)),
+        found => return Err(ParseError::Unexpected { found, parsing: "expression" }),
     };
 
     let mut next_token = &input[0];
     while precedence < next_token.precedence() {
-        left_expr = parse_infix(left_expr, input);
+        left_expr = parse_infix(left_expr, input)?;
         next_token = &input[0];
     }
 
-    left_expr
+    Ok(left_expr)
+}
+
+/// parses expressions separated by commas, consuming the closing token
+/// used for function call arguments and array literals
+fn parse_expression_list(input: &mut Tokens, closing_token: Token) -> Result<Vec<Expr>, ParseError> {
+    let mut expressions = vec![];
+
+    if input[0] == closing_token {
+        input.remove(0);
+        return Ok(expressions);
+    }
+
+    loop {
+        expressions.push(parse_expression(input, Precedence::Lowest)?);
+
+        match input.remove(0) {
+            Token::COMMA => continue,
+            token if token == closing_token => break,
+            found => return Err(ParseError::Unexpected { found, parsing: "expression list" }),
+        }
+    }
+
+    Ok(expressions)
 }
 
-fn parse_infix(left: Expr, input: &mut Vec<Token>) -> Expr {
+fn parse_infix(left: Expr, input: &mut Tokens) -> Result<Expr, ParseError> {
     let next_token = input.remove(0);
+    if next_token == Token::LPAREN {
+        return Ok(Expr::Call {
+            function: Box::new(left),
+            arguments: parse_expression_list(input, Token::RPAREN)?,
+        });
+    }
+    if next_token == Token::QUESTION {
+        // the alternative is parsed at the lowest precedence so that a ? b : c ? d : e nests to the right
+        let consequence = parse_expression(input, Precedence::Lowest)?;
+        input.expect(Token::COLON)?;
+        let alternative = parse_expression(input, Precedence::Lowest)?;
+
+        return Ok(Expr::If {
+            condition: Box::new(left),
+            consequence: vec![Statement::Expression(consequence)],
+            alternative: vec![Statement::Expression(alternative)],
+        });
+    }
+    if next_token == Token::LBRACKET {
+        let bound = |input: &mut Tokens, closing_token: Token| -> Result<Option<Box<Expr>>, ParseError> {
+            if input[0] == closing_token {
+                Ok(None)
+            } else {
+                Ok(Some(Box::new(parse_expression(input, Precedence::Lowest)?)))
+            }
+        };
+        let start = bound(input, Token::COLON)?;
+        if input[0] == Token::COLON {
+            input.remove(0);
+            let end = bound(input, Token::RBRACKET)?;
+            input.expect(Token::RBRACKET)?;
+
+            return Ok(Expr::Slice { left: Box::new(left), start, end });
+        }
+        let index = start.expect("the start of a slice is only left out before a colon");
+        input.expect(Token::RBRACKET)?;
+
+        return Ok(Expr::Index {
+            left: Box::new(left),
+            index,
+        });
+    }
+
+    let postfix = match next_token {
+        Token::INCREMENT => Some(Postfix::Increment),
+        Token::DECREMENT => Some(Postfix::Decrement),
+        _ => None,
+    };
+    if let Some(postfix) = postfix {
+        return Ok(Expr::Postfix {
+            postfix,
+            value: Box::new(left),
+        });
+    }
+
     let operator = match &next_token {
         Token::PLUS => Operator::Plus,
         Token::MINUS => Operator::Minus,
         Token::SLASH => Operator::Divide,
+        Token::TILDE_SLASH => Operator::IntDivide,
         Token::ASTERISK => Operator::Multiply,
+        Token::PERCENT => Operator::Modulo,
         Token::LT => Operator::LessThan,
         Token::GT => Operator::GreaterThan,
+        Token::LT_EQ => Operator::LessThanOrEqual,
+        Token::GT_EQ => Operator::GreaterThanOrEqual,
         Token::EQ => Operator::Equals,
         Token::NOT_EQ => Operator::NotEquals,
+        Token::AND => Operator::And,
+        Token::OR => Operator::Or,
+        Token::AMPERSAND => Operator::BitAnd,
+        Token::PIPE => Operator::BitOr,
+        Token::CARET => Operator::BitXor,
+        Token::SHIFT_LEFT => Operator::ShiftLeft,
+        Token::SHIFT_RIGHT => Operator::ShiftRight,
         _ => panic!("parse infix called on invalid operator"),
     };
-    Expr::Infix {
+    Ok(Expr::Infix {
         left: Box::new(left),
         operator,
-        right: Box::new(parse_expression(input, next_token.precedence())),
-    }
+        right: Box::new(parse_expression(input, next_token.precedence())?),
+    })
 }
 
 impl Token {
+    fn compound_assign_operator(&self) -> Option<Operator> {
+        match self {
+            Token::PLUS_ASSIGN => Some(Operator::Plus),
+            Token::MINUS_ASSIGN => Some(Operator::Minus),
+            Token::ASTERISK_ASSIGN => Some(Operator::Multiply),
+            Token::SLASH_ASSIGN => Some(Operator::Divide),
+            _ => None,
+        }
+    }
+
     fn precedence(&self) -> Precedence {
         match self {
             Token::PLUS => Precedence::Sum,
             Token::MINUS => Precedence::Sum,
             Token::SLASH => Precedence::Product,
+            Token::TILDE_SLASH => Precedence::Product,
             Token::ASTERISK => Precedence::Product,
+            Token::PERCENT => Precedence::Product,
             Token::LT => Precedence::LessGreater,
             Token::GT => Precedence::LessGreater,
+            Token::LT_EQ => Precedence::LessGreater,
+            Token::GT_EQ => Precedence::LessGreater,
             Token::EQ => Precedence::Equals,
             Token::NOT_EQ => Precedence::Equals,
+            Token::AND => Precedence::And,
+            Token::OR => Precedence::Or,
+            Token::QUESTION => Precedence::Ternary,
+            Token::PIPE => Precedence::BitOr,
+            Token::CARET => Precedence::BitXor,
+            Token::AMPERSAND => Precedence::BitAnd,
+            Token::SHIFT_LEFT => Precedence::Shift,
+            Token::SHIFT_RIGHT => Precedence::Shift,
+            Token::LPAREN => Precedence::Call,
+            Token::LBRACKET => Precedence::Index,
+            Token::INCREMENT => Precedence::Postfix,
+            Token::DECREMENT => Precedence::Postfix,
             _ => Precedence::Lowest
         }
     }
@@ -248,13 +596,63 @@ impl Token {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::lexer::lex;
+    use crate::lexer::{lex, lex_with_lines};
+
+    #[test]
+    fn parse_optional_final_semicolon() {
+        assert_eq!(Ok(Vec::<Statement>::new()), parse(&mut lex("")));
+        assert_eq!(parse(&mut lex("1; 5;")), parse(&mut lex("1; 5")));
+        assert_eq!(parse(&mut lex("fn() { let a = 1; a; };")), parse(&mut lex("fn() { let a = 1; a }")));
+    }
+
+    #[test]
+    fn parse_missing_semicolon_between_statements() {
+        assert_eq!(Err(ParseError::Expected { expected: Token::SEMICOLON, found: Token::INT(2) }), parse(&mut lex("1 2")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |input: &str| parse(&mut lex(input)).expect_err("input should fail to parse").to_string();
+
+        assert_eq!("unexpected ASSIGN in let statement", error("let = 5;"));
+        assert_eq!("expected RPAREN, got EOF", error("(1 + 2"));
+        assert_eq!("expected RBRACE, got EOF", error("while (true) { 1;"));
+        assert_eq!("unexpected SEMICOLON in expression", error("1 + ;"));
+        assert_eq!("unexpected INT(1) in function parameters", error("fn(1) { };"));
+        assert_eq!("unexpected SEMICOLON in hash literal", error("{1: 2;"));
+        assert_eq!("unexpected RBRACKET in expression", error("a[];"));
+        assert_eq!("unexpected INT(1) in array pattern", error("let [a 1] = b;"));
+        assert_eq!(r"unknown escape sequence in string: \q", error(r#"puts("a\qb");"#));
+    }
+
+    #[test]
+    fn parse_lines() {
+        let input = "let x = 1; x;\nwhile (x) {\n  x;\n}\n\nx;";
+        let (tokens, lines) = lex_with_lines(input);
+        let ast = parse_with_lines(tokens, lines).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Line(1),
+                Statement::Let { name: String::from("x"), value: Expr::Const(1) },
+                Statement::Expression(Expr::Ident(String::from("x"))),
+                Statement::Line(2),
+                Statement::While {
+                    condition: Expr::Ident(String::from("x")),
+                    body: vec![Statement::Line(3), Statement::Expression(Expr::Ident(String::from("x")))],
+                },
+                Statement::Line(6),
+                Statement::Expression(Expr::Ident(String::from("x"))),
+            ],
+            ast
+        );
+    }
 
     #[test]
     fn parse_let() {
         let input = "let x = 5;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -268,7 +666,7 @@ mod tests {
     fn parse_return() {
         let input = "return 5;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -282,7 +680,7 @@ mod tests {
     fn parse_let_ident() {
         let input = "let myVar = anotherV;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -296,7 +694,7 @@ mod tests {
     fn parse_expression_statement() {
         let input = "foo;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -310,7 +708,7 @@ mod tests {
     fn parse_expression_statement_const() {
         let input = "5;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -324,7 +722,7 @@ mod tests {
     fn parse_expression_statement_string() {
         let input = r#" "foo bar";"#;
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -338,7 +736,7 @@ mod tests {
     fn parse_prefix_expression() {
         let input = "!5; -15;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -371,10 +769,21 @@ mod tests {
             ("5 - 6;", Operator::Minus),
             ("5 * 6;", Operator::Multiply),
             ("5 / 6;", Operator::Divide),
+            ("5 ~/ 6;", Operator::IntDivide),
+            ("5 % 6;", Operator::Modulo),
             ("5 > 6;", Operator::GreaterThan),
             ("5 < 6;", Operator::LessThan),
+            ("5 >= 6;", Operator::GreaterThanOrEqual),
+            ("5 <= 6;", Operator::LessThanOrEqual),
             ("5 == 6;", Operator::Equals),
             ("5 != 6;", Operator::NotEquals),
+            ("5 && 6;", Operator::And),
+            ("5 || 6;", Operator::Or),
+            ("5 & 6;", Operator::BitAnd),
+            ("5 | 6;", Operator::BitOr),
+            ("5 ^ 6;", Operator::BitXor),
+            ("5 << 6;", Operator::ShiftLeft),
+            ("5 >> 6;", Operator::ShiftRight),
         ];
 
         for (input, operator) in test_cases {
@@ -384,7 +793,7 @@ mod tests {
 
     fn parse_infix_expression(input: &str, operator: Operator) {
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -402,7 +811,7 @@ mod tests {
     fn parse_infix_expression_order_of_operations() {
         let input = "-a * 6;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -416,11 +825,281 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_logical_order_of_operations() {
+        // && binds tighter than ||, and comparisons bind tighter than both
+        let input = "a || b && c == d;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Expression(Expr::Infix{
+                    left: Box::new(Expr::Ident(String::from("a"))),
+                    operator: Operator::Or,
+                    right: Box::new(Expr::Infix{
+                        left: Box::new(Expr::Ident(String::from("b"))),
+                        operator: Operator::And,
+                        right: Box::new(Expr::Infix{
+                            left: Box::new(Expr::Ident(String::from("c"))),
+                            operator: Operator::Equals,
+                            right: Box::new(Expr::Ident(String::from("d"))),
+                        }),
+                    }),
+                }),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_bitwise_order_of_operations() {
+        // as in c, & binds tighter than ^ which binds tighter than |, all below comparisons,
+        // while shifts sit between comparisons and sums
+        let input = "a | b ^ c & d == e; f << 1 + 2 < g;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        let ident = |name: &str| Box::new(Expr::Ident(String::from(name)));
+        let infix = |left, operator, right| Box::new(Expr::Infix { left, operator, right });
+        assert_eq!(
+            vec![
+                Statement::Expression(*infix(
+                    ident("a"),
+                    Operator::BitOr,
+                    infix(
+                        ident("b"),
+                        Operator::BitXor,
+                        infix(ident("c"), Operator::BitAnd, infix(ident("d"), Operator::Equals, ident("e")))
+                    )
+                )),
+                Statement::Expression(*infix(
+                    infix(
+                        ident("f"),
+                        Operator::ShiftLeft,
+                        infix(Box::new(Expr::Const(1)), Operator::Plus, Box::new(Expr::Const(2)))
+                    ),
+                    Operator::LessThan,
+                    ident("g")
+                )),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_destructure() {
+        let input = "let [a, b] = [1, 2]; let [a, [b, c], []] = x;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        let ident = |name: &str| Pattern::Ident(String::from(name));
+        assert_eq!(
+            vec![
+                Statement::Destructure{
+                    pattern: Pattern::Array(vec![ident("a"), ident("b")]),
+                    value: Expr::Array(vec![Expr::Const(1), Expr::Const(2)]),
+                },
+                Statement::Destructure{
+                    pattern: Pattern::Array(vec![
+                        ident("a"),
+                        Pattern::Array(vec![ident("b"), ident("c")]),
+                        Pattern::Array(vec![]),
+                    ]),
+                    value: Expr::Ident(String::from("x")),
+                },
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_ternary() {
+        let input = "let max = a > b ? a : b; x ? 1 : y || z ? 2 : 3;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        let ident = |name: &str| Box::new(Expr::Ident(String::from(name)));
+        let ternary = |condition, consequence, alternative| Expr::If {
+            condition,
+            consequence: vec![Statement::Expression(consequence)],
+            alternative: vec![Statement::Expression(alternative)],
+        };
+        assert_eq!(
+            vec![
+                Statement::Let{
+                    name: String::from("max"),
+                    value: ternary(
+                        Box::new(Expr::Infix{ left: ident("a"), operator: Operator::GreaterThan, right: ident("b") }),
+                        *ident("a"),
+                        *ident("b")
+                    ),
+                },
+                Statement::Expression(ternary(
+                    ident("x"),
+                    Expr::Const(1),
+                    ternary(
+                        Box::new(Expr::Infix{ left: ident("y"), operator: Operator::Or, right: ident("z") }),
+                        Expr::Const(2),
+                        Expr::Const(3)
+                    )
+                )),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_compound_assign() {
+        let input = "x += 1 * 2; y /= 3;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Assign{
+                    name: String::from("x"),
+                    value: Expr::Infix{
+                        left: Box::new(Expr::Ident(String::from("x"))),
+                        operator: Operator::Plus,
+                        right: Box::new(Expr::Infix{
+                            left: Box::new(Expr::Const(1)),
+                            operator: Operator::Multiply,
+                            right: Box::new(Expr::Const(2)),
+                        }),
+                    },
+                },
+                Statement::Assign{
+                    name: String::from("y"),
+                    value: Expr::Infix{
+                        left: Box::new(Expr::Ident(String::from("y"))),
+                        operator: Operator::Divide,
+                        right: Box::new(Expr::Const(3)),
+                    },
+                },
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_postfix() {
+        let input = "i++; -j-- + 1;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Expression(Expr::Postfix{
+                    postfix: Postfix::Increment,
+                    value: Box::new(Expr::Ident(String::from("i"))),
+                }),
+                Statement::Expression(Expr::Infix{
+                    left: Box::new(Expr::Prefix{
+                        prefix: Prefix::Minus,
+                        value: Box::new(Expr::Postfix{
+                            postfix: Postfix::Decrement,
+                            value: Box::new(Expr::Ident(String::from("j"))),
+                        }),
+                    }),
+                    operator: Operator::Plus,
+                    right: Box::new(Expr::Const(1)),
+                }),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_assign() {
+        let input = "x = x + 1; x == 1;";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Assign {
+                    name: String::from("x"),
+                    value: Expr::Infix{
+                        left: Box::new(Expr::Ident(String::from("x"))),
+                        operator: Operator::Plus,
+                        right: Box::new(Expr::Const(1)),
+                    },
+                },
+                Statement::Expression(Expr::Infix{
+                    left: Box::new(Expr::Ident(String::from("x"))),
+                    operator: Operator::Equals,
+                    right: Box::new(Expr::Const(1)),
+                }),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_break_and_continue() {
+        let ast = parse(&mut lex("while (true) { break; continue; }")).expect("parse");
+
+        assert_eq!(
+            vec![Statement::While { condition: Expr::Boolean(true), body: vec![Statement::Break, Statement::Continue] }],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_for() {
+        let ast = parse(&mut lex("for (x in [1, 2]) { x; }; for (y in range(0, 3)) { }")).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::For {
+                    variable: String::from("x"),
+                    iterable: Expr::Array(vec![Expr::Const(1), Expr::Const(2)]),
+                    body: vec![Statement::Expression(Expr::Ident(String::from("x")))],
+                },
+                Statement::For {
+                    variable: String::from("y"),
+                    iterable: Expr::Call {
+                        function: Box::new(Expr::Ident(String::from("range"))),
+                        arguments: vec![Expr::Const(0), Expr::Const(3)],
+                    },
+                    body: vec![],
+                },
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_while() {
+        let input = "while (x < 5) { x; } while (true) { };";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::While {
+                    condition: Expr::Infix{
+                        left: Box::new(Expr::Ident(String::from("x"))),
+                        operator: Operator::LessThan,
+                        right: Box::new(Expr::Const(5)),
+                    },
+                    body: vec![Statement::Expression(Expr::Ident(String::from("x")))],
+                },
+                Statement::While {
+                    condition: Expr::Boolean(true),
+                    body: vec![],
+                },
+            ],
+            ast
+        );
+    }
+
     #[test]
     fn parse_bool() {
         let input = "!true == false;";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -438,7 +1117,7 @@ mod tests {
     fn parse_paren() {
         let input = "1 + (2 + 3);";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -460,7 +1139,7 @@ mod tests {
     fn parse_if() {
         let input = "if (5) { 6; };";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -478,7 +1157,7 @@ mod tests {
     fn parse_if_else() {
         let input = "if (5) { 6; } else { 7; };";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -496,7 +1175,7 @@ mod tests {
     fn parse_function_literal() {
         let input = "let myFunc = fn(x, y) {x + y;};";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -524,7 +1203,7 @@ mod tests {
     fn parse_function_call() {
         let input = "add(1, 2);";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -543,7 +1222,7 @@ mod tests {
     fn parse_function_expression() {
         let input = "myFunc(x + y, a + b);";
         let mut tokens = lex(input);
-        let ast = parse(&mut tokens);
+        let ast = parse(&mut tokens).expect("parse");
 
         assert_eq!(
             vec![
@@ -569,4 +1248,128 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_array() {
+        let input = "[]; [1, 2 * 2, [3]];";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Expression(Expr::Array(vec![])),
+                Statement::Expression(
+                    Expr::Array(vec![
+                        Expr::Const(1),
+                        Expr::Infix {
+                            left: Box::new(Expr::Const(2)),
+                            operator: Operator::Multiply,
+                            right: Box::new(Expr::Const(2))
+                        },
+                        Expr::Array(vec![Expr::Const(3)]),
+                    ])
+                ),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_hash() {
+        let input = r#"{}; {"one": 1, 2: 1 + 1};"#;
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Expression(Expr::Hash(vec![])),
+                Statement::Expression(
+                    Expr::Hash(vec![
+                        (Expr::String(String::from("one")), Expr::Const(1)),
+                        (
+                            Expr::Const(2),
+                            Expr::Infix {
+                                left: Box::new(Expr::Const(1)),
+                                operator: Operator::Plus,
+                                right: Box::new(Expr::Const(1))
+                            }
+                        ),
+                    ])
+                ),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_slice() {
+        let slice = |start: Option<i32>, end: Option<i32>| Statement::Expression(Expr::Slice {
+            left: Box::new(Expr::Ident(String::from("a"))),
+            start: start.map(|start| Box::new(Expr::Const(start))),
+            end: end.map(|end| Box::new(Expr::Const(end))),
+        });
+
+        assert_eq!(Ok(vec![slice(Some(1), Some(3))]), parse(&mut lex("a[1:3];")));
+        assert_eq!(Ok(vec![slice(None, Some(2))]), parse(&mut lex("a[:2];")));
+        assert_eq!(Ok(vec![slice(Some(1), None)]), parse(&mut lex("a[1:];")));
+        assert_eq!(Ok(vec![slice(None, None)]), parse(&mut lex("a[:];")));
+    }
+
+    #[test]
+    fn parse_index() {
+        let input = "myArray[1 + 1] * [1][0];";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Expression(Expr::Infix {
+                    left: Box::new(Expr::Index {
+                        left: Box::new(Expr::Ident(String::from("myArray"))),
+                        index: Box::new(Expr::Infix {
+                            left: Box::new(Expr::Const(1)),
+                            operator: Operator::Plus,
+                            right: Box::new(Expr::Const(1))
+                        }),
+                    }),
+                    operator: Operator::Multiply,
+                    right: Box::new(Expr::Index {
+                        left: Box::new(Expr::Array(vec![Expr::Const(1)])),
+                        index: Box::new(Expr::Const(0)),
+                    }),
+                }),
+            ],
+            ast
+        );
+    }
+
+    #[test]
+    fn parse_call_expression() {
+        let input = "fn() { 1; }(); f(1)(2);";
+        let mut tokens = lex(input);
+        let ast = parse(&mut tokens).expect("parse");
+
+        assert_eq!(
+            vec![
+                Statement::Expression(
+                    Expr::Call {
+                        function: Box::new(Expr::Function {
+                            parameters: vec![],
+                            body: vec![Statement::Expression(Expr::Const(1))]
+                        }),
+                        arguments: vec![]
+                    }
+                ),
+                Statement::Expression(
+                    Expr::Call {
+                        function: Box::new(Expr::Call {
+                            function: Box::new(Expr::Ident(String::from("f"))),
+                            arguments: vec![Expr::Const(1)]
+                        }),
+                        arguments: vec![Expr::Const(2)]
+                    }
+                ),
+            ],
+            ast
+        );
+    }
 }
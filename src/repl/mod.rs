@@ -0,0 +1,83 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use crate::compiler::{compile_with_state, CompilerState};
+use crate::vm::{VM, VMError};
+
+const PROMPT : &str = ">> ";
+
+/// reads one line at a time, compiling and running each line through the vm and writing its result
+/// globals, and the constants they refer to, carry over from one line to the next
+pub fn start(input: impl Read, mut output: impl Write) -> std::io::Result<()> {
+    let mut state = CompilerState::new();
+    let mut globals = Vec::new();
+    let mut lines = BufReader::new(input).lines();
+
+    loop {
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+
+        let byte_code = match compile_with_state(&line, &mut state) {
+            Ok(byte_code) => byte_code,
+            Err(err) => {
+                writeln!(output, "compile error: {}", err)?;
+                continue;
+            },
+        };
+
+        let mut vm = VM::new_with_globals(byte_code, std::mem::take(&mut globals), Box::new(&mut output));
+        let result = vm.run().map(|_| vm.result());
+        globals = vm.into_globals();
+
+        match result {
+            Ok(obj) => writeln!(output, "{}", obj)?,
+            // each input is a single line, so there's no point saying which line it failed on
+            Err(VMError::AtLine { error, .. }) => writeln!(output, "runtime error: {}", error)?,
+            Err(err) => writeln!(output, "runtime error: {}", err)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repl_keeps_globals_between_lines() {
+        let input = "let x = 5;\nx;\nlet add = fn(a) { a + x; };\nadd(2);\n";
+
+        assert_eq!(">> null\n>> 5\n>> null\n>> 7\n>> ", run(input));
+    }
+
+    #[test]
+    fn repl_continues_after_errors() {
+        let input = "let x = 2;\ny;\n1 / 0;\nputs(x);\nx * 3;\n";
+
+        assert_eq!(
+            ">> null\n>> compile error: undefined variable: y\n>> runtime error: division by zero\n>> 2\nnull\n>> 6\n>> ",
+            run(input)
+        );
+    }
+
+    #[test]
+    fn repl_continues_after_parse_errors() {
+        let input = "let x = 1;\nlet = 5;\nx + ;\nx;\n";
+
+        assert_eq!(
+            ">> null\n>> compile error: unexpected ASSIGN in let statement\n>> compile error: unexpected SEMICOLON in expression\n>> 1\n>> ",
+            run(input)
+        );
+    }
+
+    fn run(input: &str) -> String {
+        let mut output = Vec::new();
+        start(input.as_bytes(), &mut output).expect("repl should not fail to write to a vec");
+
+        String::from_utf8(output).expect("output should be utf8")
+    }
+}
@@ -0,0 +1,33 @@
+use std::rc::Rc;
+use crate::eval::{CompiledFunction, Object};
+
+/// the execution state of a single function call
+pub struct Frame {
+    pub function: Rc<CompiledFunction>, // shared with the closure, so a call never copies the instructions
+    pub closure: Option<Rc<Object>>, // the closure being run, which holds its free variables, none for the main program
+    pub ip: usize, // instruction pointer
+    pub base_pointer: usize, // the stack pointer at the time the function was called
+}
+
+impl Frame {
+    pub fn new(function: Rc<CompiledFunction>, closure: Option<Rc<Object>>, base_pointer: usize) -> Self {
+        Frame {
+            function,
+            closure,
+            ip: 0,
+            base_pointer,
+        }
+    }
+
+    pub fn instructions(&self) -> &[u8] {
+        &self.function.instructions
+    }
+
+    /// the captured variable at the index, if the closure has one there
+    pub fn free(&self, index: usize) -> Option<&Rc<Object>> {
+        match self.closure.as_deref() {
+            Some(Object::Closure { free, .. }) => free.get(index),
+            _ => None,
+        }
+    }
+}
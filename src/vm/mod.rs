@@ -1,190 +1,947 @@
-use crate::eval::Object;
-use crate::compiler::ByteCode;
-use crate::code::convert_two_u8s_be_to_usize;
+use std::convert::TryFrom;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::eval::{Object, CompiledFunction, HashKey, float_operands, repeat_string, shift, slice, string_index, truncated_quotient};
+use crate::compiler::{compile_from_source, ByteCode, CompileError};
+use crate::parser::ParseError;
+use crate::code::{convert_two_u8s_be_to_usize, disassemble_instruction, Opcode, UnknownOpcode};
+use crate::builtins;
 
+mod frame;
+use self::frame::Frame;
+
+// unless it's changed with with_stack_size
 const STACK_SIZE : usize = 2048;
 
-// the compiler can output any index up to the max u16 value
-//  but keeping an array of that size on the stack of our Rust VM causes trouble
-const GLOBAL_SIZE : usize = 2048;
+// the booleans the vm pushes, each vm shares a single instance of each so that every comparison doesn't build its own
+const TRUE : Object = Object::Boolean(true);
+const FALSE : Object = Object::Boolean(false);
+
+#[derive(Debug, PartialEq)]
+pub enum VMError {
+    UnknownOpcode(u8),
+    StackOverflow,
+    StackUnderflow,
+    FrameOverflow,
+    TypeMismatch(String),
+    DivisionByZero,
+    IntegerOverflow, // integer arithmetic is checked rather than wrapping
+    NotCallable(&'static str),
+    WrongArgumentCount { expected: usize, given: usize },
+    InvalidConstant(usize),
+    InvalidConstantIndex(usize),
+    InvalidJumpTarget(usize),
+    InvalidLocal(usize),
+    InvalidFree(usize),
+    InvalidBuiltin(usize),
+    InstructionLimitExceeded(usize),
+    MissingOperand(usize), // the instructions end part way through an instruction, at this offset
+    AtLine { line: usize, error: Box<VMError> }, // the source line the error happened on, when it's known
+}
+
+impl std::fmt::Display for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VMError::UnknownOpcode(op) => write!(f, "unknown opcode: {:#04x}", op),
+            VMError::StackOverflow => write!(f, "stack overflow"),
+            VMError::StackUnderflow => write!(f, "stack underflow"),
+            VMError::FrameOverflow => write!(f, "frame stack overflow"),
+            VMError::TypeMismatch(message) => write!(f, "type mismatch: {}", message),
+            VMError::DivisionByZero => write!(f, "division by zero"),
+            VMError::IntegerOverflow => write!(f, "integer overflow"),
+            VMError::NotCallable(type_name) => write!(f, "calling non-function: {}", type_name),
+            VMError::WrongArgumentCount { expected, given } => {
+                write!(f, "wrong number of arguments: want={}, got={}", expected, given)
+            },
+            VMError::InvalidConstant(index) => write!(f, "constant {} is not a compiled function", index),
+            VMError::InvalidConstantIndex(index) => write!(f, "constant index {} is out of bounds", index),
+            VMError::InvalidJumpTarget(address) => write!(f, "jump target {:04} is outside the instructions", address),
+            VMError::InvalidLocal(index) => write!(f, "local {} is outside the current frame", index),
+            VMError::InvalidFree(index) => write!(f, "free variable {} is out of bounds", index),
+            VMError::InvalidBuiltin(index) => write!(f, "builtin index {} is out of bounds", index),
+            VMError::InstructionLimitExceeded(limit) => write!(f, "instruction limit of {} exceeded", limit),
+            VMError::MissingOperand(offset) => write!(f, "missing operand at {:04}", offset),
+            VMError::AtLine { line, error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl std::error::Error for VMError {}
+
+impl From<UnknownOpcode> for VMError {
+    fn from(UnknownOpcode(byte): UnknownOpcode) -> Self {
+        VMError::UnknownOpcode(byte)
+    }
+}
+
+/// why compile_file or run_file failed, each one is displayed with the path of the file
+#[derive(Debug)]
+pub enum RunFileError {
+    Read { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: ParseError },
+    Compile { path: PathBuf, error: CompileError },
+    Run { path: PathBuf, error: VMError },
+}
+
+impl std::fmt::Display for RunFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RunFileError::Read { path, error } => write!(f, "{}: {}", path.display(), error),
+            RunFileError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+            RunFileError::Compile { path, error } => write!(f, "{}: {}", path.display(), error),
+            RunFileError::Run { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for RunFileError {}
 
-struct VM {
-    instructions: Vec<u8>,
-    constants: Vec<Object>,
-    stack: [Object; STACK_SIZE],
-    globals: [Object; GLOBAL_SIZE],
+/// objects on the stack, in the constant pool and in globals are reference counted,
+/// so pushing a constant or reading a variable doesn't copy the object
+pub struct VM<'a> {
+    frames: Vec<Frame>,
+    constants: Vec<Rc<Object>>,
+    stack: Vec<Rc<Object>>,
+    globals: Vec<Rc<Object>>,
     sp: usize, // stores the next FREE space on the stack
+    last_popped: Rc<Object>, // the value discarded by the most recent OpPop, kept around for testing
+    output: Box<dyn Write + 'a>, // where builtins such as puts write to
+    trace: bool,
+    negative_indexes: bool, // whether a negative array index counts back from the end
+    max_instructions: Option<usize>,
+    executed: usize, // instructions run so far, counted against max_instructions
+    // an error hit while a builtin had the vm call a function, raised once the builtin returns
+    callback_error: Option<VMError>,
+    null: Rc<Object>,
+    true_object: Rc<Object>,
+    false_object: Rc<Object>,
 }
 
-impl VM {
-    fn new(byte_code: ByteCode) -> Self {
-        VM {
+impl VM<'static> {
+    pub fn new(byte_code: ByteCode) -> Self {
+        VM::new_with_output(byte_code, Box::new(std::io::stdout()))
+    }
+}
+
+impl<'a> VM<'a> {
+    /// builtins such as puts write to the given output rather than stdout
+    pub fn new_with_output(byte_code: ByteCode, output: Box<dyn Write + 'a>) -> Self {
+        VM::new_with_globals(byte_code, Vec::new(), output)
+    }
+
+    /// starts with the globals left behind by an earlier vm, see into_globals
+    pub fn new_with_globals(byte_code: ByteCode, globals: Vec<Rc<Object>>, output: Box<dyn Write + 'a>) -> Self {
+        let null = Rc::new(Object::Null);
+        let main = CompiledFunction {
             instructions: byte_code.instructions,
-            constants: byte_code.constants,
-            // we rely on the stack pointer to ensure we don't read zeroed memory
-            // this should have the same result as [Object::Null, STACK_SIZE] which is not allow because Object is not copy
-            stack: unsafe { std::mem::zeroed() },
-            // we rely on compiler generating valid code to ensure we don't read zeroed memory
-            globals: unsafe { std::mem::zeroed() },
-            sp: 0
+            num_locals: 0,
+            num_parameters: 0,
+            lines: byte_code.lines,
+        };
+
+        VM {
+            // the main program runs in the bottom frame
+            frames: vec![Frame::new(Rc::new(main), None, 0)],
+            constants: byte_code.constants.into_iter().map(Rc::new).collect(),
+            stack: vec![null.clone(); STACK_SIZE],
+            globals,
+            sp: 0,
+            last_popped: null.clone(),
+            output,
+            trace: false,
+            negative_indexes: false,
+            max_instructions: None,
+            executed: 0,
+            callback_error: None,
+            null,
+            true_object: Rc::new(TRUE),
+            false_object: Rc::new(FALSE),
         }
     }
 
-    fn run(&mut self) {
-        let mut ip = 0; // instruction pointer
+    /// consumes the vm, returning its globals so they can be handed to the next one
+    pub fn into_globals(self) -> Vec<Rc<Object>> {
+        self.globals
+    }
+
+    /// replaces the stack with one which holds size objects, programs which need more fail with StackOverflow
+    pub fn with_stack_size(mut self, size: usize) -> Self {
+        self.stack = vec![self.null.clone(); size];
+        self
+    }
+
+    /// when enabled, each instruction is written to the output along with the stack before it runs
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// when enabled, arr[-1] is the last element of an array rather than null, indexes before the
+    /// first element are still null. strings and hashes are indexed the same either way
+    pub fn set_negative_indexes(&mut self, negative_indexes: bool) {
+        self.negative_indexes = negative_indexes;
+    }
+
+    /// stops a run with InstructionLimitExceeded once it has executed this many instructions, None for no limit
+    pub fn set_instruction_limit(&mut self, max_instructions: Option<usize>) {
+        self.max_instructions = max_instructions;
+    }
+
+    /// errors are wrapped in AtLine when the bytecode says which line they came from
+    pub fn run(&mut self) -> Result<(), VMError> {
+        self.executed = 0;
+        self.execute(1).map_err(|error| match self.current_line() {
+            Some(line) => VMError::AtLine { line, error: Box::new(error) },
+            None => error,
+        })
+    }
+
+    /// the source line of the instruction the innermost frame ran last
+    fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+
+        frame.function.lines.line(frame.ip.saturating_sub(1))
+    }
 
-        while ip < self.instructions.len() {
-            let instruction_address = ip;
-            ip += 1;
+    /// runs until the frame at the given depth returns, or the main program finishes
+    /// if it stops early, with more frames than that left, the program was halted by an error object
+    fn execute(&mut self, depth: usize) -> Result<(), VMError> {
+        let max_instructions = self.max_instructions;
+        while self.frames.len() >= depth {
+            let frame = self.current_frame_mut();
+            if frame.ip >= frame.instructions().len() {
+                // the compiler ends every function body with a return, so only the main program runs out of instructions
+                break;
+            }
+
+            if let Some(max_instructions) = max_instructions {
+                if self.executed == max_instructions {
+                    return Err(VMError::InstructionLimitExceeded(max_instructions));
+                }
+                self.executed += 1;
+            }
+
+            let frame = self.current_frame_mut();
+            let ip = frame.ip;
+            let instruction = frame.instructions()[ip];
+            frame.ip += 1;
 
-            match self.instructions[instruction_address] {
-                0x01 => {
-                    // OpConstant
-                    let const_index = convert_two_u8s_be_to_usize(self.instructions[ip], self.instructions[ip + 1]);
-                    ip += 2;
-                    self.push(self.constants[const_index].clone());
+            if self.trace {
+                self.trace_instruction(ip);
+            }
+
+            match Opcode::try_from(instruction)? {
+                Opcode::Constant => {
+                    let const_index = self.read_two_byte_operand()?;
+                    // shared with the constant pool rather than copied, which for a scalar is no more
+                    //   expensive and for a string avoids copying its contents every time it's loaded
+                    let constant = self.constant(const_index)?.clone();
+                    self.push(constant)?;
+                },
+                Opcode::Pop => {
+                    self.last_popped = self.pop()?;
+                },
+                Opcode::PopN => {
+                    let count = self.read_one_byte_operand()?;
+                    if self.sp < count {
+                        return Err(VMError::StackUnderflow);
+                    }
+                    // the slots are cleared so the discarded objects can be freed
+                    for slot in &mut self.stack[self.sp - count..self.sp] {
+                        *slot = self.null.clone();
+                    }
+                    self.sp -= count;
+                },
+                Opcode::Add => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => {
+                            let result = left.checked_add(*right).ok_or(VMError::IntegerOverflow)?;
+                            self.push(Object::Integer(result))?
+                        },
+                        (Object::String(right), Object::String(left)) => self.push(Object::String(left.clone() + right))?,
+                        (right, left) => match float_operands(left, right) {
+                            Some((left, right)) => self.push(Object::Float(left + right))?,
+                            None => return self.halt_with_error(binary_type_mismatch("OpAdd", left, right)),
+                        },
+                    }
+                },
+                Opcode::Sub => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => {
+                            let result = left.checked_sub(*right).ok_or(VMError::IntegerOverflow)?;
+                            self.push(Object::Integer(result))?
+                        },
+                        (right, left) => match float_operands(left, right) {
+                            Some((left, right)) => self.push(Object::Float(left - right))?,
+                            None => return self.halt_with_error(binary_type_mismatch("OpSub", left, right)),
+                        },
+                    }
+                },
+                Opcode::Mul => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => {
+                            let result = left.checked_mul(*right).ok_or(VMError::IntegerOverflow)?;
+                            self.push(Object::Integer(result))?
+                        },
+                        (Object::Integer(count), Object::String(string)) | (Object::String(string), Object::Integer(count)) => {
+                            match repeat_string(string, *count) {
+                                Object::Error(message) => return self.halt_with_error(Object::Error(message)),
+                                repeated => self.push(repeated)?,
+                            }
+                        },
+                        (right, left) => match float_operands(left, right) {
+                            Some((left, right)) => self.push(Object::Float(left * right))?,
+                            None => return self.halt_with_error(binary_type_mismatch("OpMul", left, right)),
+                        },
+                    }
+                },
+                Opcode::Div => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    match (&*right, &*left) {
+                        (Object::Integer(0), Object::Integer(_)) => return Err(VMError::DivisionByZero),
+                        (Object::Integer(right), Object::Integer(left)) => {
+                            let result = left.checked_div(*right).ok_or(VMError::IntegerOverflow)?;
+                            self.push(Object::Integer(result))?
+                        },
+                        (right, left) => match float_operands(left, right) {
+                            Some((left, right)) => self.push(Object::Float(left / right))?,
+                            None => return self.halt_with_error(binary_type_mismatch("OpDiv", left, right)),
+                        },
+                    }
                 },
-                0x02 => {
-                    // OpPop
-                    self.pop();
+                Opcode::IntDiv => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    match (&*right, &*left) {
+                        (Object::Integer(0), Object::Integer(_)) => return Err(VMError::DivisionByZero),
+                        (Object::Integer(right), Object::Integer(left)) => {
+                            let result = left.checked_div(*right).ok_or(VMError::IntegerOverflow)?;
+                            self.push(Object::Integer(result))?
+                        },
+                        (right, left) => match float_operands(left, right) {
+                            Some((left, right)) => match truncated_quotient(left, right) {
+                                Object::Error(message) => return self.halt_with_error(Object::Error(message)),
+                                quotient => self.push(quotient)?,
+                            },
+                            None => return self.halt_with_error(binary_type_mismatch("OpIntDiv", left, right)),
+                        },
+                    }
                 },
-                0x03 => {
-                    // OpAdd
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Integer(left + right)),
-                        _ => panic!("unhandled argument types to OpAdd"),
+                Opcode::Mod => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    match (&*right, &*left) {
+                        (Object::Integer(0), Object::Integer(_)) => return Err(VMError::DivisionByZero),
+                        (Object::Integer(right), Object::Integer(left)) => {
+                            let result = left.checked_rem(*right).ok_or(VMError::IntegerOverflow)?;
+                            self.push(Object::Integer(result))?
+                        },
+                        (right, left) => return self.halt_with_error(binary_type_mismatch("OpMod", left, right)),
                     }
                 },
-                0x04 => {
-                    // OpSub
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Integer(left - right)),
-                        _ => panic!("unhandled argument types to OpSub"),
+                opcode @ (Opcode::BitAnd | Opcode::BitOr | Opcode::BitXor | Opcode::Shl | Opcode::Shr) => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    let (left, right) = match (&*left, &*right) {
+                        (Object::Integer(left), Object::Integer(right)) => (*left, *right),
+                        (left, right) => return self.halt_with_error(binary_type_mismatch(&format!("Op{:?}", opcode), left, right)),
+                    };
+                    let result = match opcode {
+                        Opcode::BitAnd => Object::Integer(left & right),
+                        Opcode::BitOr => Object::Integer(left | right),
+                        Opcode::BitXor => Object::Integer(left ^ right),
+                        Opcode::Shl => shift(left, right, |left, count| left << count),
+                        _ => shift(left, right, |left, count| left >> count),
+                    };
+                    if let Object::Error(_) = result {
+                        return self.halt_with_error(result);
                     }
+                    self.push(result)?;
                 },
-                0x05 => {
-                    // OpMul
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Integer(left * right)),
-                        _ => panic!("unhandled argument types to OpMul"),
+                Opcode::Dup => {
+                    if self.sp == 0 {
+                        return Err(VMError::StackUnderflow);
                     }
+                    self.push(self.stack[self.sp - 1].clone())?;
                 },
-                0x06 => {
-                    // OpDiv
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Integer(left / right)),
-                        _ => panic!("unhandled argument types to OpDiv"),
+                Opcode::Swap => {
+                    if self.sp < 2 {
+                        return Err(VMError::StackUnderflow);
                     }
+                    self.stack.swap(self.sp - 1, self.sp - 2);
+                },
+                Opcode::True => {
+                    self.push(self.true_object.clone())?;
                 },
-                0x07 => {
-                    // OpTrue
-                    self.push(Object::Boolean(true));
+                Opcode::False => {
+                    self.push(self.false_object.clone())?;
+                },
+                Opcode::Equals => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    self.push_bool(left == right)?;
+                },
+                Opcode::NotEquals => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    self.push_bool(left != right)?;
+                },
+                Opcode::GreaterThan => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    let result = match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => left > right,
+                        (Object::String(right), Object::String(left)) => left > right,
+                        (right, left) => return self.halt_with_error(binary_type_mismatch("OpGreaterThan", left, right)),
+                    };
+                    self.push_bool(result)?;
+                },
+                Opcode::LessThan => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    let result = match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => left < right,
+                        (Object::String(right), Object::String(left)) => left < right,
+                        (right, left) => return self.halt_with_error(binary_type_mismatch("OpLessThan", left, right)),
+                    };
+                    self.push_bool(result)?;
+                },
+                Opcode::GreaterThanOrEqual => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    let result = match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => left >= right,
+                        (Object::String(right), Object::String(left)) => left >= right,
+                        (right, left) => return self.halt_with_error(binary_type_mismatch("OpGreaterThanOrEqual", left, right)),
+                    };
+                    self.push_bool(result)?;
+                },
+                Opcode::LessThanOrEqual => {
+                    let (right, left) = (self.pop()?, self.pop()?);
+                    let result = match (&*right, &*left) {
+                        (Object::Integer(right), Object::Integer(left)) => left <= right,
+                        (Object::String(right), Object::String(left)) => left <= right,
+                        (right, left) => return self.halt_with_error(binary_type_mismatch("OpLessThanOrEqual", left, right)),
+                    };
+                    self.push_bool(result)?;
+                },
+                Opcode::Minus => {
+                    match &*self.pop()? {
+                        Object::Integer(num) => self.push(Object::Integer(num.checked_neg().ok_or(VMError::IntegerOverflow)?))?,
+                        Object::Float(num) => self.push(Object::Float(-num))?,
+                        obj => return self.halt_with_error(unary_type_mismatch("OpMinus", obj)),
+                    }
                 },
-                0x08 => {
-                    // OpFalse
-                    self.push(Object::Boolean(false));
+                Opcode::Bang => {
+                    match &*self.pop()? {
+                        Object::Boolean(bool) => self.push_bool(!bool)?,
+                        obj => return self.halt_with_error(unary_type_mismatch("OpBang", obj)),
+                    }
                 },
-                0x09 => {
-                    // OpEquals
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Boolean(left == right)),
-                        (Object::Boolean(right), Object::Boolean(left)) => self.push(Object::Boolean(left == right)),
-                        _ => panic!("unhandled argument types to OpEquals"),
+                Opcode::JumpNotTrue => {
+                    let jump_address = self.read_two_byte_operand()?;
+                    match &*self.pop()? {
+                        Object::Boolean(true) => {}, // don't jump, the jump address has already been skipped
+                        Object::Boolean(false) => self.jump(jump_address)?,
+                        // conditions are strict, there's no truthiness for other types
+                        obj => return Err(VMError::TypeMismatch(format!("condition must be BOOLEAN, got {}", obj.type_name()))),
                     }
+
                 },
-                0x0A => {
-                    // OpNotEquals
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Boolean(left != right)),
-                        (Object::Boolean(right), Object::Boolean(left)) => self.push(Object::Boolean(left != right)),
-                        _ => panic!("unhandled argument types to OpNotEquals"),
+                Opcode::Jump => {
+                    let jump_address = self.read_two_byte_operand()?;
+                    self.jump(jump_address)?;
+                },
+                Opcode::SetGlobal => {
+                    let global_index = self.read_two_byte_operand()?;
+
+                    let value = self.pop()?;
+
+                    // globals only grow as far as the highest index set so far
+                    if global_index >= self.globals.len() {
+                        self.globals.resize(global_index + 1, self.null.clone());
                     }
+                    self.globals[global_index] = value;
+                },
+                Opcode::GetGlobal => {
+                    let global_index = self.read_two_byte_operand()?;
+
+                    // a global which was never set reads as null
+                    let value = self.globals.get(global_index).unwrap_or(&self.null).clone();
+                    self.push(value)?;
                 },
-                0x0B => {
-                    // OpGreaterThan
-                    match (self.pop(), self.pop()) {
-                        (Object::Integer(right), Object::Integer(left)) => self.push(Object::Boolean(left > right)),
-                        _ => panic!("unhandled argument types to OpGreaterThan"),
+                Opcode::Null => {
+                    self.push(self.null.clone())?;
+                },
+                Opcode::Array => {
+                    let length = self.read_two_byte_operand()?;
+
+                    let elements = self.pop_many(length)?.into_iter().map(into_object).collect();
+
+                    self.push(Object::Array(elements))?;
+                },
+                Opcode::Destructure => {
+                    let length = self.read_two_byte_operand()?;
+                    let array = self.pop()?;
+                    let elements = match &*array {
+                        Object::Array(elements) if elements.len() == length => elements,
+                        Object::Array(elements) => return self.halt_with_error(Object::Error(format!(
+                            "wrong number of values to destructure: want={}, got={}", length, elements.len()
+                        ))),
+                        obj => return self.halt_with_error(unary_type_mismatch("OpDestructure", obj)),
+                    };
+                    // pushed last to first, so the first element is stored first
+                    for element in elements.iter().rev() {
+                        self.push(element.clone())?;
                     }
                 },
-                0x0C => {
-                    // OpMinus
-                    match self.pop() {
-                        Object::Integer(num) => self.push(Object::Integer(-num)),
-                        _ => panic!("unhandled arg type to OpMinus"),
+                Opcode::Hash => {
+                    let length = self.read_two_byte_operand()?;
+
+                    // keys and values are interleaved on the stack, inserting them in order
+                    //   means a duplicated key keeps the value that was written last
+                    let mut hash = HashMap::new();
+                    let mut pairs = self.pop_many(2 * length)?.into_iter().map(into_object);
+                    while let (Some(key), Some(value)) = (pairs.next(), pairs.next()) {
+                        match HashKey::try_from(key) {
+                            Ok(key) => hash.insert(key, value),
+                            Err(err) => return self.halt_with_error(Object::Error(err.to_string())),
+                        };
                     }
+
+                    self.push(Object::Hash(hash))?;
                 },
-                0x0D => {
-                    // OpBang
-                    match self.pop() {
-                        Object::Boolean(bool) => self.push(Object::Boolean(!bool)),
-                        _ => panic!("unhandled arg type to OpBang"),
+                Opcode::ArrayLength => {
+                    match &*self.pop()? {
+                        Object::Array(elements) => self.push(Object::Integer(elements.len() as i32))?,
+                        obj => return self.halt_with_error(Object::Error(format!(
+                            "for loops iterate over ARRAY, got {}", obj.type_name()
+                        ))),
                     }
                 },
-                0x0E => {
-                    // OpJumpNotTrue
-                    match self.pop() {
-                        Object::Boolean(true) => {
-                            ip += 2; // don't jump, but skip the jump address
+                Opcode::Index => {
+                    let (index, left) = (self.pop()?, self.pop()?);
+                    match (&*index, &*left) {
+                        (Object::Integer(index), Object::Array(elements)) => {
+                            // negative indexes are out of bounds unless enabled, like in the reference implementation
+                            let index = if *index < 0 && self.negative_indexes {
+                                elements.len() as i64 + *index as i64
+                            } else {
+                                *index as i64
+                            };
+                            let element = if index < 0 {
+                                None
+                            } else {
+                                elements.get(index as usize).cloned()
+                            };
+                            self.push(element.unwrap_or(Object::Null))?;
                         },
-                        Object::Boolean(false) => {
-                            let jump_address = convert_two_u8s_be_to_usize(self.instructions[ip], self.instructions[ip + 1]);
-                            ip = jump_address;
+                        (Object::Integer(index), Object::String(string)) => {
+                            self.push(string_index(string, *index))?;
                         },
-                        _ => panic!("unhandled arg type to OpJumpNotTrue"),
+                        (key, Object::Hash(hash)) => {
+                            let value = match HashKey::try_from(key.clone()) {
+                                Ok(key) => hash.get(&key).cloned(),
+                                Err(err) => return self.halt_with_error(Object::Error(err.to_string())),
+                            };
+                            self.push(value.unwrap_or(Object::Null))?;
+                        },
+                        (index, left) => return self.halt_with_error(binary_type_mismatch("OpIndex", left, index)),
+                    }
+                },
+                Opcode::Slice => {
+                    let (end, start, collection) = (self.pop()?, self.pop()?, self.pop()?);
+                    match slice(&collection, &start, &end) {
+                        Object::Error(err) => return self.halt_with_error(Object::Error(err)),
+                        sliced => self.push(sliced)?,
+                    }
+                },
+                Opcode::Call => {
+                    let num_args = self.read_one_byte_operand()?;
+                    // the called function sits on the stack below its arguments
+                    if self.sp < num_args + 1 {
+                        return Err(VMError::StackUnderflow);
+                    }
+                    if let Some(result) = self.call(num_args)? {
+                        if let Object::Error(_) = result {
+                            return self.halt_with_error(result);
+                        }
+                        self.push(result)?;
+                    }
+                },
+                Opcode::TailCall => {
+                    let num_args = self.read_one_byte_operand()?;
+                    if self.sp < num_args + 1 {
+                        return Err(VMError::StackUnderflow);
+                    }
+                    // anything else is called normally, the compiler only promises the result is returned
+                    if !self.reuse_frame(num_args) {
+                        if let Some(result) = self.call(num_args)? {
+                            if let Object::Error(_) = result {
+                                return self.halt_with_error(result);
+                            }
+                            self.push(result)?;
+                        }
+                    }
+                },
+                Opcode::ReturnValue => {
+                    let return_value = self.pop()?;
+                    if self.frames.len() == 1 {
+                        // returning from the main program halts it, leaving the returned value as the last popped
+                        self.last_popped = return_value;
+                        break;
                     }
+                    self.return_from_frame(return_value)?;
+                },
+                Opcode::Return => {
+                    self.return_from_frame(self.null.clone())?;
+                },
+                Opcode::SetLocal => {
+                    let local_index = self.read_one_byte_operand()?;
+                    let slot = self.local_slot(local_index)?;
 
+                    self.stack[slot] = self.pop()?;
                 },
-                0x0F => {
-                    // OpJump
-                    let jump_address = convert_two_u8s_be_to_usize(self.instructions[ip], self.instructions[ip + 1]);
-                    ip = jump_address;
+                Opcode::GetLocal => {
+                    let local_index = self.read_one_byte_operand()?;
+                    let slot = self.local_slot(local_index)?;
+
+                    self.push(self.stack[slot].clone())?;
                 },
-                0x10 => {
-                    // OpSetGlobal
-                    let global_index = convert_two_u8s_be_to_usize(self.instructions[ip], self.instructions[ip + 1]);
-                    ip += 2;
+                Opcode::Closure => {
+                    let const_index = self.read_two_byte_operand()?;
+                    let num_free = self.read_one_byte_operand()?;
 
-                    let value = self.pop();
+                    let function = match &**self.constant(const_index)? {
+                        Object::CompiledFunction(function) => function.clone(),
+                        _ => return Err(VMError::InvalidConstant(const_index)),
+                    };
+                    let free = self.pop_many(num_free)?;
 
-                    self.globals[global_index] = value;
+                    self.push(Object::Closure {function, free})?;
                 },
-                0x11 => {
-                    // OpGetGlobal
-                    let global_index = convert_two_u8s_be_to_usize(self.instructions[ip], self.instructions[ip + 1]);
-                    ip += 2;
+                Opcode::GetFree => {
+                    let free_index = self.read_one_byte_operand()?;
 
-                    self.push(self.globals[global_index].clone());
+                    let value = self.current_frame().free(free_index).cloned().ok_or(VMError::InvalidFree(free_index))?;
+                    self.push(value)?;
+                },
+                Opcode::GetBuiltin => {
+                    let builtin_index = self.read_one_byte_operand()?;
+                    if builtins::lookup(builtin_index).is_none() {
+                        return Err(VMError::InvalidBuiltin(builtin_index));
+                    }
+                    self.push(Object::Builtin(builtin_index))?;
+                },
+                Opcode::CurrentClosure => {
+                    // the main program isn't a closure, there is nothing below its frame
+                    if self.frames.len() == 1 {
+                        return Err(VMError::StackUnderflow);
+                    }
+                    // the closure being run was left on the stack just below its arguments when it was called
+                    let base_pointer = self.current_frame_mut().base_pointer;
+                    self.push(self.stack[base_pointer - 1].clone())?;
                 },
-                _ => panic!("unhandled instruction"),
             }
         }
+
+        Ok(())
+    }
+
+    /// where a local of the current frame lives on the stack, which has to be in the space reserved for its locals
+    fn local_slot(&self, local_index: usize) -> Result<usize, VMError> {
+        let slot = self.current_frame().base_pointer + local_index;
+        if slot >= self.sp {
+            return Err(VMError::InvalidLocal(local_index));
+        }
+
+        Ok(slot)
+    }
+
+    /// calls the function sitting on the stack below its arguments
+    /// a closure gets a new frame to run in, a builtin is run straight away and its result returned
+    fn call(&mut self, num_args: usize) -> Result<Option<Object>, VMError> {
+        let callee = self.stack[self.sp - 1 - num_args].clone();
+        match &*callee {
+            Object::Closure {function, ..} => {
+                if function.num_parameters != num_args {
+                    return Err(VMError::WrongArgumentCount { expected: function.num_parameters, given: num_args });
+                }
+                // every frame keeps at least its function on the stack, so deeper call chains can never be valid
+                if self.frames.len() >= self.stack.len() {
+                    return Err(VMError::FrameOverflow);
+                }
+                // the arguments become the first locals of the new frame
+                let base_pointer = self.sp - num_args;
+                self.frames.push(Frame::new(function.clone(), Some(callee.clone()), base_pointer));
+                // reserve space on the stack for the rest of the locals of the function
+                if base_pointer + function.num_locals >= self.stack.len() {
+                    return Err(VMError::StackOverflow);
+                }
+                self.sp = base_pointer + function.num_locals;
+
+                Ok(None)
+            },
+            Object::Builtin(index) => {
+                let builtin = builtins::lookup(*index).ok_or(VMError::InvalidBuiltin(*index))?;
+                // held apart from the stack, which the builtin may use to call functions
+                let arguments: Vec<Rc<Object>> = self.stack[self.sp - num_args..self.sp].to_vec();
+                let arguments: Vec<&Object> = arguments.iter().map(|obj| &**obj).collect();
+                let result = builtin(&arguments, self);
+                if let Some(err) = self.callback_error.take() {
+                    return Err(err);
+                }
+                self.sp -= num_args + 1; // plus one to also remove the builtin itself
+
+                Ok(Some(result))
+            },
+            obj => Err(VMError::NotCallable(obj.type_name())),
+        }
+    }
+
+    /// restarts the closure being run with the arguments on top of the stack, if they're for that
+    /// same closure, rather than pushing a new frame. returns whether the frame was reused
+    fn reuse_frame(&mut self, num_args: usize) -> bool {
+        if self.frames.len() == 1 {
+            return false;
+        }
+        let base_pointer = self.current_frame().base_pointer;
+        let callee = &self.stack[self.sp - 1 - num_args];
+        if !Rc::ptr_eq(callee, &self.stack[base_pointer - 1]) {
+            return false;
+        }
+        let num_locals = match &**callee {
+            Object::Closure {function, ..} if function.num_parameters == num_args => function.num_locals,
+            _ => return false,
+        };
+
+        // the arguments replace the parameters, the other locals are reset by the function itself
+        for index in 0..num_args {
+            self.stack[base_pointer + index] = self.stack[self.sp - num_args + index].clone();
+        }
+        self.sp = base_pointer + num_locals;
+        self.current_frame_mut().ip = 0;
+
+        true
+    }
+
+    /// calls a function on behalf of a builtin, running the vm until it returns
+    fn call_function(&mut self, function: &Object, arguments: Vec<Object>) -> Result<Object, VMError> {
+        let sp = self.sp;
+        let depth = self.frames.len() + 1;
+        let num_args = arguments.len();
+        self.push(function.clone())?;
+        for argument in arguments {
+            self.push(argument)?;
+        }
+
+        if let Some(result) = self.call(num_args)? {
+            return Ok(result);
+        }
+        self.execute(depth)?;
+        if self.frames.len() >= depth {
+            // halted by an error object, which is handed back to the builtin in place of a result
+            self.frames.truncate(depth - 1);
+            self.sp = sp;
+            return Ok(Object::clone(&self.last_popped));
+        }
+
+        Ok(into_object(self.pop()?))
+    }
+
+    fn trace_instruction(&mut self, ip: usize) {
+        let frame = self.current_frame();
+        let (instruction, _) = disassemble_instruction(frame.instructions(), ip);
+        let stack: Vec<String> = self.stack[..self.sp].iter().map(|obj| obj.inspect()).collect();
+
+        // the trace is only a debugging aid, failing to write it shouldn't stop the program
+        let _ = writeln!(self.output, "{} stack: [{}]", instruction, stack.join(", "));
+    }
+
+    /// pushes the error and stops the program, the error becomes its result
+    fn halt_with_error(&mut self, error: Object) -> Result<(), VMError> {
+        let error = Rc::new(error);
+        self.push(error.clone())?;
+        self.last_popped = error;
+
+        Ok(())
+    }
+
+    /// jumping to the end of the instructions is allowed, it finishes the frame
+    fn jump(&mut self, address: usize) -> Result<(), VMError> {
+        let frame = self.current_frame_mut();
+        if address > frame.instructions().len() {
+            return Err(VMError::InvalidJumpTarget(address));
+        }
+        frame.ip = address;
+
+        Ok(())
+    }
+
+    fn constant(&self, const_index: usize) -> Result<&Rc<Object>, VMError> {
+        self.constants.get(const_index).ok_or(VMError::InvalidConstantIndex(const_index))
+    }
+
+    fn current_frame(&self) -> &Frame {
+        self.frames.last().expect("vm should always have a frame")
+    }
+
+    fn current_frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("vm should always have a frame")
+    }
+
+    fn return_from_frame(&mut self, return_value: Rc<Object>) -> Result<(), VMError> {
+        let frame = self.frames.pop().expect("attempted to return without a frame");
+        self.sp = frame.base_pointer - 1; // minus one to also remove the called function from the stack
+        self.push(return_value)
+    }
+
+    /// reads the one byte operand following the current instruction and moves the instruction pointer past it
+    fn read_one_byte_operand(&mut self) -> Result<usize, VMError> {
+        let frame = self.current_frame_mut();
+        let operand = *frame.instructions().get(frame.ip).ok_or(VMError::MissingOperand(frame.ip))? as usize;
+        frame.ip += 1;
+
+        Ok(operand)
+    }
+
+    /// reads the two byte operand following the current instruction and moves the instruction pointer past it
+    fn read_two_byte_operand(&mut self) -> Result<usize, VMError> {
+        let ip = self.current_frame().ip;
+        let operand = self.read_u16(ip)?;
+        self.current_frame_mut().ip += 2;
+
+        Ok(operand)
+    }
+
+    /// the big endian u16 at offset in the instructions of the current frame
+    fn read_u16(&self, offset: usize) -> Result<usize, VMError> {
+        match self.current_frame().instructions().get(offset..offset + 2) {
+            Some(&[byte1, byte2]) => Ok(convert_two_u8s_be_to_usize(byte1, byte2)),
+            _ => Err(VMError::MissingOperand(offset)),
+        }
+    }
+
+    /// takes either a new object or a shared one
+    fn push(&mut self, obj: impl Into<Rc<Object>>) -> Result<(), VMError> {
+        if self.sp >= self.stack.len() {
+            return Err(VMError::StackOverflow);
+        }
+
+        self.stack[self.sp] = obj.into();
+        self.sp += 1;
+
+        Ok(())
     }
 
-    fn push(&mut self, obj: Object) {
-        self.stack[self.sp] = obj;
-        self.sp += 1; // ignoring the potential stack overflow
+    fn push_bool(&mut self, value: bool) -> Result<(), VMError> {
+        let obj = if value { self.true_object.clone() } else { self.false_object.clone() };
+        self.push(obj)
     }
 
-    fn pop(&mut self) -> Object {
-        // ignoring the potential of stack underflow
-        // cloning rather than mem::replace to support the last_popped method for testing
-        let obj = self.stack[self.sp - 1].clone();
+    fn pop(&mut self) -> Result<Rc<Object>, VMError> {
+        if self.sp == 0 {
+            return Err(VMError::StackUnderflow);
+        }
+
+        // moving the object out, rather than cloning, means the popped value is often its only owner
+        let obj = std::mem::replace(&mut self.stack[self.sp - 1], self.null.clone());
         self.sp -= 1;
 
-        obj
+        Ok(obj)
+    }
+
+    /// pops the top `count` elements, returning them in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Result<Vec<Rc<Object>>, VMError> {
+        if self.sp < count {
+            return Err(VMError::StackUnderflow);
+        }
+
+        let null = &self.null;
+        let elements = self.stack[self.sp - count..self.sp]
+            .iter_mut()
+            .map(|obj| std::mem::replace(obj, null.clone()))
+            .collect();
+        self.sp -= count;
+
+        Ok(elements)
     }
 
-    fn last_popped(&self) -> &Object {
-        // the stack pointer points to the next "free" space, which also holds the most recently popped element
-        &self.stack[self.sp]
+    /// the value of the last expression statement the program ran, or the value it returned
+    pub fn last_popped(&self) -> &Object {
+        &self.last_popped
     }
+
+    /// the value currently on top of the stack, a program which ran to completion leaves the stack empty
+    pub fn stack_top(&self) -> Option<&Object> {
+        self.sp.checked_sub(1).map(|top| &*self.stack[top])
+    }
+
+    /// an owned copy of the program's result, null if nothing was popped
+    /// a program halted by an error object, such as from `1 + true` or `len(1)`, has that error as its result
+    /// only meaningful after run has returned Ok, a failed run leaves whatever was popped before the error
+    pub fn result(&self) -> Object {
+        Object::clone(&self.last_popped)
+    }
+}
+
+/// the object itself when nothing else shares it, otherwise a copy of it
+fn into_object(obj: Rc<Object>) -> Object {
+    Rc::try_unwrap(obj).unwrap_or_else(|obj| Object::clone(&obj))
+}
+
+impl builtins::Interpreter for VM<'_> {
+    fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+
+    fn call(&mut self, function: &Object, arguments: Vec<Object>) -> Object {
+        match self.call_function(function, arguments) {
+            Ok(result) => result,
+            Err(err) => {
+                let error = Object::Error(err.to_string());
+                self.callback_error = Some(err);
+                error
+            },
+        }
+    }
+}
+
+fn binary_type_mismatch(op: &str, left: &Object, right: &Object) -> Object {
+    Object::Error(format!("unsupported types for {}: {} and {}", op, left.type_name(), right.type_name()))
+}
+
+fn unary_type_mismatch(op: &str, operand: &Object) -> Object {
+    Object::Error(format!("unsupported type for {}: {}", op, operand.type_name()))
+}
+
+/// runs the bytecode to completion, returning the value of the last expression statement
+pub fn run_from_bytecode(byte_code: ByteCode) -> Result<Object, VMError> {
+    let mut vm = VM::new(byte_code);
+    vm.run()?;
+
+    Ok(into_object(vm.last_popped))
+}
+
+/// reads and compiles a source file, a syntax error in the file is returned as RunFileError::Parse
+pub fn compile_file(path: &Path) -> Result<ByteCode, RunFileError> {
+    let input = std::fs::read_to_string(path)
+        .map_err(|error| RunFileError::Read { path: path.to_path_buf(), error })?;
+
+    compile_from_source(&input).map_err(|error| match error {
+        CompileError::Parse(error) => RunFileError::Parse { path: path.to_path_buf(), error },
+        error => RunFileError::Compile { path: path.to_path_buf(), error },
+    })
+}
+
+/// compiles and runs a source file, returning the value of its last expression statement
+pub fn run_file(path: &Path) -> Result<Object, RunFileError> {
+    let byte_code = compile_file(path)?;
+
+    run_from_bytecode(byte_code).map_err(|error| RunFileError::Run { path: path.to_path_buf(), error })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::compiler::compile_from_source;
+    use crate::compiler::optimize;
+    use crate::code::{make_op, LineTable, OpCode};
 
     #[test]
     fn run_infix() {
@@ -192,69 +949,1511 @@ mod tests {
         assert_last_popped("1 - 2;", Object::Integer(-1));
         assert_last_popped("3 * 2;", Object::Integer(6));
         assert_last_popped("6 / 2;", Object::Integer(3));
+        assert_last_popped("10 % 3;", Object::Integer(1));
+        assert_last_popped("-7 % 3;", Object::Integer(-1));
+        assert_last_popped("1 + 10 % 4 * 2;", Object::Integer(5));
     }
 
     #[test]
-    fn run_bool() {
-        assert_last_popped("true;", Object::Boolean(true));
-        assert_last_popped("false;", Object::Boolean(false));
+    fn run_float() {
+        assert_last_popped("1.5 + 2.5;", Object::Float(4.0));
+        assert_last_popped("3 * 2.0;", Object::Float(6.0));
+        assert_last_popped("1 - 0.25;", Object::Float(0.75));
+        assert_last_popped("7.0 / 2;", Object::Float(3.5));
+        assert_last_popped("-1.5;", Object::Float(-1.5));
+        // dividing two integers is still integer division
+        assert_last_popped("6 / 2;", Object::Integer(3));
+        assert_last_popped("7 / 2;", Object::Integer(3));
+        // ~/ always gives an integer, truncating toward zero like integer division does
+        assert_last_popped("7 ~/ 2;", Object::Integer(3));
+        assert_last_popped("-7 ~/ 2;", Object::Integer(-3));
+        assert_last_popped("7.0 ~/ 2;", Object::Integer(3));
+        assert_last_popped("7 ~/ 2.5;", Object::Integer(2));
+        assert_last_popped("-7.5 ~/ 2;", Object::Integer(-3));
+        assert_last_popped("1 + 7 ~/ 2 * 2;", Object::Integer(7));
+        assert_last_popped("1.0 ~/ 0;", Object::Error(String::from("could not convert inf to INTEGER")));
+        assert_last_popped(r#""a" ~/ 2;"#, Object::Error(String::from("unsupported types for OpIntDiv: STRING and INTEGER")));
     }
 
     #[test]
-    fn run_equals() {
-        assert_last_popped("1 == 1;", Object::Boolean(true));
-        assert_last_popped("1 == 2;", Object::Boolean(false));
-        assert_last_popped("true == true;", Object::Boolean(true));
-        assert_last_popped("true == false;", Object::Boolean(false));
+    fn run_division_by_zero() {
+        assert_run_error("1 / 0;", VMError::DivisionByZero);
+        assert_run_error("let zero = 0; 1 ~/ zero;", VMError::DivisionByZero);
+        assert_run_error("let zero = 2 - 2; 10 / zero;", VMError::DivisionByZero);
+        assert_run_error("7 % 0;", VMError::DivisionByZero);
+        assert_eq!("division by zero", VMError::DivisionByZero.to_string());
     }
 
     #[test]
-    fn run_not_equals() {
-        assert_last_popped("1 != 1;", Object::Boolean(false));
-        assert_last_popped("1 != 2;", Object::Boolean(true));
-        assert_last_popped("true != true;", Object::Boolean(false));
-        assert_last_popped("true != false;", Object::Boolean(true));
+    fn run_integer_overflow() {
+        assert_last_popped("-2147483647 - 1;", Object::Integer(i32::MIN));
+        assert_last_popped("2147483647 * -1;", Object::Integer(-i32::MAX));
+        assert_run_error("2147483647 + 1;", VMError::IntegerOverflow);
+        assert_run_error("-2147483647 - 2;", VMError::IntegerOverflow);
+        assert_run_error("2147483647 * 2;", VMError::IntegerOverflow);
+        assert_run_error("46341 * 46341;", VMError::IntegerOverflow);
+        assert_run_error("let min = -2147483647 - 1; min / -1;", VMError::IntegerOverflow);
+        assert_run_error("let min = -2147483647 - 1; min % -1;", VMError::IntegerOverflow);
+        assert_run_error("let min = -2147483647 - 1; -min;", VMError::IntegerOverflow);
+        assert_eq!("integer overflow", VMError::IntegerOverflow.to_string());
     }
 
     #[test]
-    fn run_greater_than() {
-        assert_last_popped("1 > 0;", Object::Boolean(true));
-        assert_last_popped("1 > 2;", Object::Boolean(false));
+    fn run_string() {
+        assert_last_popped(r#""foo";"#, Object::String(String::from("foo")));
+        assert_last_popped(r#""foo" + "bar";"#, Object::String(String::from("foobar")));
+        assert_last_popped(r#"let a = "foo"; a + "bar" + a;"#, Object::String(String::from("foobarfoo")));
     }
 
     #[test]
-    fn run_less_than() {
-        assert_last_popped("1 < 0;", Object::Boolean(false));
-        assert_last_popped("1 < 2;", Object::Boolean(true));
+    fn run_string_escapes() {
+        assert_last_popped(r#"len("a\nb");"#, Object::Integer(3));
+        assert_last_popped(r#""\"";"#, Object::String(String::from("\"")));
+        assert_last_popped(r#"len("\"");"#, Object::Integer(1));
+        assert_last_popped(r#"split("a\tb", "\t");"#, Object::Array(vec![
+            Object::String(String::from("a")),
+            Object::String(String::from("b")),
+        ]));
+        assert_last_popped(r#""\\" + "\0";"#, Object::String(String::from("\\\0")));
     }
 
     #[test]
-    fn run_prefix() {
-        assert_last_popped("-1;", Object::Integer(-1));
-        assert_last_popped("!false;", Object::Boolean(true));
+    fn run_raw_strings() {
+        assert_last_popped(r#"r"C:\path\to";"#, Object::String(String::from(r"C:\path\to")));
+        assert_last_popped(r#"len(r"a\nb");"#, Object::Integer(4));
+        assert_last_popped(r###"r#"say "hi""# == "say \"hi\"";"###, Object::Boolean(true));
     }
 
     #[test]
-    fn run_if() {
-        assert_last_popped("if (true) { 10; };", Object::Integer(10));
-        assert_last_popped("if (true) { 10; }; 3333;", Object::Integer(3333));
-        assert_last_popped("if (true) { 10; } else { 20; };", Object::Integer(10));
-        assert_last_popped("if (true) { 10; } else { 20; }; 3333;", Object::Integer(3333));
-        assert_last_popped("if (false) { 10; } else { 20; };", Object::Integer(20));
+    fn run_string_plus_integer() {
+        assert_last_popped(r#""foo" + 1;"#, Object::Error(String::from("unsupported types for OpAdd: STRING and INTEGER")));
     }
 
     #[test]
-    fn run_variable_declaration() {
-        assert_last_popped("let one = 1; one;", Object::Integer(1));
-        assert_last_popped("let one = 1; let two = one + one; one + two;", Object::Integer(3));
+    fn run_string_comparison() {
+        assert_last_popped(r#""abc" == "abc";"#, Object::Boolean(true));
+        assert_last_popped(r#""abc" == "abd";"#, Object::Boolean(false));
+        assert_last_popped(r#""abc" != "abd";"#, Object::Boolean(true));
+        assert_last_popped(r#""abc" != "abc";"#, Object::Boolean(false));
+        assert_last_popped(r#""abc" < "abd";"#, Object::Boolean(true));
+        assert_last_popped(r#""ab" < "abc";"#, Object::Boolean(true));
+        assert_last_popped(r#""b" < "abc";"#, Object::Boolean(false));
+        assert_last_popped(r#""b" > "abc";"#, Object::Boolean(true));
+        assert_last_popped(r#""abc" > "abc";"#, Object::Boolean(false));
+        assert_last_popped(r#""abc" >= "abc";"#, Object::Boolean(true));
+        assert_last_popped(r#""abd" <= "abc";"#, Object::Boolean(false));
+        assert_last_popped(r#""1" < 2;"#, Object::Error(String::from("unsupported types for OpLessThan: STRING and INTEGER")));
     }
 
-    fn assert_last_popped(input: &str, obj: Object) {
-        let byte_code = compile_from_source(input);
+    #[test]
+    fn run_structural_equality() {
+        assert_last_popped("[1, [2, 3]] == [1, [2, 3]];", Object::Boolean(true));
+        assert_last_popped("[1, [2, 3]] == [1, [2, 4]];", Object::Boolean(false));
+        assert_last_popped("[1, 2] != [1, 2, 3];", Object::Boolean(true));
+        assert_last_popped("[] == [];", Object::Boolean(true));
+        assert_last_popped(r#"{"a": 1, "b": [2]} == {"b": [2], "a": 1};"#, Object::Boolean(true));
+        assert_last_popped(r#"{"a": 1} == {"a": 2};"#, Object::Boolean(false));
+        assert_last_popped("let nothing = if (false) { 1; }; nothing == if (false) { 2; };", Object::Boolean(true));
+        assert_last_popped("1.5 == 1.5;", Object::Boolean(true));
+        assert_last_popped("let f = fn() { 1; }; f == f;", Object::Boolean(true));
+        // values of different types are never equal, not even numbers
+        assert_last_popped(r#"1 == "1";"#, Object::Boolean(false));
+        assert_last_popped(r#"1 != "1";"#, Object::Boolean(true));
+        assert_last_popped("1 == 1.0;", Object::Boolean(false));
+        assert_last_popped("[1] == 1;", Object::Boolean(false));
+        assert_last_popped("true == 1;", Object::Boolean(false));
+    }
+
+    #[test]
+    fn run_string_global() {
+        assert_last_popped(r#"let greeting = "hello"; greeting;"#, Object::String(String::from("hello")));
+        assert_last_popped(r#"let a = "one"; let b = a; let a = "two"; b;"#, Object::String(String::from("one")));
+    }
+
+    #[test]
+    fn run_array() {
+        assert_last_popped("[];", Object::Array(vec![]));
+        assert_last_popped("[1, 2, 3];", Object::Array(vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)]));
+        assert_last_popped("[1 + 2, 3 * 4];", Object::Array(vec![Object::Integer(3), Object::Integer(12)]));
+        assert_last_popped("[[1], [2]];", Object::Array(vec![
+            Object::Array(vec![Object::Integer(1)]),
+            Object::Array(vec![Object::Integer(2)]),
+        ]));
+    }
+
+    #[test]
+    fn run_hash() {
+        assert_last_popped("{};", Object::Hash(HashMap::new()));
 
+        let mut expected = HashMap::new();
+        expected.insert(HashKey::Integer(1), Object::Integer(2));
+        expected.insert(HashKey::String(String::from("a")), Object::Integer(7));
+        expected.insert(HashKey::Boolean(false), Object::String(String::from("b")));
+        assert_last_popped(r#"{1: 2, "a": 3 + 4, false: "b"};"#, Object::Hash(expected));
+    }
+
+    #[test]
+    fn run_hash_lookup() {
+        let byte_code = compile_from_source(r#"{"a": 1, "b": 2, "c": 3, "b": 4};"#).expect("compile");
         let mut vm = VM::new(byte_code);
-        vm.run();
+        vm.run().expect("vm run");
 
-        assert_eq!(&obj, vm.last_popped());
+        match vm.last_popped() {
+            Object::Hash(hash) => {
+                assert_eq!(3, hash.len());
+                assert_eq!(Some(&Object::Integer(1)), hash.get(&HashKey::String(String::from("a"))));
+                assert_eq!(Some(&Object::Integer(4)), hash.get(&HashKey::String(String::from("b"))));
+                assert_eq!(Some(&Object::Integer(3)), hash.get(&HashKey::String(String::from("c"))));
+            },
+            obj => panic!("expected hash, got {:?}", obj),
+        }
+    }
+
+    #[test]
+    fn run_string_repeat() {
+        assert_last_popped(r#""x" * 4;"#, Object::String(String::from("xxxx")));
+        assert_last_popped(r#"3 * "y";"#, Object::String(String::from("yyy")));
+        assert_last_popped(r#""z" * 0;"#, Object::String(String::new()));
+        assert_last_popped(r#""z" * -2;"#, Object::String(String::new()));
+        assert_last_popped(
+            r#""ab" * 1000000;"#,
+            Object::Error(String::from("repeated string would be longer than 1048576 bytes"))
+        );
+    }
+
+    #[test]
+    fn run_string_index() {
+        assert_last_popped(r#""hello"[0];"#, Object::String(String::from("h")));
+        assert_last_popped(r#""hello"[4];"#, Object::String(String::from("o")));
+        assert_last_popped(r#""héllo"[1];"#, Object::String(String::from("é")));
+        assert_last_popped(r#""héllo"[2];"#, Object::String(String::from("l")));
+        assert_last_popped(r#""hello"[5];"#, Object::Null);
+        assert_last_popped(r#""hello"[-1];"#, Object::Null);
+        assert_last_popped(r#"""[0];"#, Object::Null);
+    }
+
+    #[test]
+    fn run_hash_unusable_key() {
+        assert_last_popped("{[1]: 2};", Object::Error(String::from("unusable as hash key: ARRAY")));
+        assert_last_popped("{1: 2}[fn() { 1; }];", Object::Error(String::from("unusable as hash key: FUNCTION")));
+    }
+
+    #[test]
+    fn run_index() {
+        assert_last_popped("[1, 2, 3][1];", Object::Integer(2));
+        assert_last_popped("[1, 2, 3][0 + 2];", Object::Integer(3));
+        assert_last_popped("[[1, 1, 1]][0][0];", Object::Integer(1));
+        assert_last_popped("[1, 2, 3][99];", Object::Null);
+        assert_last_popped("[1, 2, 3][-1];", Object::Null);
+        assert_last_popped("[][0];", Object::Null);
+        assert_last_popped(r#"{"a": 5}["a"];"#, Object::Integer(5));
+        assert_last_popped(r#"{"a": 5}["b"];"#, Object::Null);
+        assert_last_popped("{1: 1, 2: 2}[2];", Object::Integer(2));
+        assert_last_popped("{true: 1}[1 > 0];", Object::Integer(1));
+    }
+
+    #[test]
+    fn run_slices() {
+        let array = |elements: &[i32]| Object::Array(elements.iter().map(|&element| Object::Integer(element)).collect());
+
+        assert_last_popped("[1, 2, 3, 4][1:3];", array(&[2, 3]));
+        assert_last_popped("[1, 2, 3, 4][:2];", array(&[1, 2]));
+        assert_last_popped("[1, 2, 3, 4][1:];", array(&[2, 3, 4]));
+        assert_last_popped("[1, 2, 3, 4][:];", array(&[1, 2, 3, 4]));
+        assert_last_popped("[1, 2, 3, 4][-5:10];", array(&[1, 2, 3, 4]));
+        assert_last_popped("[1, 2, 3, 4][3:1];", array(&[]));
+        assert_last_popped("let a = [1, 2, 3]; let i = 1; a[i:i + 1];", array(&[2]));
+        assert_last_popped(r#""hello"[1:3];"#, Object::String(String::from("el")));
+        assert_last_popped(r#""hello"[:2];"#, Object::String(String::from("he")));
+        assert_last_popped(r#""héllo"[1:];"#, Object::String(String::from("éllo")));
+        assert_last_popped(r#""hello"[4:99];"#, Object::String(String::from("o")));
+        assert_last_popped(r#""hello"[9:];"#, Object::String(String::new()));
+        assert_last_popped(r#"[1, 2]["a":];"#, Object::Error(String::from("slice bounds must be INTEGER, got STRING")));
+        assert_last_popped("{1: 2}[0:1];", Object::Error(String::from("unsupported type for slice: HASH")));
+    }
+
+    #[test]
+    fn run_bool() {
+        assert_last_popped("true;", Object::Boolean(true));
+        assert_last_popped("false;", Object::Boolean(false));
+    }
+
+    #[test]
+    fn run_equals() {
+        assert_last_popped("1 == 1;", Object::Boolean(true));
+        assert_last_popped("1 == 2;", Object::Boolean(false));
+        assert_last_popped("true == true;", Object::Boolean(true));
+        assert_last_popped("true == false;", Object::Boolean(false));
+    }
+
+    #[test]
+    fn run_not_equals() {
+        assert_last_popped("1 != 1;", Object::Boolean(false));
+        assert_last_popped("1 != 2;", Object::Boolean(true));
+        assert_last_popped("true != true;", Object::Boolean(false));
+        assert_last_popped("true != false;", Object::Boolean(true));
+    }
+
+    #[test]
+    fn run_greater_than() {
+        assert_last_popped("1 > 0;", Object::Boolean(true));
+        assert_last_popped("1 > 2;", Object::Boolean(false));
+    }
+
+    #[test]
+    fn run_less_than() {
+        assert_last_popped("1 < 0;", Object::Boolean(false));
+        assert_last_popped("1 < 2;", Object::Boolean(true));
+        assert_last_popped("2 < 2;", Object::Boolean(false));
+        assert_last_popped("-5 < -1;", Object::Boolean(true));
+        assert_last_popped("1 >= 2;", Object::Boolean(false));
+        assert_last_popped("2 >= 2;", Object::Boolean(true));
+        assert_last_popped("3 >= 2;", Object::Boolean(true));
+        assert_last_popped("1 <= 2;", Object::Boolean(true));
+        assert_last_popped("2 <= 2;", Object::Boolean(true));
+        assert_last_popped("3 <= 2;", Object::Boolean(false));
+    }
+
+    #[test]
+    fn run_prefix() {
+        assert_last_popped("-1;", Object::Integer(-1));
+        assert_last_popped("!false;", Object::Boolean(true));
+    }
+
+    #[test]
+    fn run_if() {
+        assert_last_popped("if (true) { 10; };", Object::Integer(10));
+        assert_last_popped("if (true) { 10; }; 3333;", Object::Integer(3333));
+        assert_last_popped("if (true) { 10; } else { 20; };", Object::Integer(10));
+        assert_last_popped("if (true) { 10; } else { 20; }; 3333;", Object::Integer(3333));
+        assert_last_popped("if (false) { 10; } else { 20; };", Object::Integer(20));
+        assert_last_popped("if (false) { 10; };", Object::Null);
+        assert_last_popped("if (true) { let a = 1; };", Object::Null);
+    }
+
+    #[test]
+    fn run_variable_declaration() {
+        assert_last_popped("let one = 1; one;", Object::Integer(1));
+        assert_last_popped("let one = 1; let two = one + one; one + two;", Object::Integer(3));
+    }
+
+    #[test]
+    fn run_function_call() {
+        assert_last_popped("let f = fn() { 5; }; f();", Object::Integer(5));
+        assert_last_popped("let f = fn() { 5 + 10; }; f();", Object::Integer(15));
+        assert_last_popped("let one = fn() { 1; }; let two = fn() { 2; }; one() + two();", Object::Integer(3));
+        assert_last_popped("let a = fn() { 1; }; let b = fn() { a() + 1; }; let c = fn() { b() + 1; }; c();", Object::Integer(3));
+        assert_last_popped("fn() { 24; }();", Object::Integer(24));
+        assert_last_popped("let f = fn() { }; f();", Object::Null);
+    }
+
+    #[test]
+    fn run_function_return() {
+        assert_last_popped("let f = fn() { return 99; 100; }; f();", Object::Integer(99));
+        assert_last_popped("let f = fn() { return 99; return 100; }; f();", Object::Integer(99));
+        assert_last_popped("let f = fn() { let a = 1; }; f();", Object::Null);
+        assert_last_popped("let f = fn() { if (true) { return 1; }; 2; }; f();", Object::Integer(1));
+        assert_last_popped("let f = fn() { if (false) { return 1; }; 2; }; f();", Object::Integer(2));
+        assert_last_popped("fn() { return fn() { 1; }(); }();", Object::Integer(1));
+        assert_last_popped("let f = fn() { fn() { 1; }; }; f()() + f()();", Object::Integer(2));
+        assert_last_popped("let f = fn() { 1; }; [f(), f() + 1, 3];", Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+        ]));
+    }
+
+    #[test]
+    fn run_function_locals() {
+        assert_last_popped("let f = fn() { let a = 5; a; }; f();", Object::Integer(5));
+        assert_last_popped("let f = fn() { let a = 1; let b = 2; a + b; }; f();", Object::Integer(3));
+        assert_last_popped("let f = fn() { let a = 1; let b = 2; let a = 10; b - a; }; f();", Object::Integer(-8));
+        assert_last_popped(r#"
+            let g = 50;
+            let minusOne = fn() { let num = 1; g - num; };
+            let minusTwo = fn() { let num = 2; g - num; };
+            minusOne() + minusTwo();
+        "#, Object::Integer(97));
+        assert_last_popped(r#"
+            let first = fn() { let a = 1; let b = 2; a + b; };
+            let second = fn() { let c = 3; let d = first(); c * d; };
+            second();
+        "#, Object::Integer(9));
+    }
+
+    #[test]
+    fn run_local_shadows_global() {
+        assert_last_popped("let a = 1; let f = fn() { let a = 2; a; }; f() + a;", Object::Integer(3));
+        assert_last_popped("let a = 1; let f = fn() { let b = a; let a = 2; a + b; }; f();", Object::Integer(3));
+    }
+
+    #[test]
+    fn run_function_arguments() {
+        assert_last_popped("let identity = fn(a) { a; }; identity(4);", Object::Integer(4));
+        assert_last_popped("let add = fn(a, b) { a + b; }; add(3, 4);", Object::Integer(7));
+        assert_last_popped("let add = fn(a, b) { let c = a + b; c; }; add(1, 2) + add(3, 4);", Object::Integer(10));
+        assert_last_popped(r#"
+            let sum = fn(a, b) { let c = a + b; c; };
+            let outer = fn() { sum(1, 2) + sum(3, 4); };
+            outer();
+        "#, Object::Integer(10));
+        assert_last_popped(r#"
+            let g = 10;
+            let sum = fn(a, b) { let c = a + b; c + g; };
+            let outer = fn() { sum(1, 2) + sum(3, 4) + g; };
+            outer() + g;
+        "#, Object::Integer(50));
+    }
+
+    #[test]
+    fn run_function_wrong_argument_count() {
+        assert_run_error("fn() { 1; }(1);", VMError::WrongArgumentCount { expected: 0, given: 1 });
+        assert_run_error("fn(a) { a; }();", VMError::WrongArgumentCount { expected: 1, given: 0 });
+        assert_run_error("fn(a, b) { a + b; }(1);", VMError::WrongArgumentCount { expected: 2, given: 1 });
+    }
+
+    #[test]
+    fn run_closures() {
+        assert_last_popped(r#"
+            let newClosure = fn(a) { fn() { a; }; };
+            let closure = newClosure(99);
+            closure();
+        "#, Object::Integer(99));
+        assert_last_popped(r#"
+            let newAdder = fn(x) { fn(y) { x + y; }; };
+            let addTwo = newAdder(2);
+            addTwo(3);
+        "#, Object::Integer(5));
+        assert_last_popped(r#"
+            let newAdder = fn(a, b) { let c = a + b; fn(d) { c + d; }; };
+            let adder = newAdder(1, 2);
+            adder(8);
+        "#, Object::Integer(11));
+        assert_last_popped(r#"
+            let newAdderOuter = fn(a, b) {
+                let c = a + b;
+                fn(d) {
+                    let e = d + c;
+                    fn(f) { e + f; };
+                };
+            };
+            let newAdderInner = newAdderOuter(1, 2);
+            let adder = newAdderInner(3);
+            adder(8);
+        "#, Object::Integer(14));
+        assert_last_popped(r#"
+            let a = 1;
+            let newAdderOuter = fn(b) { fn(c) { fn(d) { a + b + c + d; }; }; };
+            newAdderOuter(2)(3)(8);
+        "#, Object::Integer(14));
+        assert_last_popped(r#"
+            let newClosure = fn(a, b) {
+                let one = fn() { a; };
+                let two = fn() { b; };
+                fn() { one() + two(); };
+            };
+            let closure = newClosure(9, 90);
+            closure();
+        "#, Object::Integer(99));
+    }
+
+    #[test]
+    fn run_recursive_functions() {
+        assert_last_popped(
+            "let factorial = fn(n) { if (n < 2) { return 1; }; n * factorial(n - 1); }; factorial(10);",
+            Object::Integer(3628800)
+        );
+        assert_last_popped(
+            "let fibonacci = fn(x) { if (x < 2) { return x; }; fibonacci(x - 1) + fibonacci(x - 2); }; fibonacci(15);",
+            Object::Integer(610)
+        );
+        // a local function, which can only refer to itself through the closure being run
+        assert_last_popped(
+            "let wrapper = fn() { let countdown = fn(x) { if (x == 0) { return 0; }; countdown(x - 1); }; countdown(5); }; wrapper();",
+            Object::Integer(0)
+        );
+        // a closure created inside the recursive function captures it like any other local
+        assert_last_popped(
+            "let sum = fn(n) { if (n == 0) { return 0; }; let next = fn() { sum(n - 1); }; n + next(); }; sum(4);",
+            Object::Integer(10)
+        );
+        // a parameter with the same name shadows the function
+        assert_last_popped("let f = fn(f) { f; }; f(3);", Object::Integer(3));
+        // the name can't be reassigned inside the body, there's no slot for it
+        assert_eq!(
+            Err(crate::compiler::CompileError::InvalidAssignment(String::from("f"))),
+            compile_from_source("let f = fn() { f = 1; };")
+        );
+    }
+
+    #[test]
+    fn run_recursive_closures_are_freed() {
+        let run = |iterations: usize| {
+            let input = format!(r#"
+                let make = fn(k) {{ let countdown = fn(n) {{ n == 0 ? k : countdown(n - 1); }}; countdown; }};
+                let i = 0;
+                let last = 0;
+                while (i < {}) {{
+                    let f = make(i);
+                    let g = fn(n) {{ let next = fn() {{ g(n - 1); }}; n == 0 ? 0 : next(); }};
+                    last = f(10) + g(10);
+                    i++;
+                }}
+                [last, make(7)];
+            "#, iterations);
+            let mut vm = VM::new(compile_from_source(&input).expect("compile"));
+            vm.run().expect("vm run");
+            vm
+        };
+
+        // the objects reachable from the vm's slots, along with how many references to each were found,
+        // either from a slot or from the free variables of a closure
+        fn live_objects<'a>(vm: &'a VM) -> Vec<(&'a Rc<Object>, usize)> {
+            fn visit<'a>(reference: &'a Rc<Object>, objects: &mut Vec<(&'a Rc<Object>, usize)>) {
+                match objects.iter_mut().find(|(object, _)| Rc::ptr_eq(object, reference)) {
+                    Some((_, count)) => *count += 1,
+                    None => {
+                        objects.push((reference, 1));
+                        visit_contents(reference, objects);
+                    },
+                }
+            }
+            fn visit_contents<'a>(object: &'a Object, objects: &mut Vec<(&'a Rc<Object>, usize)>) {
+                match object {
+                    Object::Closure { free, .. } => free.iter().for_each(|reference| visit(reference, objects)),
+                    Object::Array(elements) => elements.iter().for_each(|element| visit_contents(element, objects)),
+                    Object::Hash(hash) => hash.values().for_each(|value| visit_contents(value, objects)),
+                    _ => {},
+                }
+            }
+
+            let slots = vm.stack.iter()
+                .chain(vm.globals.iter())
+                .chain(vm.constants.iter())
+                .chain(vec![&vm.last_popped, &vm.null, &vm.true_object, &vm.false_object]);
+            let mut objects = Vec::new();
+            for slot in slots {
+                visit(slot, &mut objects);
+            }
+
+            objects
+        }
+
+        let (fewer, more) = (run(500), run(1000));
+        for vm in [&fewer, &more] {
+            // a reference held by anything unreachable from a slot, such as a closure holding on to itself, is a leak
+            for (object, slots) in live_objects(vm) {
+                assert_eq!(slots, Rc::strong_count(object), "{:?} is referenced from outside the vm", object);
+            }
+        }
+        // and running the loop for longer doesn't leave any more objects alive
+        assert_eq!(live_objects(&fewer).len(), live_objects(&more).len());
+    }
+
+    #[test]
+    fn run_mutually_recursive_functions() {
+        let is_even_odd = "
+            let isEven = fn(n) { if (n == 0) { return true; }; isOdd(n - 1); };
+            let isOdd = fn(n) { if (n == 0) { return false; }; isEven(n - 1); };
+        ";
+        assert_last_popped(&format!("{} isEven(10);", is_even_odd), Object::Boolean(true));
+        assert_last_popped(&format!("{} isOdd(7);", is_even_odd), Object::Boolean(true));
+        assert_last_popped(&format!("{} isEven(7);", is_even_odd), Object::Boolean(false));
+        // a global defined later is only read when the function runs
+        assert_last_popped("let f = fn() { x; }; let x = 3; f();", Object::Integer(3));
+        // a function body still sees an earlier definition of a name until it is redefined
+        assert_last_popped("let x = 1; let f = fn() { x; }; let x = 2; f();", Object::Integer(1));
+    }
+
+    #[test]
+    fn run_integer_literals() {
+        assert_last_popped("0xFF;", Object::Integer(255));
+        assert_last_popped("0b1010;", Object::Integer(10));
+        assert_last_popped("1_000_000 + 0x10;", Object::Integer(1000016));
+        assert_last_popped("-0x7FFF_FFFF;", Object::Integer(-2147483647));
+    }
+
+    #[test]
+    fn run_bitwise() {
+        assert_last_popped("6 & 3;", Object::Integer(2));
+        assert_last_popped("6 | 1;", Object::Integer(7));
+        assert_last_popped("5 ^ 1;", Object::Integer(4));
+        assert_last_popped("1 << 4;", Object::Integer(16));
+        assert_last_popped("256 >> 2;", Object::Integer(64));
+        assert_last_popped("-16 >> 2;", Object::Integer(-4));
+        assert_last_popped("1 << 31;", Object::Integer(i32::MIN));
+        assert_last_popped("0xF0 | 0x0F & 0b0110;", Object::Integer(0xF6));
+        assert_last_popped("1 << 2 + 1;", Object::Integer(8));
+        assert_last_popped("(6 & 3) == 2;", Object::Boolean(true));
+        assert_last_popped("1 << 32;", Object::Error(String::from("shift count out of range: 32")));
+        assert_last_popped("1 >> -1;", Object::Error(String::from("shift count out of range: -1")));
+        assert_last_popped("true & 1;", Object::Error(String::from("unsupported types for OpBitAnd: BOOLEAN and INTEGER")));
+    }
+
+    #[test]
+    fn run_destructure() {
+        assert_last_popped("let [a, b] = [1, 2]; a - b;", Object::Integer(-1));
+        assert_last_popped("let [a, [b, c]] = [1, [2, 3]]; [c, b, a];", Object::Array(vec![
+            Object::Integer(3),
+            Object::Integer(2),
+            Object::Integer(1),
+        ]));
+        assert_last_popped("let pair = fn(x) { [x, x * 2]; }; let [one, two] = pair(5); one + two;", Object::Integer(15));
+        assert_last_popped("let f = fn(xs) { let [first, second] = xs; second; }; f([4, 5]);", Object::Integer(5));
+        assert_last_popped("let [] = []; 1;", Object::Integer(1));
+        assert_last_popped(
+            "let [a, b] = [1, 2, 3]; a;",
+            Object::Error(String::from("wrong number of values to destructure: want=2, got=3"))
+        );
+        assert_last_popped(
+            "let [a, [b, c]] = [1, [2]]; a;",
+            Object::Error(String::from("wrong number of values to destructure: want=2, got=1"))
+        );
+        assert_last_popped("let [a] = 1; a;", Object::Error(String::from("unsupported type for OpDestructure: INTEGER")));
+    }
+
+    #[test]
+    fn run_ternary() {
+        assert_last_popped("true ? 1 : 2;", Object::Integer(1));
+        assert_last_popped("false ? 1 : 2;", Object::Integer(2));
+        assert_last_popped("let a = 3; let b = 7; a > b ? a : b;", Object::Integer(7));
+        assert_last_popped("let sign = fn(x) { x > 0 ? 1 : x < 0 ? -1 : 0; }; [sign(5), sign(-5), sign(0)];", Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(-1),
+            Object::Integer(0),
+        ]));
+        // only the branch taken is evaluated
+        assert_last_popped("let x = 0; true ? x++ : x--; false ? x++ : x--; false ? x++ : x--; x;", Object::Integer(-1));
+        assert_last_popped("let calls = 0; let f = fn() { calls += 1; }; true ? 1 : f(); calls;", Object::Integer(0));
+        assert_run_error("1 ? 2 : 3;", VMError::TypeMismatch(String::from("condition must be BOOLEAN, got INTEGER")));
+    }
+
+    #[test]
+    fn run_conditions_must_be_boolean() {
+        let mismatch = |type_name: &str| VMError::TypeMismatch(format!("condition must be BOOLEAN, got {}", type_name));
+
+        assert_run_error("if (5) { 1; };", mismatch("INTEGER"));
+        assert_run_error("if (0) { 1; } else { 2; };", mismatch("INTEGER"));
+        assert_run_error("if (if (false) { 1; }) { 1; };", mismatch("NULL"));
+        assert_run_error(r#"if ("") { 1; };"#, mismatch("STRING"));
+        assert_run_error("if ([]) { 1; };", mismatch("ARRAY"));
+        assert_run_error("let i = 3; while (i) { i--; }", mismatch("INTEGER"));
+        assert_run_error("1 && true;", mismatch("INTEGER"));
+        assert_last_popped("if (1 > 0) { 1; } else { 2; };", Object::Integer(1));
+    }
+
+    #[test]
+    fn run_builtin_len() {
+        assert_last_popped(r#"len("");"#, Object::Integer(0));
+        assert_last_popped(r#"len("hello");"#, Object::Integer(5));
+        assert_last_popped("len([1, 2, 3]);", Object::Integer(3));
+        assert_last_popped("len([]);", Object::Integer(0));
+        assert_last_popped("let f = fn(a) { len(a); }; f([1]) + 1;", Object::Integer(2));
+        assert_last_popped("len(1);", Object::Error(String::from("argument to `len` not supported, got INTEGER")));
+        assert_last_popped(r#"len("one", "two");"#, Object::Error(String::from("wrong number of arguments. got=2, want=1")));
+    }
+
+    #[test]
+    fn run_builtin_type() {
+        let string = |s: &str| Object::String(String::from(s));
+
+        assert_last_popped("type(1);", string("INTEGER"));
+        assert_last_popped(r#"type("a");"#, string("STRING"));
+        assert_last_popped("type([1]);", string("ARRAY"));
+        assert_last_popped("type({1: 2});", string("HASH"));
+        assert_last_popped("type(1 < 2);", string("BOOLEAN"));
+        assert_last_popped("type(if (false) { 1; });", string("NULL"));
+        assert_last_popped("type(fn() { 1; });", string("FUNCTION"));
+        assert_last_popped("let x = 1; type(fn() { x; });", string("FUNCTION"));
+        assert_last_popped("type(len);", string("BUILTIN"));
+        assert_last_popped("type();", Object::Error(String::from("wrong number of arguments. got=0, want=1")));
+    }
+
+    #[test]
+    fn run_builtin_str() {
+        let string = |s: &str| Object::String(String::from(s));
+
+        assert_last_popped("str(42);", string("42"));
+        assert_last_popped("str(true);", string("true"));
+        assert_last_popped("str([1, 2]);", string("[1, 2]"));
+        assert_last_popped(r#"str({"a": 1});"#, string(r#"{"a": 1}"#));
+        assert_last_popped(r#"let n = 3; "count: " + str(n);"#, string("count: 3"));
+        assert_last_popped("str();", Object::Error(String::from("wrong number of arguments. got=0, want=1")));
+    }
+
+    #[test]
+    fn run_builtin_int() {
+        assert_last_popped(r#"int("42");"#, Object::Integer(42));
+        assert_last_popped("int(3.9);", Object::Integer(3));
+        assert_last_popped("int(str(12)) + 1;", Object::Integer(13));
+        assert_last_popped(r#"int("abc");"#, Object::Error(String::from(r#"could not parse "abc" as INTEGER"#)));
+    }
+
+    #[test]
+    fn run_builtin_keys_values() {
+        let hash = r#"let h = {"one": 1, "two": 2, "three": 3};"#;
+        assert_last_popped(&format!("{} len(keys(h));", hash), Object::Integer(3));
+        assert_last_popped(&format!("{} len(values(h));", hash), Object::Integer(3));
+        assert_last_popped(&format!("{} h[keys(h)[0]] == values(h)[0];", hash), Object::Boolean(true));
+        assert_last_popped("keys({2: 0, 1: 0});", Object::Array(vec![Object::Integer(1), Object::Integer(2)]));
+        assert_last_popped("values([]);", Object::Error(String::from("argument to `values` must be HASH, got ARRAY")));
+    }
+
+    #[test]
+    fn run_builtin_contains() {
+        assert_last_popped("contains([1, 2, 3], 2);", Object::Boolean(true));
+        assert_last_popped("contains([1, 2, 3], 4);", Object::Boolean(false));
+        assert_last_popped(r#"contains({"a": 1}, "a");"#, Object::Boolean(true));
+        assert_last_popped(r#"contains({"a": 1}, "b");"#, Object::Boolean(false));
+        assert_last_popped("contains(1, 1);", Object::Error(String::from("argument to `contains` must be ARRAY or HASH, got INTEGER")));
+    }
+
+    #[test]
+    fn run_builtin_higher_order_functions() {
+        assert_last_popped("map([1, 2, 3], fn(x) { x * 2; });", Object::Array(vec![
+            Object::Integer(2),
+            Object::Integer(4),
+            Object::Integer(6),
+        ]));
+        assert_last_popped("reduce([1, 2, 3, 4], fn(sum, x) { sum + x; }, 0);", Object::Integer(10));
+        assert_last_popped("let odd = fn(x) { x % 2 == 1; }; filter([1, 2, 3], odd);", Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(3),
+        ]));
+        // closures keep their free variables, and builtins can be passed too
+        assert_last_popped("let n = 10; map([1, 2], fn(x) { x + n; });", Object::Array(vec![
+            Object::Integer(11),
+            Object::Integer(12),
+        ]));
+        assert_last_popped(r#"map(["a", "bc"], len);"#, Object::Array(vec![Object::Integer(1), Object::Integer(2)]));
+        // functions called by builtins can call builtins which call functions
+        assert_last_popped(
+            "map([[1, 2], [3]], fn(xs) { reduce(map(xs, fn(x) { x * x; }), fn(a, b) { a + b; }, 0); });",
+            Object::Array(vec![Object::Integer(5), Object::Integer(9)])
+        );
+        // the rest of the program carries on with the stack as it was before the call
+        assert_last_popped("let f = fn(xs) { 1 + len(map(xs, fn(x) { x; })) + 2; }; [f([1, 2]), f([])];", Object::Array(vec![
+            Object::Integer(5),
+            Object::Integer(3),
+        ]));
+        assert_last_popped("let f = fn() { return map([1], fn(x) { return x; }); }; f();", Object::Array(vec![Object::Integer(1)]));
+        // an error object from inside the function halts the whole program
+        assert_last_popped(
+            r#"map([1, "a"], fn(x) { -x; }); 5;"#,
+            Object::Error(String::from("unsupported type for OpMinus: STRING"))
+        );
+        assert_run_error("map([1], fn() { 1; });", VMError::WrongArgumentCount { expected: 0, given: 1 });
+        assert_run_error("map([1], fn(x) { x / 0; });", VMError::DivisionByZero);
+    }
+
+    #[test]
+    fn run_builtin_reverse() {
+        assert_last_popped("reverse([1, 2, 3]);", Object::Array(vec![Object::Integer(3), Object::Integer(2), Object::Integer(1)]));
+        assert_last_popped(r#"reverse("abc");"#, Object::String(String::from("cba")));
+        assert_last_popped(r#"reverse("héllo");"#, Object::String(String::from("olléh")));
+        assert_last_popped("let a = [1, 2]; reverse(a); a;", Object::Array(vec![Object::Integer(1), Object::Integer(2)]));
+        assert_last_popped("reverse(1);", Object::Error(String::from("argument to `reverse` must be ARRAY or STRING, got INTEGER")));
+    }
+
+    #[test]
+    fn run_builtin_sort() {
+        let integers = |integers: &[i32]| Object::Array(integers.iter().map(|&integer| Object::Integer(integer)).collect());
+
+        assert_last_popped("sort([3, 1, 2]);", integers(&[1, 2, 3]));
+        assert_last_popped(r#"sort(["pear", "apple", "fig"]);"#, Object::Array(vec![
+            Object::String(String::from("apple")),
+            Object::String(String::from("fig")),
+            Object::String(String::from("pear")),
+        ]));
+        assert_last_popped("sort([1, 4, 2, 3], fn(a, b) { a > b; });", integers(&[4, 3, 2, 1]));
+        assert_last_popped("sort([1, 4, 2, 3], fn(a, b) { b - a; });", integers(&[4, 3, 2, 1]));
+        // the comparator only looks at the tens, so equal elements keep their order
+        assert_last_popped("sort([21, 12, 23, 11], fn(a, b) { a / 10 < b / 10; });", integers(&[12, 11, 21, 23]));
+        assert_last_popped("let a = [2, 1]; let b = sort(a); [a, b];", Object::Array(vec![
+            integers(&[2, 1]),
+            integers(&[1, 2]),
+        ]));
+        assert_last_popped(
+            r#"sort([1, "a"]);"#,
+            Object::Error(String::from("elements passed to `sort` can't be compared, got STRING and INTEGER"))
+        );
+        assert_last_popped(
+            r#"sort([1, 2], fn(a, b) { "yes"; });"#,
+            Object::Error(String::from("function passed to `sort` must return BOOLEAN or INTEGER, got STRING"))
+        );
+        assert_last_popped(
+            r#"sort([1, "a"], fn(a, b) { a < b; }); 5;"#,
+            Object::Error(String::from("unsupported types for OpLessThan: STRING and INTEGER"))
+        );
+    }
+
+    #[test]
+    fn run_builtin_split_join() {
+        assert_last_popped(r#"split("a,b,c", ",");"#, Object::Array(vec![
+            Object::String(String::from("a")),
+            Object::String(String::from("b")),
+            Object::String(String::from("c")),
+        ]));
+        assert_last_popped(r#"join(["a", "b"], "-");"#, Object::String(String::from("a-b")));
+        assert_last_popped(r#"join(split("1 2 3", " "), "+");"#, Object::String(String::from("1+2+3")));
+        assert_last_popped(r#"len(split("", ""));"#, Object::Integer(0));
+    }
+
+    #[test]
+    fn run_builtin_range() {
+        assert_last_popped("range(0, 5);", Object::Array((0..5).map(Object::Integer).collect()));
+        assert_last_popped("range(3);", Object::Array((0..3).map(Object::Integer).collect()));
+        assert_last_popped("range(0, 10, 5);", Object::Array(vec![Object::Integer(0), Object::Integer(5)]));
+        assert_last_popped("reduce(range(1, 5), fn(product, x) { product * x; }, 1);", Object::Integer(24));
+        assert_last_popped("range(3, 3);", Object::Array(vec![]));
+    }
+
+    #[test]
+    fn run_builtin_assert() {
+        assert_last_popped("assert(1 == 1); 5;", Object::Integer(5));
+        assert_last_popped(r#"assert(1 == 2, "nope"); 5;"#, Object::Error(String::from("nope")));
+        assert_last_popped("let f = fn(x) { assert(x > 0); x; }; f(1) + f(-1);", Object::Error(String::from("assertion failed")));
+    }
+
+    #[test]
+    fn run_builtin_by_index() {
+        let len_index = builtins::BUILTINS.iter().position(|&(name, _)| name == "len").expect("len should be a builtin");
+        let instructions = vec![
+            OpCode::OpGetBuiltin(len_index as u8),
+            OpCode::OpConstant(0),
+            OpCode::OpCall(1),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![Object::String(String::from("four"))], lines: LineTable::default() });
+        vm.run().expect("vm run");
+        assert_eq!(&Object::Integer(4), vm.last_popped());
+    }
+
+    #[test]
+    fn run_builtin_array_functions() {
+        assert_last_popped("first([1, 2, 3]);", Object::Integer(1));
+        assert_last_popped("first([]);", Object::Null);
+        assert_last_popped("last([1, 2, 3]);", Object::Integer(3));
+        assert_last_popped("last([]);", Object::Null);
+        assert_last_popped("rest([1, 2, 3]);", Object::Array(vec![Object::Integer(2), Object::Integer(3)]));
+        assert_last_popped("rest([]);", Object::Null);
+        assert_last_popped("push([], 1);", Object::Array(vec![Object::Integer(1)]));
+        assert_last_popped("first(1);", Object::Error(String::from("argument to `first` must be ARRAY, got INTEGER")));
+    }
+
+    #[test]
+    fn run_builtin_push_does_not_mutate() {
+        assert_last_popped("let a = [1, 2]; let b = push(a, 3); a;", Object::Array(vec![Object::Integer(1), Object::Integer(2)]));
+        assert_last_popped("let a = [1, 2]; let b = push(a, 3); b;", Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+        ]));
+    }
+
+    #[test]
+    fn run_builtin_puts() {
+        let output = SharedOutput::default();
+        let mut vm = VM::new_with_output(compile_from_source(r#"puts("hi", 42);"#).expect("compile"), Box::new(output.clone()));
+        vm.run().expect("vm run");
+
+        assert_eq!(&Object::Null, vm.last_popped());
+        assert_eq!("hi\n42\n", output.contents());
+    }
+
+    #[test]
+    fn run_assign() {
+        assert_last_popped("let x = 1; x = 5; x;", Object::Integer(5));
+        assert_last_popped("let x = 1; let f = fn() { x = x + 1; }; f(); f(); x;", Object::Integer(3));
+        assert_last_popped("let f = fn(a) { let b = a; b = b * 2; a = a + b; a; }; f(3);", Object::Integer(9));
+        assert_last_popped(
+            "let i = 0; let sum = 0; while (i < 5) { sum = sum + i; i = i + 1; } sum;",
+            Object::Integer(10)
+        );
+    }
+
+    #[test]
+    fn run_compound_assign() {
+        assert_last_popped("let x = 10; x += 5; x;", Object::Integer(15));
+        assert_last_popped("let x = 10; x += 5; x /= 3; x;", Object::Integer(5));
+        assert_last_popped("let x = 2; x *= 3 + 1; x -= 1; x;", Object::Integer(7));
+        assert_last_popped(r#"let s = "a"; s += "b"; s;"#, Object::String(String::from("ab")));
+        assert_last_popped(
+            "let sum = fn(n) { let total = 0; while (n > 0) { total += n; n -= 1; } total; }; sum(4);",
+            Object::Integer(10)
+        );
+    }
+
+    #[test]
+    fn run_postfix() {
+        assert_last_popped("let i = 0; i++; i;", Object::Integer(1));
+        assert_last_popped("let i = 0; i++;", Object::Integer(0));
+        assert_last_popped("let i = 5; i--; i-- * 10;", Object::Integer(40));
+        assert_last_popped("let sum = 0; let i = 0; while (i < 4) { sum = sum + i++; } [sum, i];", Object::Array(vec![
+            Object::Integer(6),
+            Object::Integer(4),
+        ]));
+        assert_last_popped("let f = fn(n) { n++; n++; n; }; f(1);", Object::Integer(3));
+    }
+
+    #[test]
+    fn run_while() {
+        assert_last_popped("while (false) { 1; } 2;", Object::Integer(2));
+        assert_last_popped("while (true) { return 3; }", Object::Integer(3));
+        assert_last_popped("let f = fn(x) { while (x > 0) { return x * 2; } 0; }; [f(4), f(0)];", Object::Array(vec![
+            Object::Integer(8),
+            Object::Integer(0),
+        ]));
+        // a loop inside an if block is a statement, so the block has no value
+        assert_last_popped("if (true) { while (false) { 1; } };", Object::Null);
+    }
+
+    #[test]
+    fn run_break() {
+        assert_last_popped("let i = 0; while (true) { i++; if (i == 5) { break; }; } i;", Object::Integer(5));
+        // only the innermost loop is left
+        assert_last_popped(
+            "let i = 0; let n = 0; while (i < 3) { i++; while (true) { n++; break; } } [i, n];",
+            Object::Array(vec![Object::Integer(3), Object::Integer(3)]),
+        );
+        assert_last_popped(
+            "let f = fn(x) { let total = 0; while (true) { if (x == 0) { break; }; total += x; x -= 1; } total; }; f(4);",
+            Object::Integer(10),
+        );
+        // a break inside an expression doesn't leave that expression's operands behind
+        let input = "let i = 0; while (i < 5000) { i++; while (true) { let x = [1, 2, if (true) { break; } else { 0 }]; } } i;";
+        let mut vm = VM::new(compile_from_source(input).expect("compile")).with_stack_size(64);
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(5000), vm.result());
+        assert_last_popped(
+            "let f = fn() { let n = 0; while (true) { n++; n + pick(n, if (n == 3) { break; } else { 0 }); } n; }; let pick = fn(a, b) { a; }; f();",
+            Object::Integer(3),
+        );
+    }
+
+    #[test]
+    fn run_continue() {
+        assert_last_popped(
+            "let i = 0; let sum = 0; while (i < 10) { i++; if (i % 2 == 0) { continue; }; sum += i; } sum;",
+            Object::Integer(25),
+        );
+        // locals declared in the body before the continue don't pile up on the stack
+        let input = r#"
+            let sumOdds = fn(n) {
+                let i = 0;
+                let sum = 0;
+                while (i < n) {
+                    i++;
+                    let even = i % 2 == 0;
+                    if (even) { continue; };
+                    sum += i;
+                }
+                sum;
+            };
+            sumOdds(10000);
+        "#;
+        let mut vm = VM::new(compile_from_source(input).expect("compile")).with_stack_size(64);
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(25000000), vm.result());
+        // a continue inside an expression doesn't leave that expression's operands behind
+        let input = "let i = 0; while (i < 5000) { i++; let x = 1 + if (true) { continue; } else { 0 }; } i;";
+        let mut vm = VM::new(compile_from_source(input).expect("compile")).with_stack_size(64);
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(5000), vm.result());
+        assert_last_popped(
+            "let total = 0; for (x in [1, 2, 3]) { total += len([x, x, if (x == 2) { continue; } else { x }]); } total;",
+            Object::Integer(6),
+        );
+        // only the innermost loop is continued
+        assert_last_popped(
+            "let i = 0; let n = 0; while (i < 3) { i++; let j = 0; while (j < 3) { j++; continue; n++; } n++; } n;",
+            Object::Integer(3),
+        );
+    }
+
+    #[test]
+    fn run_for() {
+        assert_last_popped("let sum = 0; for (x in [1, 2, 3]) { sum += x; } sum;", Object::Integer(6));
+        assert_last_popped("let seen = []; for (x in range(0, 3)) { seen = push(seen, x); } seen;", Object::Array(vec![
+            Object::Integer(0),
+            Object::Integer(1),
+            Object::Integer(2),
+        ]));
+        assert_last_popped(
+            "let sum = 0; for (x in range(0, 10)) { if (x == 5) { break; }; if (x % 2 == 0) { continue; }; sum += x; } sum;",
+            Object::Integer(4),
+        );
+        assert_last_popped(
+            "let pairs = 0; for (x in [1, 2, 3]) { for (y in [x, x]) { pairs += y; } } pairs;",
+            Object::Integer(12),
+        );
+        assert_last_popped("let f = fn(a) { let total = 0; for (x in a) { total += x; } total; }; f([4, 5]);", Object::Integer(9));
+        assert_last_popped("let f = fn() { for (x in [7, 8]) { return x; } }; f();", Object::Integer(7));
+        // the array is read once, reassigning the variable it came from doesn't change the loop
+        assert_last_popped("let a = [1, 2]; let n = 0; for (x in a) { a = [1, 2, 3, 4]; n++; } n;", Object::Integer(2));
+        assert_last_popped("let x = 10; for (x in [1, 2]) { } x;", Object::Integer(10));
+        assert_last_popped("for (x in 5) { }", Object::Error(String::from("for loops iterate over ARRAY, got INTEGER")));
+    }
+
+    #[test]
+    fn run_for_clears_its_globals() {
+        for input in &["for (x in [[1], [2]]) { }", "for (x in [[1], [2]]) { break; }"] {
+            let mut vm = VM::new(compile_from_source(input).expect("compile"));
+            vm.run().expect("vm run");
+
+            // nothing but the loop has used the globals, so none of them should still hold the array
+            assert!(vm.into_globals().iter().all(|global| **global == Object::Null), "{}", input);
+        }
+    }
+
+    #[test]
+    fn run_logical_operators() {
+        assert_last_popped("true && true;", Object::Boolean(true));
+        assert_last_popped("true && false;", Object::Boolean(false));
+        assert_last_popped("false && true;", Object::Boolean(false));
+        assert_last_popped("false || false;", Object::Boolean(false));
+        assert_last_popped("false || true;", Object::Boolean(true));
+        assert_last_popped("true || false;", Object::Boolean(true));
+        assert_last_popped("1 < 2 && 2 < 3 || false;", Object::Boolean(true));
+    }
+
+    #[test]
+    fn run_logical_operators_short_circuit() {
+        let output = SharedOutput::default();
+        let input = r#"
+            let loud = fn(value) { puts("evaluated"); value; };
+            let a = false && loud(true);
+            let b = true || loud(false);
+            let c = true && loud(true);
+            let d = false || loud(false);
+            [a, b, c, d];
+        "#;
+        let mut vm = VM::new_with_output(compile_from_source(input).expect("compile"), Box::new(output.clone()));
+        vm.run().expect("vm run");
+
+        assert_eq!(
+            &Object::Array(vec![Object::Boolean(false), Object::Boolean(true), Object::Boolean(true), Object::Boolean(false)]),
+            vm.last_popped()
+        );
+        // only the last two calls could change the result, so only they run
+        assert_eq!("evaluated\nevaluated\n", output.contents());
+    }
+
+    /// an output which can still be read after a clone of it has been handed to the vm
+    #[derive(Clone, Default)]
+    struct SharedOutput(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).expect("output should be utf8")
+        }
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_empty_program() {
+        let byte_code = compile_from_source("").expect("compile");
+        assert!(byte_code.instructions.is_empty());
+
+        let mut vm = VM::new(byte_code);
+        vm.run().expect("vm run");
+        assert_eq!(Object::Null, vm.result());
+    }
+
+    #[test]
+    fn run_trailing_expression() {
+        assert_last_popped("5", Object::Integer(5));
+        assert_last_popped("let a = 2; a * 3", Object::Integer(6));
+        assert_last_popped("let f = fn(x) { x + 1 }; f(1)", Object::Integer(2));
+        assert_last_popped("if (true) { 10 } else { 20 }", Object::Integer(10));
+    }
+
+    #[test]
+    fn run_top_level_return() {
+        assert_last_popped("return 10; 11;", Object::Integer(10));
+        assert_last_popped("9; return 2 * 5; 9;", Object::Integer(10));
+    }
+
+    #[test]
+    fn run_function_call_frame_overflow() {
+        // a function which keeps calling itself through global 0
+        let function = Object::CompiledFunction(Rc::new(CompiledFunction {
+            instructions: vec![OpCode::OpGetGlobal(0), OpCode::OpCall(0)].into_iter().flat_map(make_op).collect(),
+            num_locals: 0,
+            num_parameters: 0,
+            lines: LineTable::default(),
+        }));
+        let instructions = vec![
+            OpCode::OpClosure(0, 0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpCall(0),
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![function], lines: LineTable::default() });
+        assert_eq!(Err(VMError::FrameOverflow), vm.run());
+    }
+
+    #[test]
+    fn run_dup() {
+        let instructions = vec![OpCode::OpConstant(0), OpCode::OpDup]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![Object::String(String::from("twice"))], lines: LineTable::default() });
+        vm.run().expect("vm run");
+        assert_eq!(2, vm.sp);
+        assert_eq!(Object::String(String::from("twice")), *vm.stack[0]);
+        // the duplicate shares the object rather than copying it
+        assert!(Rc::ptr_eq(&vm.stack[0], &vm.stack[1]));
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpDup), constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::StackUnderflow), vm.run());
+    }
+
+    #[test]
+    fn run_swap() {
+        let instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(1),
+            OpCode::OpSwap,
+            OpCode::OpSub,
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        // 10 - 3 becomes 3 - 10 once the operands are swapped
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![Object::Integer(10), Object::Integer(3)], lines: LineTable::default() });
+        vm.run().expect("vm run");
+        assert_eq!(&Object::Integer(-7), vm.last_popped());
+
+        let instructions = vec![OpCode::OpConstant(0), OpCode::OpSwap]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![Object::Integer(1)], lines: LineTable::default() });
+        assert_eq!(Err(VMError::StackUnderflow), vm.run());
+    }
+
+    #[test]
+    fn run_high_global_index() {
+        let instructions = vec![
+            OpCode::OpGetGlobal(60000),
+            OpCode::OpPop,
+            OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(60000),
+            OpCode::OpGetGlobal(60000),
+            OpCode::OpPop,
+        ]
+            .into_iter()
+            .flat_map(make_op)
+            .collect();
+
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![Object::Integer(7)], lines: LineTable::default() });
+        vm.run().expect("vm run");
+        assert_eq!(&Object::Integer(7), vm.last_popped());
+
+        let globals = vm.into_globals();
+        assert_eq!(60001, globals.len());
+        assert_eq!(Object::Null, *globals[59999]);
+    }
+
+    #[test]
+    fn run_stack_overflow() {
+        // every level of nesting leaves one more operand waiting on the stack
+        let depth = STACK_SIZE + 1;
+        // operands are read from a variable, literals would be folded into a single constant
+        let input = format!("let a = 1; {}a{};", "a + (".repeat(depth), ")".repeat(depth));
+
+        // parsing and compiling recurse once per level, which needs more than the default test thread stack
+        // bytecode shares its functions through Rc, so it's run on the same thread it was compiled on
+        let result = std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(move || VM::new(compile_from_source(&input).expect("compile")).run())
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(Err(at_line(1, VMError::StackOverflow)), result);
+    }
+
+    #[test]
+    fn run_stack_size() {
+        let input = "let sum = fn(n) { n == 0 ? 0 : n + sum(n - 1); }; sum(100);";
+
+        let mut vm = VM::new(compile_from_source(input).expect("compile")).with_stack_size(64);
+        assert_eq!(Err(at_line(1, VMError::StackOverflow)), vm.run());
+
+        let mut vm = VM::new(compile_from_source(input).expect("compile")).with_stack_size(512);
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(5050), vm.result());
+
+        // deeper than the default stack allows
+        let input = "let sum = fn(n) { n == 0 ? 0 : n + sum(n - 1); }; sum(5000);";
+        let mut vm = VM::new(compile_from_source(input).expect("compile"));
+        assert_eq!(Err(at_line(1, VMError::StackOverflow)), vm.run());
+
+        let mut vm = VM::new(compile_from_source(input).expect("compile")).with_stack_size(1 << 16);
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(12502500), vm.result());
+    }
+
+    #[test]
+    fn run_tail_calls() {
+        // far deeper than the default stack could hold frames for
+        assert_last_popped(
+            "let countdown = fn(n) { n == 0 ? 0 : countdown(n - 1); }; countdown(100000);",
+            Object::Integer(0),
+        );
+        assert_last_popped(
+            "let sum = fn(n, total) { if (n == 0) { return total; }; sum(n - 1, total + n); }; sum(10000, 0);",
+            Object::Integer(50005000),
+        );
+        // locals from the previous run of the function don't leak into the next one
+        assert_last_popped(
+            "let f = fn(n, acc) { let doubled = acc * 2; n == 0 ? doubled : f(n - 1, doubled); }; f(3, 1);",
+            Object::Integer(16),
+        );
+        // a call which isn't in tail position still needs a frame of its own
+        assert_run_error(
+            "let sum = fn(n) { n == 0 ? 0 : n + sum(n - 1); }; sum(100000);",
+            VMError::StackOverflow,
+        );
+        assert_run_error(
+            "let f = fn(n) { n == 0 ? 0 : f(); }; f(1);",
+            VMError::WrongArgumentCount { expected: 1, given: 0 },
+        );
+    }
+
+    #[test]
+    fn run_errors() {
+        assert_run_error("1();", VMError::NotCallable("INTEGER"));
+        assert_last_popped("-true;", Object::Error(String::from("unsupported type for OpMinus: BOOLEAN")));
+        assert_last_popped("[1] + 1;", Object::Error(String::from("unsupported types for OpAdd: ARRAY and INTEGER")));
+
+        let mut vm = VM::new(ByteCode { instructions: vec![0xFF], constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::UnknownOpcode(0xFF)), vm.run());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpPop), constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::StackUnderflow), vm.run());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpConstant(1)), constants: vec![Object::Integer(1)], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidConstantIndex(1)), vm.run());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpClosure(0, 0)), constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidConstantIndex(0)), vm.run());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpJump(3)), constants: vec![], lines: LineTable::default() });
+        assert_eq!(Ok(()), vm.run());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpJump(4)), constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidJumpTarget(4)), vm.run());
+
+        let instructions = vec![OpCode::OpFalse, OpCode::OpJumpNotTrue(500)].into_iter().flat_map(make_op).collect();
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidJumpTarget(500)), vm.run());
+
+        // operands which don't refer to anything are errors rather than panics, however the bytecode was made
+        let instructions = vec![OpCode::OpGetBuiltin(200), OpCode::OpCall(0)].into_iter().flat_map(make_op).collect();
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidBuiltin(200)), vm.run());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpGetLocal(0)), constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidLocal(0)), vm.run());
+
+        let instructions = vec![OpCode::OpTrue, OpCode::OpSetLocal(200)].into_iter().flat_map(make_op).collect();
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidLocal(200)), vm.run());
+
+        let function = CompiledFunction {
+            instructions: vec![OpCode::OpGetFree(3), OpCode::OpReturnValue].into_iter().flat_map(make_op).collect(),
+            num_locals: 0,
+            num_parameters: 0,
+            lines: LineTable::default(),
+        };
+        let instructions = vec![OpCode::OpClosure(0, 0), OpCode::OpCall(0)].into_iter().flat_map(make_op).collect();
+        let mut vm = VM::new(ByteCode { instructions, constants: vec![Object::CompiledFunction(Rc::new(function))], lines: LineTable::default() });
+        assert_eq!(Err(VMError::InvalidFree(3)), vm.run());
+
+        // the same goes for bytecode which was read back from a file
+        let instructions = vec![OpCode::OpGetBuiltin(200), OpCode::OpCall(0)].into_iter().flat_map(make_op).collect();
+        let serialized = ByteCode { instructions, constants: vec![], lines: LineTable::default() }.serialize();
+        let mut vm = VM::new(ByteCode::deserialize(&serialized).expect("deserialize"));
+        assert_eq!(Err(VMError::InvalidBuiltin(200)), vm.run());
+    }
+
+    #[test]
+    fn run_source_file() {
+        let dir = std::env::temp_dir().join(format!("monkey-run-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+
+        let path = dir.join("answer.monkey");
+        std::fs::write(&path, "let x = 2; x * 21;").expect("write file");
+        assert_eq!(Object::Integer(42), run_file(&path).expect("run file"));
+
+        let path = dir.join("error.monkey");
+        std::fs::write(&path, "let x = 2;\nx();").expect("write file");
+        let err = run_file(&path).expect_err("calling an integer");
+        assert_eq!(format!("{}: line 2: calling non-function: INTEGER", path.display()), err.to_string());
+
+        let path = dir.join("dump.monkey");
+        std::fs::write(&path, "let f = fn(x) { x * 21; };\nf(2);").expect("write file");
+        let disassembled = compile_file(&path).expect("compile file").disassemble();
+        for mnemonic in &["OpClosure 1 0", "OpSetGlobal 0", "OpCall 1", "OpGetLocal 0", "OpMul", "OpReturnValue"] {
+            assert!(disassembled.contains(mnemonic), "{} missing from\n{}", mnemonic, disassembled);
+        }
+
+        let path = dir.join("syntax.monkey");
+        std::fs::write(&path, "let x = 2;\nlet = 5;").expect("write file");
+        match run_file(&path) {
+            Err(err @ RunFileError::Parse { .. }) => {
+                assert_eq!(format!("{}: unexpected ASSIGN in let statement", path.display()), err.to_string());
+            },
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+
+        let path = dir.join("missing.monkey");
+        match run_file(&path) {
+            Err(RunFileError::Read { path: error_path, .. }) => assert_eq!(path, error_path),
+            result => panic!("expected a read error, got {:?}", result),
+        }
+
+        std::fs::remove_dir_all(&dir).expect("remove temp dir");
+    }
+
+    #[test]
+    fn run_constants_are_shared() {
+        let mut vm = VM::new(ByteCode {
+            instructions: vec![OpCode::OpConstant(0), OpCode::OpConstant(0)].into_iter().flat_map(make_op).collect(),
+            constants: vec![Object::String(String::from("monkey"))],
+            lines: LineTable::default(),
+        });
+        vm.run().expect("vm run");
+
+        assert!(Rc::ptr_eq(&vm.constants[0], &vm.stack[0]));
+        assert!(Rc::ptr_eq(&vm.constants[0], &vm.stack[1]));
+    }
+
+    #[test]
+    fn run_pop_n() {
+        let byte_code = |instructions: Vec<OpCode>| ByteCode {
+            instructions: instructions.into_iter().flat_map(make_op).collect(),
+            constants: vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)],
+            lines: LineTable::default(),
+        };
+
+        let mut vm = VM::new(byte_code(vec![
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(1),
+            OpCode::OpConstant(2),
+            OpCode::OpPopN(2),
+        ]));
+        vm.run().expect("vm run");
+        assert_eq!(1, vm.sp);
+        assert_eq!(Object::Integer(1), *vm.stack[0]);
+        assert_eq!(Object::Null, *vm.stack[1]);
+        // nothing discarded by OpPopN counts as popped
+        assert_eq!(Object::Null, vm.result());
+
+        let mut vm = VM::new(byte_code(vec![OpCode::OpConstant(0), OpCode::OpPopN(0), OpCode::OpPop]));
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(1), vm.result());
+
+        let mut vm = VM::new(byte_code(vec![OpCode::OpConstant(0), OpCode::OpPopN(2)]));
+        assert_eq!(Err(VMError::StackUnderflow), vm.run());
+    }
+
+    #[test]
+    fn run_truncated_instructions() {
+        let truncated = |op: OpCode, length: usize| {
+            let mut instructions = make_op(op);
+            instructions.truncate(length);
+            VM::new(ByteCode { instructions, constants: vec![Object::Integer(1)], lines: LineTable::default() }).run()
+        };
+
+        assert_eq!(Err(VMError::MissingOperand(1)), truncated(OpCode::OpConstant(0), 2));
+        assert_eq!(Err(VMError::MissingOperand(1)), truncated(OpCode::OpConstant(0), 1));
+        assert_eq!(Err(VMError::MissingOperand(1)), truncated(OpCode::OpJump(0), 2));
+        assert_eq!(Err(VMError::MissingOperand(1)), truncated(OpCode::OpGetLocal(0), 1));
+        assert_eq!(Err(VMError::MissingOperand(3)), truncated(OpCode::OpClosure(0, 0), 3));
+        assert_eq!(Ok(()), truncated(OpCode::OpConstant(0), 3));
+        assert_eq!("missing operand at 0001", VMError::MissingOperand(1).to_string());
+    }
+
+    #[test]
+    fn run_error_objects_halt() {
+        assert_last_popped("len(1); 5;", Object::Error(String::from("argument to `len` not supported, got INTEGER")));
+        assert_last_popped("1 + true; 5;", Object::Error(String::from("unsupported types for OpAdd: INTEGER and BOOLEAN")));
+        assert_last_popped(
+            "let f = fn() { 1 + true; 2; }; f(); 3;",
+            Object::Error(String::from("unsupported types for OpAdd: INTEGER and BOOLEAN"))
+        );
+
+        let mut vm = VM::new(compile_from_source("let a = [1] + 1; a;").expect("compile"));
+        vm.run().expect("vm run");
+        assert_eq!(Object::Error(String::from("unsupported types for OpAdd: ARRAY and INTEGER")), vm.result());
+    }
+
+    #[test]
+    fn run_traced() {
+        let output = SharedOutput::default();
+        let mut vm = VM::new_with_output(compile_from_source(r#"1; "a" + "b";"#).expect("compile"), Box::new(output.clone()));
+        vm.set_trace(true);
+        vm.run().expect("vm run");
+
+        assert_eq!(
+            concat!(
+                "0000 OpConstant 0 stack: []\n",
+                "0003 OpPop stack: [1]\n",
+                "0004 OpConstant 1 stack: []\n",
+                "0007 OpConstant 2 stack: [\"a\"]\n",
+                "0010 OpAdd stack: [\"a\", \"b\"]\n",
+                "0011 OpPop stack: [\"ab\"]\n",
+            ),
+            output.contents()
+        );
+    }
+
+    #[test]
+    fn run_instruction_limit() {
+        let mut vm = VM::new(compile_from_source("while (true) {}").expect("compile"));
+        vm.set_instruction_limit(Some(1000));
+        assert_eq!(Err(at_line(1, VMError::InstructionLimitExceeded(1000))), vm.run());
+
+        // "1; 2;" is exactly four instructions
+        let mut vm = VM::new(compile_from_source("1; 2;").expect("compile"));
+        vm.set_instruction_limit(Some(4));
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(2), vm.result());
+
+        let mut vm = VM::new(compile_from_source("1; 2;").expect("compile"));
+        vm.set_instruction_limit(Some(3));
+        assert_eq!(Err(at_line(1, VMError::InstructionLimitExceeded(3))), vm.run());
+        assert_eq!(Object::Integer(1), vm.result());
+    }
+
+    #[test]
+    fn run_negative_indexes() {
+        let run = |input: &str, negative_indexes: bool| {
+            let mut vm = VM::new(compile_from_source(input).expect("compile"));
+            vm.set_negative_indexes(negative_indexes);
+            vm.run().expect("vm run");
+            vm.result()
+        };
+
+        assert_eq!(Object::Null, run("[1, 2, 3][-1];", false));
+        assert_eq!(Object::Integer(3), run("[1, 2, 3][-1];", true));
+        assert_eq!(Object::Integer(1), run("[1, 2, 3][-3];", true));
+        assert_eq!(Object::Null, run("[1, 2, 3][-4];", true));
+        assert_eq!(Object::Null, run("[][-1];", true));
+        assert_eq!(Object::Integer(2), run("let a = [1, 2]; let i = -1; a[i];", true));
+        assert_eq!(Object::Null, run("[1, 2, 3][3];", true));
+    }
+
+    #[test]
+    fn run_result_accessors() {
+        let mut vm = VM::new(compile_from_source("let a = [1, 2]; a[1] * 3;").expect("compile"));
+        assert_eq!(Object::Null, vm.result());
+        assert_eq!(None, vm.stack_top());
+
+        vm.run().expect("vm run");
+        assert_eq!(Object::Integer(6), vm.result());
+        assert_eq!(None, vm.stack_top());
+
+        let mut vm = VM::new(ByteCode { instructions: make_op(OpCode::OpTrue), constants: vec![], lines: LineTable::default() });
+        vm.run().expect("vm run");
+        assert_eq!(Some(&Object::Boolean(true)), vm.stack_top());
+        assert_eq!(Object::Null, vm.result());
+    }
+
+    #[test]
+    fn run_from_bytecode_returns_result() {
+        assert_eq!(Ok(Object::Integer(3)), run_from_bytecode(compile_from_source("1; 2 + 1;").expect("compile")));
+        assert_eq!(Ok(Object::Null), run_from_bytecode(compile_from_source("let a = 1;").expect("compile")));
+        assert_eq!(
+            Err(at_line(1, VMError::DivisionByZero)),
+            run_from_bytecode(compile_from_source("1 / 0;").expect("compile"))
+        );
+    }
+
+    #[test]
+    fn run_error_lines() {
+        let input = "let a = 1;\nlet b = a / 0;";
+        assert_eq!(Err(at_line(2, VMError::DivisionByZero)), run_from_bytecode(compile_from_source(input).expect("compile")));
+        assert_eq!("line 2: division by zero", run_from_bytecode(compile_from_source(input).expect("compile")).unwrap_err().to_string());
+
+        // the line is the one inside the function, not where it was called from
+        let input = "
+            let divide = fn(a, b) {
+                a / b;
+            };
+            divide(1, 0);
+        ";
+        assert_eq!(Err(at_line(3, VMError::DivisionByZero)), run_from_bytecode(compile_from_source(input).expect("compile")));
+
+        let input = "let f = fn(x) {\n  x + 1;\n};\nmap([1, 2], f);\nf(1, 2);";
+        assert_eq!(
+            Err(at_line(5, VMError::WrongArgumentCount { expected: 1, given: 2 })),
+            run_from_bytecode(compile_from_source(input).expect("compile"))
+        );
+        let input = "reduce([1, 2],\n  fn(acc, x) {\n    acc / (x - 2);\n  }, 0);";
+        assert_eq!(Err(at_line(3, VMError::DivisionByZero)), run_from_bytecode(compile_from_source(input).expect("compile")));
+
+        let input = "if (true) {\n  1;\n  2 / 0;\n};";
+        assert_eq!(Err(at_line(3, VMError::DivisionByZero)), run_from_bytecode(compile_from_source(input).expect("compile")));
+
+        // lines are moved along with the instructions the optimizer removes
+        let input = "1;\n2;\n3 / 0;";
+        let byte_code = optimize(compile_from_source(input).expect("compile"));
+        assert_eq!(Err(at_line(3, VMError::DivisionByZero)), run_from_bytecode(byte_code));
+
+        // bytecode without a line table gives the bare error
+        let byte_code = compile_from_source("1 / 0;").expect("compile");
+        let byte_code = ByteCode { lines: LineTable::default(), ..byte_code };
+        assert_eq!(Err(VMError::DivisionByZero), run_from_bytecode(byte_code));
+    }
+
+    fn at_line(line: usize, error: VMError) -> VMError {
+        VMError::AtLine { line, error: Box::new(error) }
+    }
+
+    fn assert_last_popped(input: &str, obj: Object) {
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let mut vm = VM::new(byte_code);
+        vm.run().expect("vm run");
+
+        assert_eq!(&obj, vm.last_popped());
+    }
+
+    /// ignores which line the error happened on, see run_error_lines for those
+    fn assert_run_error(input: &str, error: VMError) {
+        let byte_code = compile_from_source(input).expect("compile");
+
+        let mut vm = VM::new(byte_code);
+
+        assert_eq!(Err(error), vm.run().map_err(|error| match error {
+            VMError::AtLine { error, .. } => *error,
+            error => error,
+        }));
     }
 }